@@ -1,4 +1,6 @@
-use std::net::SocketAddr;
+use std::env;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::future::Future;
 
 use anyhow::Result;
 use tokio::net::{TcpListener, TcpStream};
@@ -8,9 +10,19 @@ use kaminari::opt;
 use kaminari::trick::Ref;
 use kaminari::AsyncConnect;
 use kaminari::nop::NopConnect;
-use kaminari::ws::WsConnect;
-#[cfg(feature = "tls")]
+use kaminari::ws::{WsConnect, MaskMode};
+use kaminari::ws::http2::Ws2Connect;
+use kaminari::grpc::GrpcConnect;
+use kaminari::post::PostConnect;
+use kaminari::split::SplitConnect;
+use kaminari::upgrade::HttpUpgradeConnect;
+use kaminari::httpproxy::HttpProxyConnect;
+#[cfg(all(feature = "tls", not(feature = "tls-openssl")))]
 use kaminari::tls::{TlsConnect, install_provider};
+#[cfg(feature = "tls-openssl")]
+use kaminari::tls::openssl::{TlsConnect, install_provider};
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+use kaminari::tls::ktls::{KtlsConnect, KtlsStream};
 
 use kaminari_cmd::{Endpoint, parse_cmd, parse_env};
 
@@ -19,22 +31,86 @@ async fn main() -> Result<()> {
     let (Endpoint { local, remote }, options) = parse_env().or_else(|_| parse_cmd())?;
 
     let ws = opt::get_ws_conf(&options);
+    let ws_early = ws.as_ref().is_some_and(|x| x.early_data != 0);
+    let ws_mask = ws.as_ref().map_or(MaskMode::Skip, |x| x.client.mask);
+    let h2 = opt::has_opt!(&options => "h2");
+    if h2 && ws.is_none() {
+        anyhow::bail!("h2 requires ws");
+    }
+    if h2 && ws_early {
+        anyhow::bail!("h2 does not work with early data");
+    }
+    let grpc = opt::get_grpc_conf(&options);
+    let post = opt::get_post_conf(&options);
+    let split = opt::get_split_conf(&options);
+    let upgrade = opt::get_upgrade_conf(&options);
+    let transports = [
+        ws.is_some(),
+        grpc.is_some(),
+        post.is_some(),
+        split.is_some(),
+        upgrade.is_some(),
+    ];
+    if transports.iter().filter(|x| **x).count() > 1 {
+        anyhow::bail!("ws, grpc, post, split and upgrade do not work together");
+    }
+    // the option string, then the environment
+    let http_proxy = opt::get_http_proxy_conf(&options).or_else(|| {
+        ["HTTPS_PROXY", "https_proxy"]
+            .into_iter()
+            .find_map(|k| env::var(k).ok().filter(|x| !x.is_empty()))
+            .map(|x| opt::parse_http_proxy(&x))
+    });
     #[cfg(feature = "tls")]
     let tls = opt::get_tls_client_conf(&options);
+    #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+    let ktls = opt::has_opt!(&options => "ktls");
 
-    eprintln!("listen: {}", &local);
-    eprintln!("remote: {}", &remote);
+    eprintln!("listen: {}", local);
+    eprintln!("remote: {}", remote);
+
+    if let Some(http_proxy) = &http_proxy {
+        eprintln!("httpproxy: {http_proxy}")
+    }
 
     if let Some(ws) = &ws {
         eprintln!("ws: {}", ws)
     }
 
+    if let Some(grpc) = &grpc {
+        eprintln!("grpc: {grpc}")
+    }
+
+    if let Some(post) = &post {
+        eprintln!("post: {post}")
+    }
+
+    if let Some(split) = &split {
+        eprintln!("split: {split}")
+    }
+
+    if let Some(upgrade) = &upgrade {
+        eprintln!("upgrade: {upgrade}")
+    }
+
     #[cfg(feature = "tls")]
     if let Some(tls) = &tls {
-        eprintln!("tls: {}", &tls);
+        eprintln!("tls: {}", tls);
         install_provider();
     }
 
+    let proxy = match http_proxy {
+        Some(conf) => {
+            let addr = (conf.proxy.to_socket_addrs()?.next())
+                .ok_or_else(|| anyhow::anyhow!("httpproxy: cannot resolve {}", conf.proxy))?;
+            let proxy = HttpProxyConnect::new(NopConnect {}, conf, remote.to_string());
+            Some((addr, proxy))
+        }
+        None => None,
+    };
+    let remote = Dial { remote, proxy };
+    let remote = Ref::new(&remote);
+
     let lis = TcpListener::bind(local).await?;
 
     #[cfg(all(unix, not(target_os = "android")))]
@@ -42,11 +118,14 @@ async fn main() -> Result<()> {
 
     macro_rules! run {
         ($cc: expr) => {
+            run!($cc, relay)
+        };
+        ($cc: expr, $relay: ident) => {
             println!("connect: {}", $cc.as_ref());
             loop {
                 match lis.accept().await {
                     Ok((stream, _)) => {
-                        tokio::spawn(relay(stream, remote, $cc));
+                        tokio::spawn($relay(stream, remote, $cc));
                     }
                     Err(e) => {
                         eprintln!("accept error: {}", e);
@@ -59,24 +138,89 @@ async fn main() -> Result<()> {
 
     macro_rules! run_ws_each {
         ($client: expr) => {
-            let ws_mask_mode = opt::get_opt!(&options => "mask");
-            match ws_mask_mode {
-                Some("standard") => {
-                    eprintln!("mask: standard");
+            eprintln!("mask: {ws_mask}");
+            if h2 {
+                let client = $client.http2();
+                run!(Ref::new(&client), relay_shared);
+                return Ok(());
+            }
+            match ws_mask {
+                MaskMode::Standard => {
                     let client = $client.standard();
-                    run!(Ref::new(&client));
-                },
-                Some("fixed") => {
+                    run_ws_early!(client);
+                }
+                MaskMode::Fixed => {
                     let client = $client.fixed();
-                    eprintln!("mask: fixed");
-                    run!(Ref::new(&client));
-                },
-                _ => {
-                    eprintln!("mask: skip");
-                    run!(Ref::new(&$client));
+                    run_ws_early!(client);
+                }
+                MaskMode::Skip => {
+                    run_ws_early!($client);
                 }
             };
+        };
+    }
+
+    // send the first write within the upgrade request
+    macro_rules! run_ws_early {
+        ($client: expr) => {
+            if ws_early {
+                let client = $client.early();
+                run!(Ref::new(&client));
+            } else {
+                run!(Ref::new(&$client));
+            }
+        };
+    }
+
+    // tunnels share one h2 connection
+    macro_rules! run_shared {
+        ($client: ident, $conf: expr) => {
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+                if ktls {
+                    let client = $client::new(KtlsConnect::new(NopConnect {}, tls), $conf);
+                    run!(Ref::new(&client), relay_shared);
+                    return Ok(());
+                }
+                let client = $client::new(TlsConnect::new(NopConnect {}, tls), $conf);
+                run!(Ref::new(&client), relay_shared);
+                return Ok(());
+            }
+            let client = $client::new(NopConnect {}, $conf);
+            run!(Ref::new(&client), relay_shared);
+            return Ok(());
+        };
+    }
+
+    if let Some(grpc) = grpc {
+        run_shared!(GrpcConnect, grpc);
+    }
+
+    if let Some(post) = post {
+        run_shared!(PostConnect, post);
+    }
+
+    if let Some(split) = split {
+        run_shared!(SplitConnect, split);
+    }
+
+    if let Some(upgrade) = upgrade {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = tls {
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls {
+                let client = HttpUpgradeConnect::new(KtlsConnect::new(NopConnect {}, tls), upgrade);
+                run!(Ref::new(&client));
+                return Ok(());
+            }
+            let client = HttpUpgradeConnect::new(TlsConnect::new(NopConnect {}, tls), upgrade);
+            run!(Ref::new(&client));
+            return Ok(());
         }
+        let client = HttpUpgradeConnect::new(NopConnect {}, upgrade);
+        run!(Ref::new(&client));
+        return Ok(());
     }
 
     #[cfg(feature = "tls")]
@@ -90,10 +234,22 @@ async fn main() -> Result<()> {
             run_ws_each!(client);
         }
         (None, Some(tls)) => {
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls {
+                let client = KtlsConnect::new(NopConnect {}, tls);
+                run!(Ref::new(&client), relay_ktls);
+                return Ok(());
+            }
             let client = TlsConnect::new(NopConnect {}, tls);
             run!(Ref::new(&client));
         }
         (Some(ws), Some(tls)) => {
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls {
+                let client = WsConnect::new(KtlsConnect::new(NopConnect {}, tls), ws);
+                run_ws_each!(client);
+                return Ok(());
+            }
             let client = WsConnect::new(TlsConnect::new(NopConnect {}, tls), ws);
             run_ws_each!(client);
         }
@@ -111,15 +267,33 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+// the remote, or an http proxy in front of it
+struct Dial {
+    remote: SocketAddr,
+    proxy: Option<(SocketAddr, HttpProxyConnect<NopConnect>)>,
+}
+
+impl Dial {
+    async fn connect(&self, buf: &mut [u8]) -> std::io::Result<TcpStream> {
+        match &self.proxy {
+            Some((addr, proxy)) => {
+                let stream = TcpStream::connect(addr).await?;
+                proxy.connect(stream, buf).await
+            }
+            None => TcpStream::connect(self.remote).await,
+        }
+    }
+}
+
 #[rustfmt::skip]
-async fn relay<T>(mut local: TcpStream, remote: SocketAddr, client: Ref<T>) -> std::io::Result<()>
+async fn relay<T>(mut local: TcpStream, remote: Ref<Dial>, client: Ref<T>) -> std::io::Result<()>
 where
     T: AsyncConnect<TcpStream>,
 {
     let mut buf1 = vec![0u8; 0x2000];
     let buf2 = vec![0u8; 0x2000];
 
-    let remote = TcpStream::connect(remote).await?;
+    let remote = remote.connect(&mut buf1).await?;
     let mut remote = client.connect(remote, &mut buf1).await?;
 
     let buf1 = CopyBuffer::new(buf1.into_boxed_slice());
@@ -127,3 +301,73 @@ where
 
     bidi_copy_buf(&mut local, &mut remote, buf1, buf2).await.map(|_| ())
 }
+
+// clients that share one connection among tunnels
+trait Shared: AsyncConnect<TcpStream> {
+    fn open(&self) -> impl Future<Output = Option<std::io::Result<Self::Stream>>> + Send;
+}
+
+macro_rules! impl_shared {
+    ($($client: ident),+) => {
+        $(
+            impl<T> Shared for $client<T>
+            where
+                T: AsyncConnect<TcpStream> + Sync,
+                T::Stream: Send,
+            {
+                #[inline]
+                fn open(&self) -> impl Future<Output = Option<std::io::Result<Self::Stream>>> + Send {
+                    $client::open(self)
+                }
+            }
+        )+
+    };
+}
+
+impl_shared!(Ws2Connect, GrpcConnect, PostConnect, SplitConnect);
+
+// reuse the connection if there is a live one
+#[rustfmt::skip]
+async fn relay_shared<T>(mut local: TcpStream, remote: Ref<Dial>, client: Ref<T>) -> std::io::Result<()>
+where
+    T: Shared,
+{
+    let mut buf1 = vec![0u8; 0x2000];
+    let buf2 = vec![0u8; 0x2000];
+
+    let mut remote = match client.open().await {
+        Some(remote) => remote?,
+        None => {
+            let remote = remote.connect(&mut buf1).await?;
+            client.connect(remote, &mut buf1).await?
+        }
+    };
+
+    let buf1 = CopyBuffer::new(buf1.into_boxed_slice());
+    let buf2 = CopyBuffer::new(buf2.into_boxed_slice());
+
+    bidi_copy_buf(&mut local, &mut remote, buf1, buf2).await.map(|_| ())
+}
+
+// splice if the kernel takes over tls
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+#[rustfmt::skip]
+async fn relay_ktls<T>(mut local: TcpStream, remote: Ref<Dial>, client: Ref<T>) -> std::io::Result<()>
+where
+    T: AsyncConnect<TcpStream, Stream = KtlsStream<TcpStream>>,
+{
+    let mut buf1 = vec![0u8; 0x2000];
+
+    let remote = remote.connect(&mut buf1).await?;
+    let mut remote = match client.connect(remote, &mut buf1).await? {
+        KtlsStream::Kernel(mut remote) => {
+            return realm_io::bidi_zero_copy(&mut local, &mut remote).await.map(|_| ())
+        }
+        remote => remote,
+    };
+
+    let buf1 = CopyBuffer::new(buf1.into_boxed_slice());
+    let buf2 = CopyBuffer::new(vec![0u8; 0x2000].into_boxed_slice());
+
+    bidi_copy_buf(&mut local, &mut remote, buf1, buf2).await.map(|_| ())
+}
@@ -27,6 +27,17 @@ pub fn parse_env() -> Result<(Endpoint, String)> {
     Ok((Endpoint { local, remote }, plugin_opts))
 }
 
+// issue <ca_dir> <name>
+pub fn parse_issue() -> Option<(String, String)> {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() == 4 && args[1] == "issue" {
+        Some((args[2].clone(), args[3].clone()))
+    } else {
+        None
+    }
+}
+
 pub fn parse_cmd() -> Result<(Endpoint, String)> {
     let args: Vec<String> = env::args().collect();
 
@@ -1,21 +1,43 @@
 use std::net::SocketAddr;
 
 use anyhow::Result;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use realm_io::{CopyBuffer, bidi_copy_buf};
 
 use kaminari::opt;
 use kaminari::trick::Ref;
-use kaminari::AsyncAccept;
+use kaminari::{IOStream, AsyncAccept};
 use kaminari::nop::NopAccept;
 use kaminari::ws::WsAccept;
-#[cfg(feature = "tls")]
+use kaminari::ws::route::Routed;
+use kaminari::ws::http2::{Ws2Accept, Incoming};
+use kaminari::grpc::GrpcAccept;
+use kaminari::post::PostAccept;
+use kaminari::split::SplitAccept;
+use kaminari::upgrade::HttpUpgradeAccept;
+use kaminari::proxy::{ProxyAccept, ClientAddr, encode_v2};
+use kaminari::fallback::FallbackAccept;
+#[cfg(all(feature = "tls", not(feature = "tls-openssl")))]
 use kaminari::tls::{TlsAccept, install_provider};
+#[cfg(feature = "tls-openssl")]
+use kaminari::tls::openssl::{TlsAccept, install_provider};
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+use kaminari::tls::ktls::{KtlsAccept, KtlsStream};
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+use kaminari::proxy::ProxyStream;
 
 use kaminari_cmd::{Endpoint, parse_cmd, parse_env};
+#[cfg(feature = "tls")]
+use kaminari_cmd::parse_issue;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some((ca, name)) = parse_issue() {
+        return issue(&ca, &name);
+    }
+
     let (Endpoint { local, remote }, options) = parse_env()
         .map(|(Endpoint { local, remote }, opt)| {
             (
@@ -29,23 +51,112 @@ async fn main() -> Result<()> {
         .or_else(|_| parse_cmd())?;
 
     let ws = opt::get_ws_conf(&options);
+    let accept_proxy = opt::has_opt!(&options => "acceptproxy");
+    let send_proxy = opt::has_opt!(&options => "sendproxy");
+    let raw_fallback: Option<SocketAddr> = opt::get_opt!(&options => "rawfallback")
+        .map(|s| s.parse())
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("rawfallback: {e}"))?;
+    let h2 = opt::has_opt!(&options => "h2");
+    if h2 && ws.is_none() {
+        anyhow::bail!("h2 requires ws");
+    }
+    if h2 && raw_fallback.is_some() {
+        anyhow::bail!("h2 does not work with rawfallback");
+    }
+    let grpc = opt::get_grpc_conf(&options);
+    let post = opt::get_post_conf(&options);
+    let split = opt::get_split_conf(&options);
+    let upgrade = opt::get_upgrade_conf(&options);
+    let transports = [
+        ws.is_some(),
+        grpc.is_some(),
+        post.is_some(),
+        split.is_some(),
+        upgrade.is_some(),
+    ];
+    if transports.iter().filter(|x| **x).count() > 1 {
+        anyhow::bail!("ws, grpc, post, split and upgrade do not work together");
+    }
+    if grpc.is_some() && raw_fallback.is_some() {
+        anyhow::bail!("grpc does not work with rawfallback");
+    }
+    if post.is_some() && raw_fallback.is_some() {
+        anyhow::bail!("post does not work with rawfallback");
+    }
+    if split.is_some() && raw_fallback.is_some() {
+        anyhow::bail!("split does not work with rawfallback");
+    }
 
     #[cfg(feature = "tls")]
     let tls = opt::get_tls_server_conf(&options);
+    #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+    let ktls = opt::has_opt!(&options => "ktls");
+    #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+    if ktls && raw_fallback.is_some() {
+        anyhow::bail!("ktls does not work with rawfallback");
+    }
 
-    eprintln!("listen: {}", &local);
-    eprintln!("remote: {}", &remote);
+    eprintln!("listen: {}", local);
+    eprintln!("remote: {}", remote);
 
     if let Some(ws) = &ws {
         eprintln!("ws: {}", ws)
     }
 
+    if let Some(grpc) = &grpc {
+        eprintln!("grpc: {grpc}")
+    }
+
+    if let Some(post) = &post {
+        eprintln!("post: {post}")
+    }
+
+    if let Some(split) = &split {
+        eprintln!("split: {split}")
+    }
+
+    if let Some(upgrade) = &upgrade {
+        eprintln!("upgrade: {upgrade}")
+    }
+
     #[cfg(feature = "tls")]
     if let Some(tls) = &tls {
-        eprintln!("tls: {}", &tls);
+        eprintln!("tls: {}", tls);
         install_provider();
     }
 
+    // route targets are backend addresses
+    let routes = ws
+        .iter()
+        .flat_map(|ws| ws.server.routes.iter())
+        .map(|r| {
+            eprintln!("route: {r}");
+            r.target
+                .parse()
+                .map_err(|e| anyhow::anyhow!("route {r}: {e}"))
+        })
+        .collect::<Result<Vec<SocketAddr>>>()?;
+
+    if accept_proxy {
+        eprintln!("accept proxy protocol");
+    }
+
+    if send_proxy {
+        eprintln!("send proxy protocol v2");
+    }
+
+    if let Some(addr) = &raw_fallback {
+        eprintln!("raw fallback: {addr}");
+    }
+
+    let backend = Backend {
+        remote,
+        routes,
+        send_proxy,
+    };
+    let backend = Ref::new(&backend);
+
     let lis = TcpListener::bind(local).await?;
 
     #[cfg(all(unix, not(target_os = "android")))]
@@ -53,11 +164,14 @@ async fn main() -> Result<()> {
 
     macro_rules! run {
         ($ac: expr) => {
+            run!($ac, relay)
+        };
+        ($ac: expr, $relay: ident) => {
             println!("accept: {}", $ac.as_ref());
             loop {
                 match lis.accept().await {
-                    Ok((stream, _)) => {
-                        tokio::spawn(relay(stream, remote, $ac));
+                    Ok((stream, peer)) => {
+                        tokio::spawn($relay(stream, peer, $ac, backend));
                     }
                     Err(e) => {
                         eprintln!("accept error: {}", e);
@@ -68,51 +182,354 @@ async fn main() -> Result<()> {
         };
     }
 
+    // accept both http/1.1 and h2
+    macro_rules! run_h2 {
+        ($lis: expr, $ws: expr) => {
+            if accept_proxy {
+                let server = Ws2Accept::new(ProxyAccept::new($lis), $ws);
+                run!(Ref::new(&server), relay_h2);
+            } else {
+                let server = Ws2Accept::new($lis, $ws);
+                run!(Ref::new(&server), relay_h2);
+            }
+            return Ok(());
+        };
+    }
+
+    macro_rules! run_serve {
+        ($server: ident, $relay: ident, $lis: expr, $conf: expr) => {
+            if accept_proxy {
+                let server = $server::new(ProxyAccept::new($lis), $conf);
+                run!(Ref::new(&server), $relay);
+            } else {
+                let server = $server::new($lis, $conf);
+                run!(Ref::new(&server), $relay);
+            }
+            return Ok(());
+        };
+    }
+
+    // tunnels share one h2 connection
+    macro_rules! run_shared {
+        ($server: ident, $relay: ident, $conf: expr) => {
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+                if ktls {
+                    run_serve!($server, $relay, KtlsAccept::new(NopAccept {}, tls), $conf);
+                }
+                run_serve!($server, $relay, TlsAccept::new(NopAccept {}, tls), $conf);
+            }
+            run_serve!($server, $relay, NopAccept {}, $conf);
+        };
+    }
+
+    if let Some(grpc) = grpc {
+        run_shared!(GrpcAccept, relay_grpc, grpc);
+    }
+
+    if let Some(post) = post {
+        run_shared!(PostAccept, relay_post, post);
+    }
+
+    if let Some(split) = split {
+        run_shared!(SplitAccept, relay_split, split);
+    }
+
+    macro_rules! run_each {
+        ($server: expr $(, $relay: ident)?) => {
+            if let Some(addr) = raw_fallback {
+                let server = FallbackAccept::new($server, addr);
+                run_proxy!(server $(, $relay)?);
+            } else {
+                run_proxy!($server $(, $relay)?);
+            }
+        };
+    }
+
+    macro_rules! run_proxy {
+        ($server: expr $(, $relay: ident)?) => {
+            if accept_proxy {
+                let server = ProxyAccept::new($server);
+                run!(Ref::new(&server) $(, $relay)?);
+            } else {
+                run!(Ref::new(&$server) $(, $relay)?);
+            }
+        };
+    }
+
+    if let Some(upgrade) = upgrade {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = tls {
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls {
+                let server = HttpUpgradeAccept::new(KtlsAccept::new(NopAccept {}, tls), upgrade);
+                run_proxy!(server);
+                return Ok(());
+            }
+            let server = HttpUpgradeAccept::new(TlsAccept::new(NopAccept {}, tls), upgrade);
+            run_each!(server);
+            return Ok(());
+        }
+        let server = HttpUpgradeAccept::new(NopAccept {}, upgrade);
+        run_each!(server);
+        return Ok(());
+    }
+
     #[cfg(feature = "tls")]
     match (ws, tls) {
         (None, None) => {
             let server = NopAccept {};
-            run!(Ref::new(&server));
+            run_each!(server);
         }
         (Some(ws), None) => {
+            if h2 {
+                run_h2!(NopAccept {}, ws);
+            }
             let server = WsAccept::new(NopAccept {}, ws);
-            run!(Ref::new(&server));
+            run_each!(server, relay_routed);
         }
         (None, Some(tls)) => {
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls {
+                let server = KtlsAccept::new(NopAccept {}, tls);
+                run_proxy!(server, relay_ktls);
+                return Ok(());
+            }
             let server = TlsAccept::new(NopAccept {}, tls);
-            run!(Ref::new(&server));
+            run_each!(server);
         }
         (Some(ws), Some(tls)) => {
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls && h2 {
+                run_h2!(KtlsAccept::new(NopAccept {}, tls), ws);
+            }
+            if h2 {
+                run_h2!(TlsAccept::new(NopAccept {}, tls), ws);
+            }
+            #[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+            if ktls {
+                let server = WsAccept::new(KtlsAccept::new(NopAccept {}, tls), ws);
+                run_proxy!(server, relay_routed);
+                return Ok(());
+            }
             let server = WsAccept::new(TlsAccept::new(NopAccept {}, tls), ws);
-            run!(Ref::new(&server));
+            run_each!(server, relay_routed);
         }
     };
 
     #[cfg(not(feature = "tls"))]
     if let Some(ws) = ws {
+        if h2 {
+            run_h2!(NopAccept {}, ws);
+        }
         let server = WsAccept::new(NopAccept {}, ws);
-        run!(Ref::new(&server));
+        run_each!(server, relay_routed);
     } else {
         let server = NopAccept {};
-        run!(Ref::new(&server));
+        run_each!(server);
     }
 
     Ok(())
 }
 
+// mint a client certificate from the local ca
+#[cfg(feature = "tls")]
+fn issue(ca: &str, name: &str) -> Result<()> {
+    use kaminari::tls::ca::Ca;
+    use kaminari::tls::{KeyType, SelfSignedConf, write_pem};
+
+    let ca_crt = Ca::cert_path(ca);
+    let ca = Ca::load_or_create(ca, KeyType::default())?;
+    let (crt, key) = ca.issue_client(name, &SelfSignedConf::default())?;
+
+    let crt_path = format!("{name}.crt");
+    let key_path = format!("{name}.key");
+    write_pem(crt_path.as_ref(), &crt, 0o644)?;
+    write_pem(key_path.as_ref(), &key, 0o600)?;
+
+    eprintln!("ca: {}", ca_crt.display());
+    eprintln!("cert: {crt_path}");
+    eprintln!("key: {key_path}");
+
+    Ok(())
+}
+
+#[rustfmt::skip]
+async fn relay<T>(local: TcpStream, peer: SocketAddr, server: Ref<T>, backend: Ref<Backend>) -> std::io::Result<()>
+where
+    T: AsyncAccept<TcpStream>,
+    T::Stream: ClientAddr,
+{
+    relay_with(local, peer, server, backend, |_| None).await
+}
+
+// pick the backend by the matched route
+#[rustfmt::skip]
+async fn relay_routed<T>(local: TcpStream, peer: SocketAddr, server: Ref<T>, backend: Ref<Backend>) -> std::io::Result<()>
+where
+    T: AsyncAccept<TcpStream>,
+    T::Stream: ClientAddr + Routed,
+{
+    relay_with(local, peer, server, backend, Routed::route).await
+}
+
+#[rustfmt::skip]
+async fn relay_with<T>(local: TcpStream, peer: SocketAddr, server: Ref<T>, backend: Ref<Backend>, route: fn(&T::Stream) -> Option<usize>) -> std::io::Result<()>
+where
+    T: AsyncAccept<TcpStream>,
+    T::Stream: ClientAddr,
+{
+    let mut buf1 = vec![0u8; 0x2000];
+
+    let dst = local.local_addr()?;
+    let local = server.accept(local, &mut buf1).await?;
+    let route = route(&local);
+    relay_stream(local, route, peer, dst, backend, buf1).await
+}
+
+// serve each h2 tunnel in its own task
 #[rustfmt::skip]
-async fn relay<T>(local: TcpStream, remote: SocketAddr, server: Ref<T>) -> std::io::Result<()>
+async fn relay_h2<T>(local: TcpStream, peer: SocketAddr, server: Ref<Ws2Accept<T>>, backend: Ref<Backend>) -> std::io::Result<()>
 where
     T: AsyncAccept<TcpStream>,
+    T::Stream: ClientAddr + Send,
 {
     let mut buf1 = vec![0u8; 0x2000];
+
+    let dst = local.local_addr()?;
+    let io = match server.accept(local, &mut buf1).await? {
+        Incoming::Http1(local) => {
+            let route = local.route();
+            return relay_stream(local, route, peer, dst, backend, buf1).await;
+        }
+        Incoming::Http2(io) => io,
+    };
+
+    let peer = io.client_addr(peer);
+    let mut conn = server.serve(io).await?;
+    while let Some(local) = conn.accept().await {
+        let local = local?;
+        let route = local.route();
+        tokio::spawn(relay_stream(local, route, peer, dst, backend, vec![0u8; 0x2000]));
+    }
+
+    Ok(())
+}
+
+// serve each tunnel of an h2 connection in its own task
+macro_rules! relay_serve {
+    ($($relay: ident => $server: ident),+) => {
+        $(
+            #[rustfmt::skip]
+            async fn $relay<T>(local: TcpStream, peer: SocketAddr, server: Ref<$server<T>>, backend: Ref<Backend>) -> std::io::Result<()>
+            where
+                T: AsyncAccept<TcpStream>,
+                T::Stream: ClientAddr + Send,
+            {
+                let mut buf = vec![0u8; 0x2000];
+
+                let dst = local.local_addr()?;
+                let io = server.accept_inner(local, &mut buf).await?;
+
+                let peer = io.client_addr(peer);
+                let mut conn = server.serve(io).await?;
+                while let Some(local) = conn.accept().await {
+                    let local = local?;
+                    tokio::spawn(relay_stream(local, None, peer, dst, backend, vec![0u8; 0x2000]));
+                }
+
+                Ok(())
+            }
+        )+
+    };
+}
+
+relay_serve!(
+    relay_grpc => GrpcAccept,
+    relay_post => PostAccept,
+    relay_split => SplitAccept
+);
+
+#[rustfmt::skip]
+async fn relay_stream<S>(mut local: S, route: Option<usize>, peer: SocketAddr, dst: SocketAddr, backend: Ref<Backend>, buf1: Vec<u8>) -> std::io::Result<()>
+where
+    S: IOStream + ClientAddr,
+{
     let buf2 = vec![0u8; 0x2000];
 
-    let mut local = server.accept(local, &mut buf1).await?;
-    let mut remote = TcpStream::connect(remote).await?;
+    let mut remote = backend.connect(route, local.client_addr(peer), dst).await?;
 
     let buf1 = CopyBuffer::new(buf1.into_boxed_slice());
     let buf2 = CopyBuffer::new(buf2.into_boxed_slice());
 
     bidi_copy_buf(&mut local, &mut remote, buf1, buf2).await.map(|_| ())
 }
+
+struct Backend {
+    remote: SocketAddr,
+    // one for each ws route
+    routes: Vec<SocketAddr>,
+    send_proxy: bool,
+}
+
+impl Backend {
+    // prepend a proxy protocol v2 header if required
+    async fn connect(
+        &self,
+        route: Option<usize>,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> std::io::Result<TcpStream> {
+        let remote = route.map_or(self.remote, |i| self.routes[i]);
+        let mut stream = TcpStream::connect(remote).await?;
+
+        if self.send_proxy {
+            stream.write_all(&encode_v2(src, dst)).await?;
+        }
+
+        Ok(stream)
+    }
+}
+
+// ktls stream, maybe behind proxy protocol
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+trait IntoKtls {
+    fn into_ktls(self) -> KtlsStream<TcpStream>;
+}
+
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+impl IntoKtls for KtlsStream<TcpStream> {
+    fn into_ktls(self) -> KtlsStream<TcpStream> { self }
+}
+
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+impl IntoKtls for ProxyStream<KtlsStream<TcpStream>> {
+    fn into_ktls(self) -> KtlsStream<TcpStream> { self.into_inner() }
+}
+
+// splice if the kernel takes over tls
+#[cfg(all(feature = "ktls", target_os = "linux", not(feature = "tls-openssl")))]
+#[rustfmt::skip]
+async fn relay_ktls<T>(local: TcpStream, peer: SocketAddr, server: Ref<T>, backend: Ref<Backend>) -> std::io::Result<()>
+where
+    T: AsyncAccept<TcpStream>,
+    T::Stream: ClientAddr + IntoKtls,
+{
+    let mut buf1 = vec![0u8; 0x2000];
+
+    let dst = local.local_addr()?;
+    let local = server.accept(local, &mut buf1).await?;
+    let mut remote = backend.connect(None, local.client_addr(peer), dst).await?;
+    let mut local = match local.into_ktls() {
+        KtlsStream::Kernel(mut local) => {
+            return realm_io::bidi_zero_copy(&mut local, &mut remote).await.map(|_| ())
+        }
+        local => local,
+    };
+
+    let buf1 = CopyBuffer::new(buf1.into_boxed_slice());
+    let buf2 = CopyBuffer::new(vec![0u8; 0x2000].into_boxed_slice());
+
+    bidi_copy_buf(&mut local, &mut remote, buf1, buf2).await.map(|_| ())
+}
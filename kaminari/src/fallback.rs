@@ -0,0 +1,418 @@
+//! Relay failed handshakes to another server.
+//!
+//! [`FallbackAccept`] records what the inner layers read during the
+//! handshake, and holds what they write. If the inner accept fails,
+//! e.g. on garbage or an unknown sni, the connection is relayed to the
+//! fallback address, starting with the recorded bytes. Held writes, like
+//! a tls alert, are discarded.
+//!
+//! Held writes are sent once the inner layers read again. After a
+//! successful accept, they are sent on the first read or write.
+//!
+//! Recording stops if the inner layers shut down the stream, which means
+//! they have answered the peer, e.g. with a `403`, or after reading more
+//! than [`MAX_RECORD`] bytes. There is no fallback then.
+//!
+//! The relay runs in its own task, accept returns a [`Handled`] error
+//! right away, which is not a failure, see [`is_handled`].
+
+use std::io::{Error, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Context, Poll};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+use super::{IOStream, AsyncAccept};
+
+/// Give up the fallback after reading this many bytes.
+pub const MAX_RECORD: usize = 0x10000;
+
+// ========== outcome ==========
+/// Error of an accept that handed the connection to a fallback.
+#[derive(Debug)]
+pub struct Handled {
+    // why the handshake failed
+    reason: Error,
+}
+
+impl Display for Handled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fallback: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Handled {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { Some(&self.reason) }
+}
+
+impl Handled {
+    #[inline]
+    pub fn new_error(reason: Error) -> Error { Error::other(Self { reason }) }
+}
+
+/// Whether the connection went to a fallback, instead of failing.
+#[inline]
+pub fn is_handled(err: &Error) -> bool { err.get_ref().is_some_and(|x| x.is::<Handled>()) }
+
+/// Relay to `addr` in a new task, starting with `prefix`.
+pub fn spawn_relay<S>(io: S, prefix: Vec<u8>, addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let _ = relay(io, &prefix, addr).await;
+    });
+}
+
+// ========== accept ==========
+#[derive(Debug, Clone)]
+pub struct FallbackAccept<T> {
+    lis: T,
+    addr: SocketAddr,
+}
+
+impl<T> Display for FallbackAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[fallback]{}", self.lis) }
+}
+
+impl<T> FallbackAccept<T> {
+    #[inline]
+    pub const fn new(lis: T, addr: SocketAddr) -> Self { Self { lis, addr } }
+}
+
+impl<S, T> AsyncAccept<S> for FallbackAccept<T>
+where
+    S: IOStream + Send,
+    T: AsyncAccept<Recorded<S>>,
+{
+    type Stream = T::Stream;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let shared = Arc::new(Shared::new());
+            let stream = Recorded::new(stream, shared.clone());
+
+            let err = match self.lis.accept(stream, buf).await {
+                Ok(stream) => {
+                    shared.done.store(true, Ordering::Release);
+                    return Ok(stream);
+                }
+                // by an inner fallback
+                Err(e) if is_handled(&e) => return Err(e),
+                Err(e) => e,
+            };
+
+            // handed back on drop
+            let Some((io, prefix)) = shared.back.lock().unwrap().take() else {
+                return Err(err);
+            };
+
+            spawn_relay(io, prefix, self.addr);
+            Err(Handled::new_error(err))
+        }
+    }
+}
+
+async fn relay<S>(mut io: S, prefix: &[u8], addr: SocketAddr) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut remote = TcpStream::connect(addr).await?;
+    remote.write_all(prefix).await?;
+    tokio::io::copy_bidirectional(&mut io, &mut remote)
+        .await
+        .map(|_| ())
+}
+
+// ========== stream ==========
+struct Shared<S> {
+    done: AtomicBool,
+    back: Mutex<Option<(S, Vec<u8>)>>,
+}
+
+impl<S> Shared<S> {
+    const fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            back: Mutex::new(None),
+        }
+    }
+}
+
+struct State<S> {
+    shared: Arc<Shared<S>>,
+    read: Vec<u8>,
+    held: Vec<u8>,
+    sent: usize,
+}
+
+/// Stream that records the handshake.
+pub struct Recorded<S> {
+    // taken on drop
+    io: Option<S>,
+    // none once the handshake is done
+    state: Option<Box<State<S>>>,
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for Recorded<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorded")
+            .field("io", &self.io)
+            .field("recording", &self.state.is_some())
+            .finish()
+    }
+}
+
+impl<S> Recorded<S> {
+    fn new(io: S, shared: Arc<Shared<S>>) -> Self {
+        let state = State {
+            shared,
+            read: Vec::new(),
+            held: Vec::new(),
+            sent: 0,
+        };
+        Self {
+            io: Some(io),
+            state: Some(Box::new(state)),
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S { self.io.as_ref().unwrap() }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S { self.io.as_mut().unwrap() }
+}
+
+impl<S> Recorded<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    // send held writes
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let (Some(io), Some(state)) = (&mut self.io, &mut self.state) else {
+            return Poll::Ready(Ok(()));
+        };
+
+        while state.sent < state.held.len() {
+            let n = ready!(Pin::new(&mut *io).poll_write(cx, &state.held[state.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            state.sent += n;
+        }
+
+        state.held.clear();
+        state.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    // stop recording, no fallback since then
+    fn poll_release(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        self.state = None;
+        Poll::Ready(Ok(()))
+    }
+
+    // stop recording after a successful accept
+    fn poll_commit(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &self.state {
+            Some(state) if state.shared.done.load(Ordering::Acquire) => self.poll_release(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<S> Drop for Recorded<S> {
+    fn drop(&mut self) {
+        if let (Some(io), Some(state)) = (self.io.take(), self.state.take()) {
+            if !state.shared.done.load(Ordering::Acquire) {
+                *state.shared.back.lock().unwrap() = Some((io, state.read));
+            }
+        }
+    }
+}
+
+impl<S> AsyncRead for Recorded<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_commit(cx))?;
+
+        if this.state.is_none() {
+            return Pin::new(this.get_mut()).poll_read(cx, buf);
+        }
+
+        // the inner layers are waiting for the peer
+        ready!(this.poll_drain(cx))?;
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(this.get_mut()).poll_read(cx, buf))?;
+        if let Some(state) = &mut this.state {
+            state.read.extend_from_slice(&buf.filled()[filled..]);
+            // nothing is held after draining
+            if state.read.len() > MAX_RECORD {
+                this.state = None;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for Recorded<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_commit(cx))?;
+
+        match &mut this.state {
+            Some(state) => {
+                state.held.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            None => Pin::new(this.get_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_commit(cx))?;
+
+        match this.state {
+            Some(_) => Poll::Ready(Ok(())),
+            None => Pin::new(this.get_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // the inner layers have answered the peer
+        let this = self.get_mut();
+        ready!(this.poll_release(cx))?;
+        Pin::new(this.get_mut()).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    // read 4 bytes, write an alert, then fail,
+    // or succeed with the right bytes
+    struct Picky {}
+
+    impl<S: IOStream> AsyncAccept<S> for Picky {
+        type Stream = S;
+
+        type AcceptFut<'a>
+            = impl Future<Output = Result<Self::Stream>> + 'a
+        where
+            Self: 'a;
+
+        fn accept<'a>(&'a self, mut stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+            async move {
+                stream.read_exact(&mut buf[..4]).await?;
+                if &buf[..4] == b"good" {
+                    stream.write_all(b"welcome").await?;
+                    return Ok(stream);
+                }
+                stream.write_all(b"alert").await?;
+                if &buf[..4] == b"shut" {
+                    stream.shutdown().await?;
+                }
+                Err(Error::other("bad"))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_on_failure() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let lis = FallbackAccept::new(Picky {}, backend.local_addr().unwrap());
+
+        let backend = async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buf = [0u8; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"evil-bytes");
+            stream.write_all(b"from backend").await.unwrap();
+        };
+
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let client = async move {
+            client.write_all(b"evil").await.unwrap();
+            client.write_all(b"-bytes").await.unwrap();
+            let mut resp = Vec::new();
+            client.read_to_end(&mut resp).await.unwrap();
+            assert_eq!(resp, b"from backend");
+        };
+
+        let mut buf = vec![0u8; 0x1000];
+        let (res, ..) = tokio::join!(lis.accept(server, &mut buf), backend, client);
+        assert!(is_handled(&res.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn no_fallback_after_shutdown() {
+        let lis = FallbackAccept::new(Picky {}, "127.0.0.1:1".parse().unwrap());
+
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        client.write_all(b"shut").await.unwrap();
+
+        let mut buf = vec![0u8; 0x1000];
+        let err = lis.accept(server, &mut buf).await.unwrap_err();
+        assert_eq!(err.to_string(), "bad");
+        assert!(!is_handled(&err));
+
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).await.unwrap();
+        assert_eq!(resp, b"alert");
+    }
+
+    #[tokio::test]
+    async fn commit_on_success() {
+        let lis = FallbackAccept::new(Picky {}, "127.0.0.1:1".parse().unwrap());
+
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        client.write_all(b"good").await.unwrap();
+
+        let mut buf = vec![0u8; 0x1000];
+        let mut stream = lis.accept(server, &mut buf).await.unwrap();
+
+        // held writes go first
+        stream.write_all(b", hi").await.unwrap();
+        let mut resp = [0u8; 11];
+        client.read_exact(&mut resp).await.unwrap();
+        assert_eq!(&resp, b"welcome, hi");
+
+        // no longer recorded
+        client.write_all(b"ping").await.unwrap();
+        let mut req = [0u8; 4];
+        stream.read_exact(&mut req).await.unwrap();
+        assert_eq!(&req, b"ping");
+        assert!(stream.state.is_none());
+    }
+}
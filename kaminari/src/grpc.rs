@@ -0,0 +1,581 @@
+//! gRPC stream transport, compatible with gun and the `grpc` transport of xray.
+//!
+//! Each tunnel is a bidirectional gRPC call to `/<service>/Tun`. Data is
+//! carried by length-prefixed messages, each one a protobuf `Hunk` with a
+//! single `bytes data = 1` field.
+//!
+//! [`GrpcConnect`] makes an h2 connection on each [`AsyncConnect::connect`],
+//! and [`GrpcConnect::open`] reuses it for more tunnels until it closes.
+//! [`GrpcAccept`] yields the first tunnel of each connection, use
+//! [`GrpcAccept::serve`] to take all of them.
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{ready, Context, Poll};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use h2::RecvStream;
+use h2::client::SendRequest;
+use h2::server::{self, SendResponse};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+
+use super::{IOStream, AsyncAccept, AsyncConnect};
+use super::http2::{H2Stream, Pool, into_io};
+
+const CONTENT_TYPE: &str = "application/grpc";
+
+// tag of Hunk.data, length delimited
+const DATA_TAG: u8 = 0x0a;
+
+// keep messages small, on both sides
+const MAX_HUNK: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrpcConf {
+    // client: authority of requests
+    pub host: String,
+    // tunnels go to /<service>/Tun
+    pub service: String,
+}
+
+impl Display for GrpcConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "host: {}, service: {}", self.host, self.service)
+    }
+}
+
+impl GrpcConf {
+    #[inline]
+    fn path(&self) -> String { format!("/{}/Tun", self.service) }
+}
+
+#[inline]
+fn invalid(msg: &'static str) -> Error { Error::new(ErrorKind::InvalidData, msg) }
+
+// ========== message ==========
+enum Head {
+    // bytes still missing
+    More(usize),
+    // length of data that follows
+    Data(usize),
+}
+
+// grpc prefix, then the tag and length of Hunk.data
+fn parse_head(head: &[u8]) -> Result<Head> {
+    if head.len() < 5 {
+        return Ok(Head::More(5 - head.len()));
+    }
+    if head[0] != 0 {
+        return Err(invalid("grpc: compressed message"));
+    }
+    let size = u32::from_be_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    if size == 0 {
+        return Ok(Head::Data(0));
+    }
+
+    match head.get(5) {
+        None => return Ok(Head::More(1)),
+        Some(&DATA_TAG) => {}
+        Some(_) => return Err(invalid("grpc: unknown field")),
+    }
+
+    // a u32 takes up to 5 bytes
+    let mut len = 0;
+    for (i, b) in head[6..].iter().enumerate() {
+        len |= ((b & 0x7f) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            return match 1 + (i + 1) + len == size {
+                true => Ok(Head::Data(len)),
+                false => Err(invalid("grpc: invalid message")),
+            };
+        }
+    }
+    match head.len() - 6 < 5 {
+        true => Ok(Head::More(1)),
+        false => Err(invalid("grpc: invalid message")),
+    }
+}
+
+fn encode(data: &[u8]) -> Bytes {
+    let mut len = [0u8; 5];
+    let mut n = 0;
+    let mut x = data.len();
+    loop {
+        len[n] = (x & 0x7f) as u8;
+        x >>= 7;
+        n += 1;
+        if x == 0 {
+            break;
+        }
+        len[n - 1] |= 0x80;
+    }
+
+    let size = 1 + n + data.len();
+    let mut buf = BytesMut::with_capacity(5 + size);
+    buf.put_u8(0);
+    buf.put_u32(size as u32);
+    buf.put_u8(DATA_TAG);
+    buf.put_slice(&len[..n]);
+    buf.put_slice(data);
+    buf.freeze()
+}
+
+// ========== stream ==========
+/// Data of a gRPC tunnel.
+#[derive(Debug)]
+pub struct GrpcStream {
+    io: H2Stream,
+    // data left of the current message
+    left: usize,
+    head: [u8; 11],
+    head_len: usize,
+}
+
+impl GrpcStream {
+    #[inline]
+    const fn new(io: H2Stream) -> Self {
+        Self {
+            io,
+            left: 0,
+            head: [0u8; 11],
+            head_len: 0,
+        }
+    }
+
+    #[inline]
+    pub const fn get_ref(&self) -> &H2Stream { &self.io }
+}
+
+impl AsyncRead for GrpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // never read past the current part
+        macro_rules! read_to {
+            ($buf: expr) => {{
+                let mut part = ReadBuf::new($buf);
+                ready!(Pin::new(&mut this.io).poll_read(cx, &mut part))?;
+                part.filled().len()
+            }};
+        }
+
+        loop {
+            if this.left != 0 {
+                let len = this.left.min(buf.remaining());
+                let n = read_to!(buf.initialize_unfilled_to(len));
+                if n == 0 {
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+                buf.advance(n);
+                this.left -= n;
+                return Poll::Ready(Ok(()));
+            }
+
+            match parse_head(&this.head[..this.head_len])? {
+                Head::More(k) => {
+                    let n = read_to!(&mut this.head[this.head_len..this.head_len + k]);
+                    if n == 0 {
+                        return match this.head_len {
+                            0 => Poll::Ready(Ok(())),
+                            _ => Poll::Ready(Err(ErrorKind::UnexpectedEof.into())),
+                        };
+                    }
+                    this.head_len += n;
+                }
+                Head::Data(len) => {
+                    this.head_len = 0;
+                    this.left = len;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GrpcStream {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let buf = &buf[..buf.len().min(MAX_HUNK)];
+        self.get_mut().io.poll_send_with(cx, buf, encode)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// ========== client ==========
+#[derive(Debug, Clone)]
+pub struct GrpcConnect<T> {
+    conn: T,
+    conf: GrpcConf,
+    pool: Pool,
+}
+
+impl<T> Display for GrpcConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[grpc]{}", self.conn) }
+}
+
+impl<T> GrpcConnect<T> {
+    #[inline]
+    pub fn new(conn: T, conf: GrpcConf) -> Self {
+        Self {
+            conn,
+            conf,
+            pool: Pool::default(),
+        }
+    }
+
+    /// Open a tunnel on the shared connection, `None` if there is no live one.
+    pub async fn open(&self) -> Option<Result<GrpcStream>> {
+        let send = self.pool.get().await?;
+        Some(request(send, &self.conf).await)
+    }
+}
+
+impl<S, T> AsyncConnect<S> for GrpcConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+    T::Stream: Send,
+{
+    type Stream = GrpcStream;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // make a new connection, which replaces the shared one
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.conn.connect(stream, buf).await?;
+            let send = self.pool.connect(stream).await?;
+            request(send, &self.conf).await
+        }
+    }
+}
+
+async fn request(mut send: SendRequest<Bytes>, conf: &GrpcConf) -> Result<GrpcStream> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("https://{}{}", conf.host, conf.path()))
+        .header("content-type", CONTENT_TYPE)
+        .header("te", "trailers")
+        .body(())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    let (response, send) = send.send_request(req, false).map_err(into_io)?;
+    let response = response.await.map_err(into_io)?;
+    if response.status() != StatusCode::OK {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("grpc: unexpected status {}", response.status()),
+        ));
+    }
+
+    // a failed call may end with headers only
+    match response.headers().get("grpc-status") {
+        Some(x) if x != "0" => {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("grpc: status {}", x.to_str().unwrap_or_default()),
+            ))
+        }
+        _ => {}
+    }
+
+    Ok(GrpcStream::new(H2Stream::new(send, response.into_body())))
+}
+
+// ========== server ==========
+#[derive(Debug, Clone)]
+pub struct GrpcAccept<T> {
+    lis: T,
+    conf: GrpcConf,
+}
+
+impl<T> Display for GrpcAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[grpc]{}", self.lis) }
+}
+
+impl<T> GrpcAccept<T> {
+    #[inline]
+    pub const fn new(lis: T, conf: GrpcConf) -> Self { Self { lis, conf } }
+
+    /// Run the inner layers, for [`serve`](Self::serve).
+    #[inline]
+    pub async fn accept_inner<S>(&self, stream: S, buf: &mut [u8]) -> Result<T::Stream>
+    where
+        S: IOStream,
+        T: AsyncAccept<S>,
+    {
+        self.lis.accept(stream, buf).await
+    }
+
+    /// Start the h2 connection, then take tunnels from [`GrpcConn::accept`].
+    pub async fn serve<IO: IOStream>(&self, io: IO) -> Result<GrpcConn<IO>> {
+        let conn = server::handshake(io).await.map_err(into_io)?;
+        Ok(GrpcConn {
+            conn,
+            path: self.conf.path(),
+        })
+    }
+}
+
+impl<S, T> AsyncAccept<S> for GrpcAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+    T::Stream: Send,
+{
+    type Stream = GrpcStream;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // other tunnels of the connection are dropped
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let stream = self.lis.accept(stream, buf).await?;
+            let mut conn = self.serve(stream).await?;
+            let stream = match conn.accept().await {
+                Some(x) => x?,
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            };
+
+            // drive the connection
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+            Ok(stream)
+        }
+    }
+}
+
+/// Tunnels of an h2 connection.
+pub struct GrpcConn<T> {
+    conn: server::Connection<T, Bytes>,
+    path: String,
+}
+
+impl<T: IOStream> GrpcConn<T> {
+    /// Next tunnel, `None` once the connection closes.
+    ///
+    /// This also drives the connection, keep calling it while tunnels
+    /// are in use. Other requests get a `404` and are skipped.
+    pub async fn accept(&mut self) -> Option<Result<GrpcStream>> {
+        loop {
+            let (req, respond) = match self.conn.accept().await? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(into_io(e))),
+            };
+            if let Some(stream) = accept_call(req, respond, &self.path) {
+                return Some(Ok(stream));
+            }
+        }
+    }
+
+    /// Stop taking new tunnels, open ones are served until done.
+    #[inline]
+    pub fn graceful_shutdown(&mut self) { self.conn.graceful_shutdown() }
+}
+
+fn accept_call(
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    path: &str,
+) -> Option<GrpcStream> {
+    let is_grpc = req.method() == Method::POST
+        && req.uri().path() == path
+        && (req.headers().get("content-type"))
+            .is_some_and(|x| x.as_bytes().starts_with(CONTENT_TYPE.as_bytes()));
+
+    let status = if is_grpc {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    };
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    if is_grpc {
+        let headers = response.headers_mut();
+        headers.insert("content-type", HeaderValue::from_static(CONTENT_TYPE));
+    }
+    let send = respond.send_response(response, !is_grpc).ok()?;
+    if !is_grpc {
+        return None;
+    }
+
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+    let io = H2Stream::new(send, req.into_body()).with_trailers(trailers);
+    Some(GrpcStream::new(io))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::nop::{NopAccept, NopConnect};
+
+    fn conf(service: &str) -> GrpcConf {
+        GrpcConf {
+            host: String::from("abc"),
+            service: String::from(service),
+        }
+    }
+
+    #[test]
+    fn message() {
+        macro_rules! y {
+            ( $( $len: expr, )+ ) => {
+                $(
+                    let data = vec![7u8; $len];
+                    let msg = encode(&data);
+                    let head = &msg[..msg.len() - $len];
+                    // parsed once complete
+                    for i in 0..head.len() {
+                        assert!(matches!(parse_head(&head[..i]).unwrap(), Head::More(_)));
+                    }
+                    assert!(matches!(parse_head(head).unwrap(), Head::Data(n) if n == $len));
+                    assert_eq!(&msg[head.len()..], &data[..]);
+                )+
+            };
+        }
+
+        y![0, 1, 127, 128, 0x3fff, 0x4000, MAX_HUNK,];
+
+        assert_eq!(&encode(b"abc")[..], b"\x00\x00\x00\x00\x05\x0a\x03abc");
+        assert!(matches!(
+            parse_head(b"\x00\x00\x00\x00\x00").unwrap(),
+            Head::Data(0)
+        ));
+    }
+
+    #[test]
+    fn message_err() {
+        macro_rules! n {
+            ( $( $head: expr, )+ ) => {
+                $(
+                    let err = parse_head($head).err().unwrap();
+                    assert_eq!(err.kind(), ErrorKind::InvalidData);
+                )+
+            };
+        }
+
+        n![
+            b"\x01\x00\x00\x00\x05\x0a\x03",
+            b"\x00\x00\x00\x00\x05\x12\x03",
+            b"\x00\x00\x00\x00\x06\x0a\x03",
+            b"\x00\x00\x00\x00\x05\x0a\x80\x80\x80\x80\x80",
+        ];
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        // messages span h2 frames, and reads stop within them
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = GrpcConnect::new(NopConnect {}, conf("GunService"));
+        let lis = GrpcAccept::new(NopAccept {}, conf("GunService"));
+
+        let mut buf1 = vec![0u8; 0x1000];
+        let mut buf2 = vec![0u8; 0x1000];
+        let (c, s) = tokio::join!(
+            conn.connect(client, &mut buf1),
+            lis.accept(server, &mut buf2)
+        );
+        let (mut c, mut s) = (c.unwrap(), s.unwrap());
+
+        let data: Vec<u8> = (0..0x30000).map(|x| x as u8).collect();
+        macro_rules! run {
+            ($from: expr, $to: expr) => {{
+                let send = async {
+                    for chunk in data.chunks(0x5000) {
+                        $from.write_all(chunk).await.unwrap();
+                    }
+                    $from.shutdown().await.unwrap();
+                };
+                let recv = async {
+                    let mut recv = Vec::new();
+                    $to.read_to_end(&mut recv).await.unwrap();
+                    recv
+                };
+                let (_, recv) = tokio::join!(send, recv);
+                assert_eq!(recv, data);
+            }};
+        }
+
+        run!(c, s);
+        run!(s, c);
+    }
+
+    #[tokio::test]
+    async fn multiplex() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let lis = GrpcAccept::new(NopAccept {}, conf("abc"));
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0u8; 0x1000];
+            let io = lis.accept_inner(server, &mut buf).await.unwrap();
+            let mut conn = lis.serve(io).await.unwrap();
+            while let Some(Ok(mut stream)) = conn.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 0x100];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    stream.write_all(&buf[..n]).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let conn = GrpcConnect::new(NopConnect {}, conf("abc"));
+        assert!(conn.open().await.is_none());
+
+        let mut buf = vec![0u8; 0x1000];
+        let a = conn.connect(client, &mut buf).await.unwrap();
+        let b = conn.open().await.unwrap().unwrap();
+        let c = conn.open().await.unwrap().unwrap();
+
+        // answered in reverse
+        for (mut x, data) in [(c, b"c"), (b, b"b"), (a, b"a")] {
+            x.write_all(data).await.unwrap();
+            let mut recv = Vec::new();
+            x.read_to_end(&mut recv).await.unwrap();
+            assert_eq!(recv, data);
+        }
+
+        // another service
+        let other = GrpcConnect {
+            conf: conf("xyz"),
+            ..conn.clone()
+        };
+        let err = other.open().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(err.to_string(), "grpc: unexpected status 404 Not Found");
+
+        drop((conn, other));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Plumbing shared by transports over h2.
+//!
+//! [`H2Stream`] reads and writes the body of an h2 stream. A client
+//! keeps one connection in a pool, shared by all its tunnels until
+//! it closes. A server that also speaks HTTP/1.1 looks for the preface
+//! first, then replays it with [`Rewind`].
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use h2::{RecvStream, SendStream};
+use http::HeaderMap;
+
+use super::IOStream;
+
+#[cfg(any(feature = "ws2", feature = "split"))]
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub(crate) fn into_io(e: h2::Error) -> Error {
+    match e.is_io() {
+        true => e.into_io().unwrap(),
+        false => Error::other(e),
+    }
+}
+
+// ========== stream ==========
+/// Body of an h2 stream.
+#[derive(Debug)]
+pub struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    // received but not read
+    buf: Bytes,
+    // sent on shutdown, instead of an empty frame
+    trailers: Option<HeaderMap>,
+    eof_sent: bool,
+}
+
+impl H2Stream {
+    #[inline]
+    pub(crate) const fn new(send: SendStream<Bytes>, recv: RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            buf: Bytes::new(),
+            trailers: None,
+            eof_sent: false,
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[inline]
+    pub(crate) fn with_trailers(mut self, trailers: HeaderMap) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    /// Send as much of `buf` as the peer's window allows, each piece
+    /// encoded by `f`. Returns the length taken from `buf`.
+    pub(crate) fn poll_send_with<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        f: F,
+    ) -> Poll<Result<usize>>
+    where
+        F: FnOnce(&[u8]) -> Bytes,
+    {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // wait for the peer's window
+        self.send.reserve_capacity(buf.len());
+        let n = loop {
+            match ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(0)) => continue,
+                Some(Ok(n)) => break n.min(buf.len()),
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+                None => return Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+            }
+        };
+
+        self.send.send_data(f(&buf[..n]), false).map_err(into_io)?;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        poll_read_body(&mut this.recv, &mut this.buf, cx, buf)
+    }
+}
+
+/// Read an h2 body, keep what does not fit in `rest`.
+pub(crate) fn poll_read_body(
+    recv: &mut RecvStream,
+    rest: &mut Bytes,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<Result<()>> {
+    while rest.is_empty() {
+        match ready!(recv.poll_data(cx)) {
+            Some(Ok(data)) => {
+                let _ = recv.flow_control().release_capacity(data.len());
+                *rest = data;
+            }
+            Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            None => return Poll::Ready(Ok(())),
+        }
+    }
+
+    let n = rest.len().min(buf.remaining());
+    buf.put_slice(&rest.split_to(n));
+    Poll::Ready(Ok(()))
+}
+
+impl AsyncWrite for H2Stream {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.get_mut()
+            .poll_send_with(cx, buf, Bytes::copy_from_slice)
+    }
+
+    // sent by the connection
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if !this.eof_sent {
+            this.eof_sent = true;
+            match this.trailers.take() {
+                Some(trailers) => this.send.send_trailers(trailers),
+                None => this.send.send_data(Bytes::new(), true),
+            }
+            .map_err(into_io)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// ========== client ==========
+/// Client connection shared by clones.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pool(Arc<Mutex<Option<SendRequest<Bytes>>>>);
+
+impl Pool {
+    /// The shared connection, ready for a new stream.
+    /// `None` if there is no live one.
+    pub(crate) async fn get(&self) -> Option<SendRequest<Bytes>> {
+        let send = self.0.lock().unwrap().clone()?;
+        match send.ready().await {
+            Ok(send) => Some(send),
+            Err(_) => {
+                self.0.lock().unwrap().take();
+                None
+            }
+        }
+    }
+
+    /// Make a new connection, which replaces the shared one.
+    pub(crate) async fn connect<IO>(&self, io: IO) -> Result<SendRequest<Bytes>>
+    where
+        IO: IOStream + Send,
+    {
+        let (send, conn) = h2::client::handshake(io).await.map_err(into_io)?;
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+
+        *self.0.lock().unwrap() = Some(send.clone());
+        send.ready().await.map_err(into_io)
+    }
+}
+
+// ========== server ==========
+// read until the preface or a mismatch, return the length
+#[cfg(any(feature = "ws2", feature = "split"))]
+pub(crate) async fn read_preface<IO>(io: &mut IO, buf: &mut [u8]) -> Result<(usize, bool)>
+where
+    IO: AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut offset = 0;
+    loop {
+        let n = offset.min(PREFACE.len());
+        if buf[..n] != PREFACE[..n] {
+            return Ok((offset, false));
+        }
+        if n == PREFACE.len() {
+            return Ok((offset, true));
+        }
+
+        let n = io.read(&mut buf[offset..]).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        offset += n;
+    }
+}
+
+// ========== rewind ==========
+/// Stream that replays what was read while looking for the preface.
+#[cfg(any(feature = "ws2", feature = "split"))]
+#[derive(Debug)]
+pub struct Rewind<T> {
+    io: T,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(any(feature = "ws2", feature = "split"))]
+impl<T> Rewind<T> {
+    #[inline]
+    pub(crate) fn new(io: T, buf: &[u8]) -> Self {
+        Self {
+            io,
+            buf: buf.to_vec(),
+            pos: 0,
+        }
+    }
+
+    #[inline]
+    pub const fn get_ref(&self) -> &T { &self.io }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T { &mut self.io }
+}
+
+#[cfg(any(feature = "ws2", feature = "split"))]
+impl<T> AsyncRead for Rewind<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if this.pos < this.buf.len() {
+            let n = (this.buf.len() - this.pos).min(buf.remaining());
+            buf.put_slice(&this.buf[this.pos..this.pos + n]);
+            this.pos += n;
+            if this.pos == this.buf.len() {
+                this.buf = Vec::new();
+                this.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+#[cfg(any(feature = "ws2", feature = "split"))]
+impl<T> AsyncWrite for Rewind<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool { self.io.is_write_vectored() }
+}
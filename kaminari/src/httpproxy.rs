@@ -0,0 +1,249 @@
+//! Dial through an HTTP proxy with `CONNECT`.
+//!
+//! The caller connects to the proxy, then [`HttpProxyConnect`] asks it
+//! for a tunnel to the target, with basic auth if set. It runs before
+//! the other layers, and keeps the stream type, so tls or websocket go
+//! on top as if connected directly. The response is read exactly, bytes
+//! after it are left to the outer layers.
+
+use std::io::{Error, ErrorKind, Result};
+use std::future::Future;
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use super::{IOStream, AsyncConnect};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpProxyConf {
+    // host:port of the proxy, dialed by the caller
+    pub proxy: String,
+    // user:password for basic auth
+    pub auth: String,
+}
+
+impl Display for HttpProxyConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "proxy: {}", self.proxy)?;
+
+        // hide the password
+        if let Some((user, _)) = self.auth.split_once(':') {
+            write!(f, ", user: {user}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpProxyConnect<T> {
+    conn: T,
+    conf: HttpProxyConf,
+    // host:port to reach through the proxy
+    target: String,
+}
+
+impl<T> Display for HttpProxyConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[httpproxy]{}", self.conn)
+    }
+}
+
+impl<T> HttpProxyConnect<T> {
+    #[inline]
+    pub const fn new(conn: T, conf: HttpProxyConf, target: String) -> Self {
+        Self { conn, conf, target }
+    }
+}
+
+impl<S, T> AsyncConnect<S> for HttpProxyConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+{
+    type Stream = T::Stream;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let mut stream = self.conn.connect(stream, buf).await?;
+            handshake(&mut stream, buf, &self.target, &self.conf).await?;
+            Ok(stream)
+        }
+    }
+}
+
+fn encode_request(target: &str, conf: &HttpProxyConf) -> String {
+    let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if !conf.auth.is_empty() {
+        let auth = STANDARD.encode(&conf.auth);
+        req.push_str(&format!("Proxy-Authorization: Basic {auth}\r\n"));
+    }
+    req.push_str("\r\n");
+    req
+}
+
+async fn handshake<IO>(
+    io: &mut IO,
+    buf: &mut [u8],
+    target: &str,
+    conf: &HttpProxyConf,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    // send
+    io.write_all(encode_request(target, conf).as_bytes())
+        .await?;
+
+    // recv, byte by byte to not read past the head
+    let mut offset = 0;
+    while !buf[..offset].ends_with(b"\r\n\r\n") {
+        if offset == buf.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "httpproxy: response too large",
+            ));
+        }
+        if io.read(&mut buf[offset..offset + 1]).await? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        offset += 1;
+    }
+
+    // check
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut response = httparse::Response::new(&mut headers);
+    if response.parse(&buf[..offset]).is_err() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "httpproxy: invalid response",
+        ));
+    }
+
+    match response.code.unwrap_or_default() {
+        200..=299 => Ok(()),
+        407 => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "httpproxy: proxy authentication required",
+        )),
+        code => Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "httpproxy: unexpected status {} {}",
+                code,
+                response.reason.unwrap_or_default()
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nop::NopConnect;
+
+    // answers CONNECT, then echoes
+    async fn proxy<IO>(mut io: IO, auth: &str)
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; 0x1000];
+        let mut offset = 0;
+        while !buf[..offset].ends_with(b"\r\n\r\n") {
+            offset += io.read(&mut buf[offset..]).await.unwrap();
+        }
+
+        let req = std::str::from_utf8(&buf[..offset]).unwrap();
+        assert!(req.starts_with("CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\n"));
+        let expect = match auth {
+            "" => String::new(),
+            _ => format!("Proxy-Authorization: Basic {}", STANDARD.encode(auth)),
+        };
+        if !req.contains(&expect) || (expect.is_empty() && req.contains("Proxy-Authorization")) {
+            let _ = io
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await;
+            return;
+        }
+
+        // sent along with the response
+        io.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+            .await
+            .unwrap();
+        let n = io.read(&mut buf).await.unwrap();
+        io.write_all(&buf[..n]).await.unwrap();
+    }
+
+    fn conf(auth: &str) -> HttpProxyConf {
+        HttpProxyConf {
+            proxy: String::from("127.0.0.1:3128"),
+            auth: String::from(auth),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect() {
+        for auth in ["", "user:p@ss:word"] {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let conn =
+                HttpProxyConnect::new(NopConnect {}, conf(auth), String::from("127.0.0.1:443"));
+            tokio::spawn(async move { proxy(server, auth).await });
+
+            let mut buf = vec![0u8; 0x1000];
+            let mut stream = conn.connect(client, &mut buf).await.unwrap();
+
+            let mut recv = [0u8; 5];
+            stream.read_exact(&mut recv).await.unwrap();
+            assert_eq!(&recv, b"hello");
+
+            stream.write_all(b"ping").await.unwrap();
+            let mut recv = [0u8; 4];
+            stream.read_exact(&mut recv).await.unwrap();
+            assert_eq!(&recv, b"ping");
+        }
+    }
+
+    #[tokio::test]
+    async fn reject() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = HttpProxyConnect::new(
+            NopConnect {},
+            conf("user:bad"),
+            String::from("127.0.0.1:443"),
+        );
+        tokio::spawn(async move { proxy(server, "user:pass").await });
+
+        let mut buf = vec![0u8; 0x1000];
+        let err = conn.connect(client, &mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let (client, mut server) = tokio::io::duplex(0x1000);
+        let conn = HttpProxyConnect::new(NopConnect {}, conf(""), String::from("127.0.0.1:443"));
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 0x1000];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let err = conn.connect(client, &mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(
+            err.to_string(),
+            "httpproxy: unexpected status 403 Forbidden"
+        );
+    }
+}
@@ -31,6 +31,24 @@ pub mod trick;
 #[cfg(feature = "ws")]
 pub mod ws;
 
+#[cfg(feature = "http2")]
+pub mod http2;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "post")]
+pub mod post;
+
+#[cfg(feature = "split")]
+pub mod split;
+
+#[cfg(feature = "upgrade")]
+pub mod upgrade;
+
+#[cfg(feature = "httpproxy")]
+pub mod httpproxy;
+
 #[cfg(feature = "tls")]
 pub mod tls;
 
@@ -40,5 +58,11 @@ pub mod uot;
 #[cfg(feature = "mix")]
 pub mod mix;
 
+#[cfg(feature = "proxy")]
+pub mod proxy;
+
+#[cfg(feature = "fallback")]
+pub mod fallback;
+
 #[cfg(feature = "tls")]
 pub use tls::install_provider as install_tls_provider;
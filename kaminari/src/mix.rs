@@ -4,8 +4,12 @@ use std::fmt::{Display, Formatter};
 
 use super::{IOStream, AsyncAccept, AsyncConnect};
 use super::nop::{NopAccept, NopConnect};
-use super::ws::{WsConf, WsAccept, WsConnect};
-use super::tls::{TlsClientConf, TlsServerConf, TlsAccept, TlsConnect};
+use super::ws::{WsConf, WsAccept, WsConnect, Dynamic};
+use super::tls::{TlsClientConf, TlsServerConf};
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+use super::tls::{TlsAccept, TlsConnect};
+#[cfg(not(any(feature = "tls-ring", feature = "tls-awslc")))]
+use super::tls::openssl::{TlsAccept, TlsConnect};
 
 // ========== client ==========
 #[derive(Debug, Clone)]
@@ -17,9 +21,9 @@ pub struct MixClientConf {
 #[derive(Debug, Clone)]
 pub enum MixConnect {
     Plain(NopConnect),
-    Ws(WsConnect<NopConnect>),
+    Ws(WsConnect<NopConnect, Dynamic>),
     Tls(TlsConnect<NopConnect>),
-    Wss(WsConnect<TlsConnect<NopConnect>>),
+    Wss(WsConnect<TlsConnect<NopConnect>, Dynamic>),
 }
 
 impl MixConnect {
@@ -28,9 +32,11 @@ impl MixConnect {
         let MixClientConf { ws, tls } = conf;
         match (ws, tls) {
             (None, None) => Plain(NopConnect {}),
-            (Some(ws), None) => Ws(WsConnect::new(NopConnect {}, ws)),
+            (Some(ws), None) => Ws(WsConnect::new(NopConnect {}, ws).dynamic()),
             (None, Some(tls)) => Tls(TlsConnect::new(NopConnect {}, tls)),
-            (Some(ws), Some(tls)) => Wss(WsConnect::new(TlsConnect::new(NopConnect {}, tls), ws)),
+            (Some(ws), Some(tls)) => {
+                Wss(WsConnect::new(TlsConnect::new(NopConnect {}, tls), ws).dynamic())
+            }
         }
     }
 
@@ -39,12 +45,11 @@ impl MixConnect {
         let MixClientConf { ws, tls } = conf;
         match (ws, tls) {
             (None, None) => Plain(NopConnect {}),
-            (Some(ws), None) => Ws(WsConnect::new(NopConnect {}, ws)),
+            (Some(ws), None) => Ws(WsConnect::new(NopConnect {}, ws).dynamic()),
             (None, Some(tls)) => Tls(TlsConnect::new_shared(NopConnect {}, tls)),
-            (Some(ws), Some(tls)) => Wss(WsConnect::new(
-                TlsConnect::new_shared(NopConnect {}, tls),
-                ws,
-            )),
+            (Some(ws), Some(tls)) => {
+                Wss(WsConnect::new(TlsConnect::new_shared(NopConnect {}, tls), ws).dynamic())
+            }
         }
     }
 }
@@ -113,7 +118,7 @@ impl MixAccept {
     }
 }
 
-impl<S: IOStream> AsyncAccept<S> for MixAccept {
+impl<S: IOStream + Send> AsyncAccept<S> for MixAccept {
     type Stream = stream::MixServerStream<S>;
 
     type AcceptFut<'a>
@@ -144,15 +149,19 @@ mod stream {
     use std::pin::Pin;
     use std::task::{Poll, Context};
     use tokio::io::{ReadBuf, AsyncRead, AsyncWrite};
-    use crate::ws::{WsClientStream, WsServerStream};
+    use crate::ws::{WsDynamicClientStream, WsServerStream};
+    use crate::ws::route::Routed;
+    #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
     use crate::tls::{TlsClientStream, TlsServerStream};
+    #[cfg(not(any(feature = "tls-ring", feature = "tls-awslc")))]
+    use crate::tls::openssl::{TlsClientStream, TlsServerStream};
 
     #[derive(Debug)]
     pub enum MixClientStream<T> {
         Plain(T),
-        Ws(WsClientStream<T>),
+        Ws(WsDynamicClientStream<T>),
         Tls(TlsClientStream<T>),
-        Wss(WsClientStream<TlsClientStream<T>>),
+        Wss(WsDynamicClientStream<TlsClientStream<T>>),
     }
 
     #[derive(Debug)]
@@ -224,6 +233,17 @@ mod stream {
     impl_async_write!(MixClientStream);
     impl_async_read!(MixServerStream);
     impl_async_write!(MixServerStream);
+
+    impl<T> Routed for MixServerStream<T> {
+        fn route(&self) -> Option<usize> {
+            use MixServerStream::*;
+            match self {
+                Plain(_) | Tls(_) => None,
+                Ws(x) => x.route(),
+                Wss(x) => x.route(),
+            }
+        }
+    }
 }
 
 // ========== type cast ==========
@@ -248,9 +268,9 @@ macro_rules! impl_type_cast {
 impl_type_cast!(
     MixConnect ||
         [as_plain :: Plain => NopConnect],
-        [as_ws :: Ws => WsConnect<NopConnect>],
+        [as_ws :: Ws => WsConnect<NopConnect, Dynamic>],
         [as_tls :: Tls => TlsConnect<NopConnect>],
-        [as_wss :: Wss => WsConnect<TlsConnect<NopConnect>>],
+        [as_wss :: Wss => WsConnect<TlsConnect<NopConnect>, Dynamic>],
 );
 
 impl_type_cast!(
@@ -291,12 +311,17 @@ mod test {
             ws: Some(WsConf {
                 host: String::from("abc"),
                 path: String::from("chat"),
+                ..Default::default()
             }),
             tls: Some(TlsClientConf {
                 sni: String::from("abc"),
                 alpn: vec![Vec::from("h2"), Vec::from("http/1.1")],
                 insecure: true,
                 early_data: true,
+                ca: String::new(),
+                crt: String::new(),
+                key: String::new(),
+                crl: String::new(),
             }),
         };
 
@@ -314,12 +339,17 @@ mod test {
             ws: Some(WsConf {
                 host: String::from("abc"),
                 path: String::from("chat"),
+                ..Default::default()
             }),
             tls: Some(TlsServerConf {
                 crt: String::new(),
                 key: String::new(),
                 ocsp: String::new(),
                 server_name: String::from("abc"),
+                ca: String::new(),
+                self_signed: Default::default(),
+                client_ca: String::new(),
+                crl: String::new(),
             }),
         };
 
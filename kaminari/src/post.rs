@@ -0,0 +1,380 @@
+//! HTTP/2 tunnel, which looks like a long-lived upload.
+//!
+//! Each tunnel is a `POST` to `path`. The request body carries data
+//! from the client, and the response body carries data from the server,
+//! without any framing.
+//!
+//! [`PostConnect`] makes an h2 connection on each [`AsyncConnect::connect`],
+//! and [`PostConnect::open`] reuses it for more tunnels until it closes.
+//! [`PostAccept`] yields the first tunnel of each connection, use
+//! [`PostAccept::serve`] to take all of them. The client should negotiate
+//! `h2` by alpn, if over tls.
+
+use std::io::{Error, ErrorKind, Result};
+use std::future::Future;
+use std::fmt::{Display, Formatter};
+
+use bytes::Bytes;
+use h2::RecvStream;
+use h2::client::SendRequest;
+use h2::server::{self, SendResponse};
+use http::{Method, Request, Response, StatusCode};
+
+use super::{IOStream, AsyncAccept, AsyncConnect};
+use super::http2::{H2Stream, Pool, into_io};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostConf {
+    pub host: String,
+    pub path: String,
+    // client: extra request headers, sent in order
+    pub headers: Vec<(String, String)>,
+    // server: required request headers
+    pub require_headers: Vec<(String, String)>,
+}
+
+impl Display for PostConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "host: {}, path: {}", self.host, self.path)?;
+
+        // values may carry credentials
+        if !self.headers.is_empty() {
+            let names: Vec<_> = self.headers.iter().map(|(k, _)| k.as_str()).collect();
+            write!(f, ", headers: {names:?}")?;
+        }
+
+        if !self.require_headers.is_empty() {
+            let names: Vec<_> = (self.require_headers.iter())
+                .map(|(k, _)| k.as_str())
+                .collect();
+            write!(f, ", require_headers: {names:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// ========== client ==========
+#[derive(Debug, Clone)]
+pub struct PostConnect<T> {
+    conn: T,
+    conf: PostConf,
+    pool: Pool,
+}
+
+impl<T> Display for PostConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[post]{}", self.conn) }
+}
+
+impl<T> PostConnect<T> {
+    #[inline]
+    pub fn new(conn: T, conf: PostConf) -> Self {
+        Self {
+            conn,
+            conf,
+            pool: Pool::default(),
+        }
+    }
+
+    /// Open a tunnel on the shared connection, `None` if there is no live one.
+    pub async fn open(&self) -> Option<Result<H2Stream>> {
+        let send = self.pool.get().await?;
+        Some(request(send, &self.conf).await)
+    }
+}
+
+impl<S, T> AsyncConnect<S> for PostConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+    T::Stream: Send,
+{
+    type Stream = H2Stream;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // make a new connection, which replaces the shared one
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.conn.connect(stream, buf).await?;
+            let send = self.pool.connect(stream).await?;
+            request(send, &self.conf).await
+        }
+    }
+}
+
+async fn request(mut send: SendRequest<Bytes>, conf: &PostConf) -> Result<H2Stream> {
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("https://{}{}", conf.host, conf.path));
+    for (k, v) in conf.headers.iter() {
+        req = req.header(k, v);
+    }
+    let req = req
+        .body(())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    let (response, send) = send.send_request(req, false).map_err(into_io)?;
+    let response = response.await.map_err(into_io)?;
+    if response.status() != StatusCode::OK {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("post: unexpected status {}", response.status()),
+        ));
+    }
+
+    Ok(H2Stream::new(send, response.into_body()))
+}
+
+// ========== server ==========
+#[derive(Debug, Clone)]
+pub struct PostAccept<T> {
+    lis: T,
+    conf: PostConf,
+}
+
+impl<T> Display for PostAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[post]{}", self.lis) }
+}
+
+impl<T> PostAccept<T> {
+    #[inline]
+    pub const fn new(lis: T, conf: PostConf) -> Self { Self { lis, conf } }
+
+    /// Run the inner layers, for [`serve`](Self::serve).
+    #[inline]
+    pub async fn accept_inner<S>(&self, stream: S, buf: &mut [u8]) -> Result<T::Stream>
+    where
+        S: IOStream,
+        T: AsyncAccept<S>,
+    {
+        self.lis.accept(stream, buf).await
+    }
+
+    /// Start the h2 connection, then take tunnels from [`PostConn::accept`].
+    pub async fn serve<IO: IOStream>(&self, io: IO) -> Result<PostConn<'_, IO>> {
+        let conn = server::handshake(io).await.map_err(into_io)?;
+        Ok(PostConn {
+            conn,
+            conf: &self.conf,
+        })
+    }
+}
+
+impl<S, T> AsyncAccept<S> for PostAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+    T::Stream: Send,
+{
+    type Stream = H2Stream;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // other tunnels of the connection are dropped
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let stream = self.lis.accept(stream, buf).await?;
+            let mut conn = self.serve(stream).await?;
+            let stream = match conn.accept().await {
+                Some(x) => x?,
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            };
+
+            // drive the connection
+            let PostConn { mut conn, .. } = conn;
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+            Ok(stream)
+        }
+    }
+}
+
+/// Tunnels of an h2 connection.
+pub struct PostConn<'a, T> {
+    conn: server::Connection<T, Bytes>,
+    conf: &'a PostConf,
+}
+
+impl<T: IOStream> PostConn<'_, T> {
+    /// Next tunnel, `None` once the connection closes.
+    ///
+    /// This also drives the connection, keep calling it while tunnels
+    /// are in use. Rejected requests are answered here and skipped.
+    pub async fn accept(&mut self) -> Option<Result<H2Stream>> {
+        loop {
+            let (req, respond) = match self.conn.accept().await? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(into_io(e))),
+            };
+            if let Some(stream) = accept_stream(req, respond, self.conf) {
+                return Some(Ok(stream));
+            }
+        }
+    }
+
+    /// Stop taking new tunnels, open ones are served until done.
+    #[inline]
+    pub fn graceful_shutdown(&mut self) { self.conn.graceful_shutdown() }
+}
+
+fn accept_stream(
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    conf: &PostConf,
+) -> Option<H2Stream> {
+    let (parts, body) = req.into_parts();
+    let host = parts.uri.authority().map_or("", |x| x.as_str());
+    let path = parts.uri.path_and_query().map_or("/", |x| x.as_str());
+
+    let status = if parts.method != Method::POST || host != conf.host || path != conf.path {
+        StatusCode::NOT_FOUND
+    } else if (conf.require_headers.iter())
+        .any(|(k, v)| parts.headers.get(k).is_none_or(|x| x != v.as_str()))
+    {
+        StatusCode::FORBIDDEN
+    } else {
+        StatusCode::OK
+    };
+
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    let is_ok = status == StatusCode::OK;
+    let send = respond.send_response(response, !is_ok).ok()?;
+    if !is_ok {
+        return None;
+    }
+
+    Some(H2Stream::new(send, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::nop::{NopAccept, NopConnect};
+
+    fn conf(path: &str, headers: &[(&str, &str)]) -> PostConf {
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect();
+        PostConf {
+            host: String::from("abc"),
+            path: String::from(path),
+            headers: headers.clone(),
+            require_headers: headers,
+        }
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = PostConnect::new(NopConnect {}, conf("/up", &[("x-token", "t0k")]));
+        let lis = PostAccept::new(NopAccept {}, conf("/up", &[("x-token", "t0k")]));
+
+        let mut buf1 = vec![0u8; 0x1000];
+        let mut buf2 = vec![0u8; 0x1000];
+        let (c, s) = tokio::join!(
+            conn.connect(client, &mut buf1),
+            lis.accept(server, &mut buf2)
+        );
+        let (mut c, mut s) = (c.unwrap(), s.unwrap());
+
+        let data: Vec<u8> = (0..0x30000).map(|x| x as u8).collect();
+        macro_rules! run {
+            ($from: expr, $to: expr) => {{
+                let send = async {
+                    for chunk in data.chunks(0x5000) {
+                        $from.write_all(chunk).await.unwrap();
+                    }
+                    $from.shutdown().await.unwrap();
+                };
+                let recv = async {
+                    let mut recv = Vec::new();
+                    $to.read_to_end(&mut recv).await.unwrap();
+                    recv
+                };
+                let (_, recv) = tokio::join!(send, recv);
+                assert_eq!(recv, data);
+            }};
+        }
+
+        run!(c, s);
+        run!(s, c);
+    }
+
+    #[tokio::test]
+    async fn reject() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let lis = PostAccept::new(NopAccept {}, conf("/up", &[("x-token", "t0k")]));
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0u8; 0x1000];
+            let io = lis.accept_inner(server, &mut buf).await.unwrap();
+            let mut conn = lis.serve(io).await.unwrap();
+            while let Some(Ok(mut stream)) = conn.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 0x100];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    stream.write_all(&buf[..n]).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let conn = PostConnect::new(NopConnect {}, conf("/up", &[("x-token", "t0k")]));
+        let mut buf = vec![0u8; 0x1000];
+        let mut a = conn.connect(client, &mut buf).await.unwrap();
+
+        macro_rules! n {
+            ( $( ($conf: expr, $status: expr), )+ ) => {
+                $({
+                    let other = PostConnect {
+                        conf: $conf,
+                        ..conn.clone()
+                    };
+                    let err = other.open().await.unwrap().unwrap_err();
+                    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+                    assert_eq!(err.to_string(), format!("post: unexpected status {}", $status));
+                })+
+            };
+        }
+
+        n![
+            (conf("/down", &[("x-token", "t0k")]), "404 Not Found"),
+            (conf("/up?a=b", &[("x-token", "t0k")]), "404 Not Found"),
+            (
+                PostConf {
+                    host: String::from("xyz"),
+                    ..conf("/up", &[("x-token", "t0k")])
+                },
+                "404 Not Found"
+            ),
+            (conf("/up", &[]), "403 Forbidden"),
+            (conf("/up", &[("x-token", "abc")]), "403 Forbidden"),
+        ];
+
+        // the connection is still usable
+        let mut b = conn.open().await.unwrap().unwrap();
+        for (x, data) in [(&mut b, b"b"), (&mut a, b"a")] {
+            x.write_all(data).await.unwrap();
+            let mut recv = Vec::new();
+            x.read_to_end(&mut recv).await.unwrap();
+            assert_eq!(recv, data);
+        }
+
+        drop((conn, a, b));
+        server.await.unwrap();
+    }
+}
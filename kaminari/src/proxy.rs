@@ -0,0 +1,554 @@
+//! HAProxy PROXY protocol.
+//!
+//! [`ProxyAccept`] reads a v1 or v2 header before the inner layers,
+//! and keeps the addresses on [`ProxyStream`]. The header is read
+//! exactly, bytes after it are left to the inner layers.
+//!
+//! [`encode_v2`] makes a header which could be sent to backends,
+//! with the address from [`ClientAddr`].
+//!
+//! Spec: <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::str::FromStr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::task::{Context, Poll};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use super::{IOStream, AsyncAccept};
+
+pub const V2_SIG: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// PROXY TCP6 <39> <39> <5> <5>\r\n
+const V1_MAX: usize = 107;
+
+// ========== server ==========
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyAccept<T> {
+    lis: T,
+}
+
+impl<T> Display for ProxyAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[proxy]{}", self.lis) }
+}
+
+impl<T> ProxyAccept<T> {
+    #[inline]
+    pub const fn new(lis: T) -> Self { Self { lis } }
+}
+
+impl<S, T> AsyncAccept<S> for ProxyAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+{
+    type Stream = ProxyStream<T::Stream>;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn accept<'a>(&'a self, mut stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let addr = read_header(&mut stream, buf).await?;
+            let stream = self.lis.accept(stream, buf).await?;
+            Ok(ProxyStream { io: stream, addr })
+        }
+    }
+}
+
+// ========== header ==========
+#[inline]
+fn invalid(msg: &'static str) -> Error { Error::new(ErrorKind::InvalidData, msg) }
+
+/// Read a v1 or v2 header, returns (src, dst).
+///
+/// Returns `None` for `UNKNOWN`, `LOCAL` or unix sockets.
+pub async fn read_header<S>(io: &mut S, buf: &mut [u8]) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    S: AsyncRead + Unpin,
+{
+    if buf.len() < V1_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "proxy: buffer too small",
+        ));
+    }
+
+    // "PROXY UNKNOWN\r\n" is shorter than a v2 header
+    io.read_exact(&mut buf[..8]).await?;
+
+    if buf[..8] == V2_SIG[..8] {
+        read_v2(io, buf).await
+    } else if buf.starts_with(b"PROXY ") {
+        read_v1(io, buf).await
+    } else {
+        Err(invalid("proxy: no header"))
+    }
+}
+
+async fn read_v1<S>(io: &mut S, buf: &mut [u8]) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut n = 8;
+
+    // do not read beyond the line
+    while !buf[..n].ends_with(b"\r\n") {
+        if n == V1_MAX {
+            return Err(invalid("proxy: v1 header too long"));
+        }
+        io.read_exact(&mut buf[n..n + 1]).await?;
+        n += 1;
+    }
+
+    parse_v1(&buf[..n])
+}
+
+fn parse_v1(b: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let s = b
+        .strip_suffix(b"\r\n")
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .ok_or_else(|| invalid("proxy: invalid v1 header"))?;
+
+    let mut it = s.split(' ');
+    if it.next() != Some("PROXY") {
+        return Err(invalid("proxy: invalid v1 header"));
+    }
+
+    let v4 = match it.next() {
+        Some("TCP4") => true,
+        Some("TCP6") => false,
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("proxy: invalid v1 protocol")),
+    };
+
+    let fields: Vec<&str> = it.collect();
+    let [src, dst, sport, dport] = fields[..] else {
+        return Err(invalid("proxy: invalid v1 header"));
+    };
+
+    let ip = |x: &str| {
+        IpAddr::from_str(x)
+            .ok()
+            .filter(|ip| ip.is_ipv4() == v4)
+            .ok_or_else(|| invalid("proxy: invalid v1 address"))
+    };
+    let port = |x: &str| u16::from_str(x).map_err(|_| invalid("proxy: invalid v1 port"));
+
+    let src = SocketAddr::new(ip(src)?, port(sport)?);
+    let dst = SocketAddr::new(ip(dst)?, port(dport)?);
+    Ok(Some((src, dst)))
+}
+
+async fn read_v2<S>(io: &mut S, buf: &mut [u8]) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    S: AsyncRead + Unpin,
+{
+    io.read_exact(&mut buf[8..16]).await?;
+
+    if buf[8..12] != V2_SIG[8..12] {
+        return Err(invalid("proxy: invalid v2 signature"));
+    }
+
+    if buf[12] >> 4 != 2 {
+        return Err(invalid("proxy: invalid v2 version"));
+    }
+
+    let local = match buf[12] & 0x0f {
+        0 => true,
+        1 => false,
+        _ => return Err(invalid("proxy: invalid v2 command")),
+    };
+    let family = buf[13] >> 4;
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    let addr_len = match family {
+        1 => 12,
+        2 => 36,
+        _ => 0,
+    };
+
+    if len < addr_len {
+        return Err(invalid("proxy: v2 header too short"));
+    }
+
+    io.read_exact(&mut buf[..addr_len]).await?;
+    let addr = parse_v2_addr(family, &buf[..addr_len]);
+
+    // skip tlvs
+    let mut left = len - addr_len;
+    while left > 0 {
+        let n = left.min(buf.len());
+        io.read_exact(&mut buf[..n]).await?;
+        left -= n;
+    }
+
+    Ok(if local { None } else { addr })
+}
+
+fn parse_v2_addr(family: u8, b: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    let port = |x: &[u8]| u16::from_be_bytes([x[0], x[1]]);
+    match family {
+        1 => {
+            let src: [u8; 4] = b[0..4].try_into().unwrap();
+            let dst: [u8; 4] = b[4..8].try_into().unwrap();
+            Some((
+                SocketAddr::new(src.into(), port(&b[8..])),
+                SocketAddr::new(dst.into(), port(&b[10..])),
+            ))
+        }
+        2 => {
+            let src: [u8; 16] = b[0..16].try_into().unwrap();
+            let dst: [u8; 16] = b[16..32].try_into().unwrap();
+            Some((
+                SocketAddr::new(src.into(), port(&b[32..])),
+                SocketAddr::new(dst.into(), port(&b[34..])),
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[inline]
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(x) => x.to_ipv6_mapped(),
+        IpAddr::V6(x) => x,
+    }
+}
+
+/// Make a v1 header.
+pub fn encode_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (proto, src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => ("TCP4", s.to_string(), d.to_string()),
+        (s, d) => ("TCP6", to_v6(s).to_string(), to_v6(d).to_string()),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        proto,
+        src_ip,
+        dst_ip,
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+/// Make a v2 header with the `PROXY` command over tcp.
+///
+/// Addresses of different families are sent as ipv6.
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + 36);
+    buf.extend_from_slice(&V2_SIG);
+    buf.push(0x21);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            buf.extend_from_slice(&[0x11, 0, 12]);
+            buf.extend_from_slice(&s.octets());
+            buf.extend_from_slice(&d.octets());
+        }
+        (s, d) => {
+            buf.extend_from_slice(&[0x21, 0, 36]);
+            buf.extend_from_slice(&to_v6(s).octets());
+            buf.extend_from_slice(&to_v6(d).octets());
+        }
+    }
+
+    buf.extend_from_slice(&src.port().to_be_bytes());
+    buf.extend_from_slice(&dst.port().to_be_bytes());
+    buf
+}
+
+// ========== stream ==========
+/// Accepted stream with addresses from the header.
+#[derive(Debug)]
+pub struct ProxyStream<T> {
+    io: T,
+    addr: Option<(SocketAddr, SocketAddr)>,
+}
+
+impl<T> ProxyStream<T> {
+    #[inline]
+    pub const fn get_ref(&self) -> &T { &self.io }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T { &mut self.io }
+
+    #[inline]
+    pub fn into_inner(self) -> T { self.io }
+
+    /// Source address from the header.
+    #[inline]
+    pub fn src(&self) -> Option<SocketAddr> { self.addr.map(|x| x.0) }
+
+    /// Destination address from the header.
+    #[inline]
+    pub fn dst(&self) -> Option<SocketAddr> { self.addr.map(|x| x.1) }
+}
+
+impl<T> AsyncRead for ProxyStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for ProxyStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// ========== client addr ==========
+/// Real client address, as seen by each layer of an accepted stream.
+pub trait ClientAddr {
+    /// `peer` is the address of the tcp connection.
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr;
+}
+
+impl ClientAddr for TcpStream {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { peer }
+}
+
+// the header comes before inner layers
+impl<T: ClientAddr> ClientAddr for ProxyStream<T> {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr {
+        self.io.client_addr(self.src().unwrap_or(peer))
+    }
+}
+
+#[cfg(feature = "ws")]
+mod ws {
+    use super::*;
+    use crate::ws::Accepted;
+    use crate::ws::realip::Forwarded;
+    use crate::ws::route::Routed;
+    use crate::ws::stream::WsStream;
+
+    impl<T: Routed> Routed for ProxyStream<T> {
+        #[inline]
+        fn route(&self) -> Option<usize> { self.io.route() }
+    }
+
+    impl<T: ClientAddr, R> ClientAddr for WsStream<T, R> {
+        #[inline]
+        fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.as_ref().client_addr(peer) }
+    }
+
+    impl<T: ClientAddr> ClientAddr for Accepted<T> {
+        #[inline]
+        fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().client_addr(peer) }
+    }
+
+    // headers come after inner layers, port 0 if unknown
+    impl<T: ClientAddr> ClientAddr for Forwarded<T> {
+        #[inline]
+        fn client_addr(&self, peer: SocketAddr) -> SocketAddr {
+            self.real_addr(self.get_ref().client_addr(peer))
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::*;
+    use tokio_rustls::server::TlsStream;
+
+    impl<T: ClientAddr> ClientAddr for TlsStream<T> {
+        #[inline]
+        fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().0.client_addr(peer) }
+    }
+
+    #[cfg(feature = "tls-openssl")]
+    impl<T: ClientAddr> ClientAddr for tokio_openssl::SslStream<T> {
+        #[inline]
+        fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().client_addr(peer) }
+    }
+}
+
+#[cfg(any(feature = "ws2", feature = "split"))]
+impl<T: ClientAddr> ClientAddr for crate::http2::Rewind<T> {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().client_addr(peer) }
+}
+
+// peer is the address of the h2 connection
+#[cfg(feature = "http2")]
+impl ClientAddr for crate::http2::H2Stream {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { peer }
+}
+
+// peer is the address of the GET
+#[cfg(feature = "split")]
+impl<T> ClientAddr for crate::split::SplitServerStream<T> {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { peer }
+}
+
+#[cfg(feature = "upgrade")]
+impl<T: ClientAddr> ClientAddr for crate::upgrade::UpgradeStream<T> {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().client_addr(peer) }
+}
+
+#[cfg(feature = "grpc")]
+impl ClientAddr for crate::grpc::GrpcStream {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().client_addr(peer) }
+}
+
+#[cfg(feature = "fallback")]
+impl<T: ClientAddr> ClientAddr for crate::fallback::Recorded<T> {
+    #[inline]
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr { self.get_ref().client_addr(peer) }
+}
+
+#[cfg(feature = "mix")]
+impl<T: ClientAddr> ClientAddr for crate::mix::MixServerStream<T> {
+    fn client_addr(&self, peer: SocketAddr) -> SocketAddr {
+        use crate::mix::MixServerStream::*;
+        match self {
+            Plain(x) => x.client_addr(peer),
+            Ws(x) => x.client_addr(peer),
+            Tls(x) => x.client_addr(peer),
+            Wss(x) => x.client_addr(peer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nop::NopAccept;
+    use tokio::io::AsyncWriteExt;
+
+    fn addr(s: &str) -> SocketAddr { s.parse().unwrap() }
+
+    // header followed by data
+    async fn accept(header: &[u8]) -> Result<(Option<(SocketAddr, SocketAddr)>, Vec<u8>)> {
+        let (mut client, server) = tokio::io::duplex(0x10000);
+        client.write_all(header).await?;
+        client.write_all(b"hello").await?;
+        drop(client);
+
+        let mut buf = vec![0u8; 0x200];
+        let lis = ProxyAccept::new(NopAccept {});
+        let mut stream = lis.accept(server, &mut buf).await?;
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await?;
+        Ok((stream.addr, data))
+    }
+
+    #[tokio::test]
+    async fn proxy_v1() {
+        macro_rules! y {
+            ( $( ($header: expr, $addr: expr); )+ ) => {
+                $(
+                    let (a, data) = accept($header).await.unwrap();
+                    let addr: Option<(&str, &str)> = $addr;
+                    assert_eq!(a, addr.map(|(s, d)| (self::addr(s), self::addr(d))));
+                    assert_eq!(data, b"hello");
+                )+
+            }
+        }
+
+        y![
+            (b"PROXY TCP4 1.1.1.1 2.2.2.2 1000 443\r\n", Some(("1.1.1.1:1000", "2.2.2.2:443")));
+            (b"PROXY TCP6 ::1 2001:db8::1 1000 443\r\n", Some(("[::1]:1000", "[2001:db8::1]:443")));
+            (b"PROXY UNKNOWN\r\n", None);
+            (b"PROXY UNKNOWN ::1 ::1 1 1\r\n", None);
+        ];
+    }
+
+    #[tokio::test]
+    async fn proxy_v2() {
+        let v4 = (addr("1.1.1.1:1000"), addr("2.2.2.2:443"));
+        let v6 = (addr("[::1]:1000"), addr("[2001:db8::1]:443"));
+        let mixed = (addr("1.1.1.1:1000"), addr("[2001:db8::1]:443"));
+        let mapped = (addr("[::ffff:1.1.1.1]:1000"), addr("[2001:db8::1]:443"));
+
+        for (x, expect) in [(v4, v4), (v6, v6), (mixed, mapped)] {
+            let (a, data) = accept(&encode_v2(x.0, x.1)).await.unwrap();
+            assert_eq!(a, Some(expect));
+            assert_eq!(data, b"hello");
+        }
+
+        // tlv
+        let mut header = encode_v2(v4.0, v4.1);
+        header[15] += 7;
+        header.extend_from_slice(&[0x04, 0, 4, b'a', b'b', b'c', b'd']);
+        let (a, data) = accept(&header).await.unwrap();
+        assert_eq!((a, data.as_slice()), (Some(v4), &b"hello"[..]));
+
+        // local
+        let mut header = encode_v2(v4.0, v4.1);
+        header[12] = 0x20;
+        let (a, data) = accept(&header).await.unwrap();
+        assert_eq!((a, data.as_slice()), (None, &b"hello"[..]));
+
+        // v1 roundtrip
+        let (a, _) = accept(&encode_v1(mixed.0, mixed.1)).await.unwrap();
+        assert_eq!(a, Some(mapped));
+    }
+
+    #[tokio::test]
+    async fn proxy_err() {
+        let mut bad_version = encode_v2(addr("1.1.1.1:1"), addr("2.2.2.2:2"));
+        bad_version[12] = 0x11;
+        let mut bad_len = encode_v2(addr("1.1.1.1:1"), addr("2.2.2.2:2"));
+        bad_len[15] = 4;
+
+        for header in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 1000\r\n",
+            b"PROXY TCP4 ::1 2.2.2.2 1000 443\r\n",
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 1000 65536\r\n",
+            b"PROXY TCP5 1.1.1.1 2.2.2.2 1000 443\r\n",
+            &[b"PROXY UNKNOWN ".as_slice(), &[b'a'; 120], b"\r\n"].concat(),
+            &bad_version,
+            &bad_len,
+        ] {
+            assert!(
+                accept(header).await.is_err(),
+                "{}",
+                String::from_utf8_lossy(header)
+            );
+        }
+    }
+}
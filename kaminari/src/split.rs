@@ -0,0 +1,1292 @@
+//! Split HTTP transport, for cdns that buffer uploads or refuse upgrades.
+//!
+//! A tunnel is a session of ordinary requests under `path`. The response
+//! of `GET <path>/<id>` streams data from the server, and data to the
+//! server is cut into pieces, each sent by `POST <path>/<id>/<seq>`.
+//! The server puts pieces back in order, since they may come through
+//! different connections of a cdn. An empty piece ends the upload.
+//!
+//! [`SplitConnect`] sends all requests of a session on one h2 connection,
+//! which is shared by sessions like [`PostConnect`](crate::post::PostConnect).
+//! [`SplitAccept`] takes both h2 and HTTP/1.1 connections, and shares
+//! sessions between clones. Its [`AsyncAccept::accept`] yields the first
+//! session of a connection, use [`SplitAccept::serve`] to take all of them,
+//! including connections that only carry pieces.
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::future::{poll_fn, Future};
+use std::task::{ready, Context, Poll, Waker};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use bytes::{Bytes, BytesMut};
+use h2::RecvStream;
+use h2::client::{ResponseFuture, SendRequest};
+use h2::server::{self, SendResponse};
+use http::header::{HeaderName, HeaderValue};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+
+use super::{IOStream, AsyncAccept, AsyncConnect};
+use super::http2::{H2Stream, Pool, Rewind, into_io, poll_read_body, read_preface};
+
+// drop a session if no GET comes
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// sessions made by pieces, before their GET
+const MAX_PENDING: usize = 128;
+
+// all sessions, opened or not
+const MAX_SESSIONS: usize = 1024;
+
+// drop stale sessions at most this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// HTTP/1.1 request head
+const MAX_HEAD: usize = 0x2000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitConf {
+    pub host: String,
+    pub path: String,
+    // client: extra request headers, sent in order
+    pub headers: Vec<(String, String)>,
+    // server: required request headers
+    pub require_headers: Vec<(String, String)>,
+    // client: max body of a POST
+    // server: reject larger ones
+    pub max_post: usize,
+    // client: POSTs in flight
+    // server: pieces held ahead of the reader
+    pub max_posts: usize,
+}
+
+impl Display for SplitConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "host: {}, path: {}", self.host, self.path)?;
+
+        // values may carry credentials
+        if !self.headers.is_empty() {
+            let names: Vec<_> = self.headers.iter().map(|(k, _)| k.as_str()).collect();
+            write!(f, ", headers: {names:?}")?;
+        }
+
+        if !self.require_headers.is_empty() {
+            let names: Vec<_> = (self.require_headers.iter())
+                .map(|(k, _)| k.as_str())
+                .collect();
+            write!(f, ", require_headers: {names:?}")?;
+        }
+
+        write!(f, ", post: {} bytes x {}", self.max_post, self.max_posts)
+    }
+}
+
+impl SplitConf {
+    #[inline]
+    fn base(&self) -> &str { self.path.trim_end_matches('/') }
+}
+
+#[inline]
+fn refused(status: StatusCode) -> Error {
+    Error::new(
+        ErrorKind::ConnectionRefused,
+        format!("split: unexpected status {status}"),
+    )
+}
+
+#[inline]
+fn invalid(msg: &'static str) -> Error { Error::new(ErrorKind::InvalidData, msg) }
+
+// ========== client ==========
+#[derive(Debug, Clone)]
+pub struct SplitConnect<T> {
+    conn: T,
+    conf: SplitConf,
+    pool: Pool,
+}
+
+impl<T> Display for SplitConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[split]{}", self.conn) }
+}
+
+impl<T> SplitConnect<T> {
+    #[inline]
+    pub fn new(conn: T, conf: SplitConf) -> Self {
+        Self {
+            conn,
+            conf,
+            pool: Pool::default(),
+        }
+    }
+
+    /// Open a session on the shared connection, `None` if there is no live one.
+    pub async fn open(&self) -> Option<Result<SplitClientStream>> {
+        let send = self.pool.get().await?;
+        Some(session(send, &self.conf))
+    }
+}
+
+impl<S, T> AsyncConnect<S> for SplitConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+    T::Stream: Send,
+{
+    type Stream = SplitClientStream;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // make a new connection, which replaces the shared one
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.conn.connect(stream, buf).await?;
+            let send = self.pool.connect(stream).await?;
+            session(send, &self.conf)
+        }
+    }
+}
+
+// the response is not awaited, pieces can be sent before it comes
+fn session(mut send: SendRequest<Bytes>, conf: &SplitConf) -> Result<SplitClientStream> {
+    let uri = format!(
+        "https://{}{}/{:032x}",
+        conf.host,
+        conf.base(),
+        rand::random::<u128>()
+    );
+
+    let mut req = Request::builder().method(Method::GET).uri(&uri);
+    for (k, v) in conf.headers.iter() {
+        req = req.header(k, v);
+    }
+    let req = req
+        .body(())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let (response, _) = send.send_request(req, true).map_err(into_io)?;
+
+    Ok(SplitClientStream {
+        down: Download::Wait(response),
+        buf: Bytes::new(),
+        up: Upload {
+            send,
+            uri: uri + "/",
+            headers: conf.headers.clone(),
+            seq: 0,
+            posts: Vec::new(),
+            max_post: conf.max_post,
+            max_posts: conf.max_posts,
+            eof_sent: false,
+        },
+    })
+}
+
+/// Session of a [`SplitConnect`].
+#[derive(Debug)]
+pub struct SplitClientStream {
+    down: Download,
+    // received but not read
+    buf: Bytes,
+    up: Upload,
+}
+
+#[derive(Debug)]
+enum Download {
+    Wait(ResponseFuture),
+    Body(RecvStream),
+}
+
+#[derive(Debug)]
+struct Upload {
+    send: SendRequest<Bytes>,
+    // https://<host><path>/<id>/
+    uri: String,
+    headers: Vec<(String, String)>,
+    seq: u64,
+    // not yet taken by the server
+    posts: Vec<ResponseFuture>,
+    max_post: usize,
+    max_posts: usize,
+    eof_sent: bool,
+}
+
+impl Upload {
+    // drop finished POSTs, fail on a rejected one
+    fn poll_posts(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        let mut i = 0;
+        while i < self.posts.len() {
+            match Pin::new(&mut self.posts[i]).poll(cx) {
+                Poll::Ready(Ok(x)) if x.status() == StatusCode::OK => {
+                    drop(self.posts.swap_remove(i));
+                }
+                Poll::Ready(Ok(x)) => return Err(refused(x.status())),
+                Poll::Ready(Err(e)) => return Err(into_io(e)),
+                Poll::Pending => i += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_post(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<Result<()>> {
+        self.poll_posts(cx)?;
+        if self.posts.len() >= self.max_posts {
+            return Poll::Pending;
+        }
+        ready!(self.send.poll_ready(cx)).map_err(into_io)?;
+
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}{}", self.uri, self.seq))
+            .header("content-length", data.len());
+        for (k, v) in self.headers.iter() {
+            req = req.header(k, v);
+        }
+        let req = req
+            .body(())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let (response, mut send) = (self.send)
+            .send_request(req, data.is_empty())
+            .map_err(into_io)?;
+        if !data.is_empty() {
+            // buffered by h2 until the peer's window opens
+            (send.send_data(Bytes::copy_from_slice(data), true)).map_err(into_io)?;
+        }
+
+        self.posts.push(response);
+        self.seq += 1;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for SplitClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.down {
+                Download::Wait(response) => {
+                    let response = ready!(Pin::new(response).poll(cx)).map_err(into_io)?;
+                    if response.status() != StatusCode::OK {
+                        return Poll::Ready(Err(refused(response.status())));
+                    }
+                    this.down = Download::Body(response.into_body());
+                }
+                Download::Body(recv) => return poll_read_body(recv, &mut this.buf, cx, buf),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SplitClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(this.up.max_post);
+        ready!(this.up.poll_post(cx, &buf[..n]))?;
+        Poll::Ready(Ok(n))
+    }
+
+    // sent by the connection
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(self.get_mut().up.poll_posts(cx))
+    }
+
+    // wait until the server takes all pieces
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let up = &mut self.get_mut().up;
+        if !up.eof_sent {
+            ready!(up.poll_post(cx, &[]))?;
+            up.eof_sent = true;
+        }
+
+        up.poll_posts(cx)?;
+        match up.posts.is_empty() {
+            true => Poll::Ready(Ok(())),
+            false => Poll::Pending,
+        }
+    }
+}
+
+// ========== session ==========
+#[derive(Debug)]
+struct Session {
+    created: Instant,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // pieces ahead of the reader, by seq
+    pieces: BTreeMap<u64, Bytes>,
+    // seq of the next piece to read
+    next: u64,
+    // taken by a GET
+    opened: bool,
+    closed: bool,
+    reader: Option<Waker>,
+    // POSTs waiting for their pieces to be read
+    writers: Vec<Waker>,
+}
+
+impl Inner {
+    fn wake_writers(&mut self) { self.writers.drain(..).for_each(Waker::wake) }
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    // no GET came in time
+    fn is_stale(&self, opened: bool) -> bool {
+        !opened && self.created.elapsed() >= SESSION_TIMEOUT
+    }
+
+    fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed = true;
+        inner.wake_writers();
+        if let Some(x) = inner.reader.take() {
+            x.wake();
+        }
+    }
+
+    // answered once the piece is read, so the client does not get too far ahead
+    async fn push(&self, seq: u64, data: Bytes, max_posts: usize) -> StatusCode {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return StatusCode::GONE;
+            }
+            // a retry
+            if seq < inner.next || inner.pieces.contains_key(&seq) {
+                return StatusCode::OK;
+            }
+            if seq - inner.next >= max_posts as u64 {
+                return StatusCode::BAD_REQUEST;
+            }
+
+            inner.pieces.insert(seq, data);
+            if let Some(x) = inner.reader.take() {
+                x.wake();
+            }
+        }
+
+        let wait = || {
+            poll_fn(|cx| {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.next > seq {
+                    Poll::Ready(StatusCode::OK)
+                } else if inner.closed {
+                    Poll::Ready(StatusCode::GONE)
+                } else {
+                    inner.writers.push(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        };
+
+        // give up if no GET comes
+        loop {
+            match tokio::time::timeout(SESSION_TIMEOUT, wait()).await {
+                Ok(status) => return status,
+                Err(_) if !self.inner.lock().unwrap().opened => {
+                    self.close();
+                    return StatusCode::GONE;
+                }
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    conf: SplitConf,
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Debug)]
+struct Sessions {
+    map: HashMap<String, Arc<Session>>,
+    // not opened yet
+    pending: usize,
+    swept: Instant,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+            pending: 0,
+            swept: Instant::now(),
+        }
+    }
+
+    // drop the ones no GET came for
+    fn sweep(&mut self) {
+        if self.swept.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        self.swept = Instant::now();
+
+        let mut pending = 0;
+        self.map.retain(|_, x| {
+            let opened = x.inner.lock().unwrap().opened;
+            if x.is_stale(opened) {
+                x.close();
+                return false;
+            }
+            pending += usize::from(!opened);
+            true
+        });
+        self.pending = pending;
+    }
+}
+
+impl Shared {
+    // make it if missing, and take it for a GET
+    //
+    // only a GET may add one while too many wait for theirs,
+    // and none may beyond the limit of all sessions
+    fn session(&self, id: &str, open: bool) -> std::result::Result<Arc<Session>, StatusCode> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.sweep();
+
+        let found = sessions.map.get(id).cloned();
+        let session = match found {
+            Some(x) if !x.is_stale(x.inner.lock().unwrap().opened) => x,
+            found => {
+                if let Some(x) = found {
+                    x.close();
+                    sessions.map.remove(id);
+                    sessions.pending -= 1;
+                }
+                if sessions.map.len() >= MAX_SESSIONS || (!open && sessions.pending >= MAX_PENDING)
+                {
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+                let x = Arc::new(Session::new());
+                sessions.map.insert(String::from(id), x.clone());
+                sessions.pending += 1;
+                x
+            }
+        };
+
+        if open {
+            let mut inner = session.inner.lock().unwrap();
+            if inner.opened {
+                return Err(StatusCode::CONFLICT);
+            }
+            inner.opened = true;
+            drop(inner);
+            sessions.pending -= 1;
+        }
+        Ok(session)
+    }
+
+    // an opened session ends with its GET
+    fn close(&self, id: &str, session: &Session) {
+        session.close();
+        self.sessions.lock().unwrap().map.remove(id);
+    }
+
+    async fn push(&self, id: &str, seq: u64, data: Bytes) -> StatusCode {
+        match self.session(id, false) {
+            Ok(x) => x.push(seq, data, self.conf.max_posts).await,
+            Err(status) => status,
+        }
+    }
+}
+
+enum Call<'a> {
+    // GET <path>/<id>
+    Open(&'a str),
+    // POST <path>/<id>/<seq>
+    Push(&'a str, u64),
+}
+
+fn route<'a>(
+    conf: &SplitConf,
+    method: &Method,
+    host: &str,
+    path: &'a str,
+    headers: &HeaderMap,
+) -> std::result::Result<Call<'a>, StatusCode> {
+    let path = path.split_once('?').map_or(path, |(x, _)| x);
+    let rest = path
+        .strip_prefix(conf.base())
+        .and_then(|x| x.strip_prefix('/'));
+    let Some(rest) = rest.filter(|_| host == conf.host) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    if (conf.require_headers.iter()).any(|(k, v)| headers.get(k).is_none_or(|x| x != v.as_str())) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let call = match (method, rest.split_once('/')) {
+        (&Method::GET, None) => Call::Open(rest),
+        (&Method::POST, Some((id, seq))) => match seq.parse() {
+            Ok(seq) => Call::Push(id, seq),
+            Err(_) => return Err(StatusCode::NOT_FOUND),
+        },
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+
+    let id = match call {
+        Call::Open(id) | Call::Push(id, _) => id,
+    };
+    let is_id = |c: u8| c.is_ascii_alphanumeric() || c == b'-';
+    match !id.is_empty() && id.len() <= 64 && id.bytes().all(is_id) {
+        true => Ok(call),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// ========== server ==========
+#[derive(Debug, Clone)]
+pub struct SplitAccept<T> {
+    lis: T,
+    shared: Arc<Shared>,
+}
+
+impl<T> Display for SplitAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[split]{}", self.lis) }
+}
+
+impl<T> SplitAccept<T> {
+    #[inline]
+    pub fn new(lis: T, conf: SplitConf) -> Self {
+        Self {
+            lis,
+            shared: Arc::new(Shared {
+                conf,
+                sessions: Mutex::new(Sessions::new()),
+            }),
+        }
+    }
+
+    /// Run the inner layers, for [`serve`](Self::serve).
+    #[inline]
+    pub async fn accept_inner<S>(&self, stream: S, buf: &mut [u8]) -> Result<T::Stream>
+    where
+        S: IOStream,
+        T: AsyncAccept<S>,
+    {
+        self.lis.accept(stream, buf).await
+    }
+
+    /// Tell h2 from HTTP/1.1, then take sessions from [`SplitConn::accept`].
+    pub async fn serve<IO: IOStream>(&self, mut io: IO) -> Result<SplitConn<IO>> {
+        let mut buf = vec![0u8; 0x400];
+        let (n, is_h2) = read_preface(&mut io, &mut buf).await?;
+        let io = Rewind::new(io, &buf[..n]);
+
+        let conn = match is_h2 {
+            true => Conn::Http2(Box::new(server::handshake(io).await.map_err(into_io)?)),
+            false => Conn::Http1(Some(io)),
+        };
+        Ok(SplitConn {
+            conn,
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+impl<S, T> AsyncAccept<S> for SplitAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+    T::Stream: Send,
+{
+    type Stream = SplitServerStream<Rewind<T::Stream>>;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // other sessions of the connection are dropped
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let stream = self.lis.accept(stream, buf).await?;
+            let mut conn = self.serve(stream).await?;
+            let stream = match conn.accept().await {
+                Some(x) => x?,
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            };
+
+            // drive the connection
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+            Ok(stream)
+        }
+    }
+}
+
+/// Sessions opened on a connection.
+pub struct SplitConn<T> {
+    conn: Conn<T>,
+    shared: Arc<Shared>,
+}
+
+enum Conn<T> {
+    // until a GET takes it
+    Http1(Option<Rewind<T>>),
+    Http2(Box<server::Connection<Rewind<T>, Bytes>>),
+}
+
+impl<T: IOStream> SplitConn<T> {
+    /// Next session, `None` once the connection closes.
+    ///
+    /// This also takes pieces, keep calling it until `None`. Over
+    /// HTTP/1.1, a GET takes the whole connection.
+    pub async fn accept(&mut self) -> Option<Result<SplitServerStream<Rewind<T>>>> {
+        let conn = match &mut self.conn {
+            Conn::Http1(io) => return accept_h1(&self.shared, io.take()?).await.transpose(),
+            Conn::Http2(conn) => conn,
+        };
+
+        loop {
+            let (req, respond) = match conn.accept().await? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(into_io(e))),
+            };
+            if let Some(stream) = accept_h2(&self.shared, req, respond) {
+                return Some(Ok(stream));
+            }
+        }
+    }
+}
+
+fn accept_h2<T>(
+    shared: &Arc<Shared>,
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) -> Option<SplitServerStream<T>> {
+    let (parts, body) = req.into_parts();
+    let host = parts.uri.authority().map_or("", |x| x.as_str());
+    let path = parts.uri.path_and_query().map_or("/", |x| x.as_str());
+
+    let status = match route(&shared.conf, &parts.method, host, path, &parts.headers) {
+        Ok(Call::Open(id)) => match shared.session(id, true) {
+            Ok(session) => {
+                let Ok(send) = respond.send_response(download(), false) else {
+                    shared.close(id, &session);
+                    return None;
+                };
+                let down = Down::H2(H2Stream::new(send, body));
+                return Some(SplitServerStream::new(shared.clone(), id, session, down));
+            }
+            Err(status) => status,
+        },
+        Ok(Call::Push(id, seq)) => {
+            let (shared, id) = (shared.clone(), String::from(id));
+            tokio::spawn(async move {
+                let status = match read_body(body, shared.conf.max_post).await {
+                    Ok(data) => shared.push(&id, seq, data).await,
+                    Err(status) => status,
+                };
+                let _ = respond.send_response(status_only(status), true);
+            });
+            return None;
+        }
+        Err(status) => status,
+    };
+
+    let _ = respond.send_response(status_only(status), true);
+    None
+}
+
+async fn read_body(mut body: RecvStream, max: usize) -> std::result::Result<Bytes, StatusCode> {
+    let mut buf = BytesMut::new();
+    while let Some(data) = body.data().await {
+        let data = data.map_err(|_| StatusCode::BAD_REQUEST)?;
+        let _ = body.flow_control().release_capacity(data.len());
+        if buf.len() + data.len() > max {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&data);
+    }
+    Ok(buf.freeze())
+}
+
+// ask proxies not to buffer it
+fn download() -> Response<()> {
+    let mut response = Response::new(());
+    let headers = response.headers_mut();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert("cache-control", HeaderValue::from_static("no-store"));
+    headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    response
+}
+
+fn status_only(status: StatusCode) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    response
+}
+
+// pieces, until a GET or the end
+async fn accept_h1<T>(shared: &Arc<Shared>, mut io: T) -> Result<Option<SplitServerStream<T>>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // read but not used
+    let mut buf = Vec::new();
+
+    macro_rules! read_more {
+        () => {{
+            let mut chunk = [0u8; 0x1000];
+            let n = io.read(&mut chunk).await?;
+            buf.extend_from_slice(&chunk[..n]);
+            n
+        }};
+    }
+
+    loop {
+        let (n, method, path, headers) = loop {
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut req = httparse::Request::new(&mut headers);
+            if let httparse::Status::Complete(n) = req
+                .parse(&buf)
+                .map_err(|_| invalid("split: invalid request"))?
+            {
+                let (method, path, headers) = head(&req)?;
+                break (n, method, path, headers);
+            }
+
+            if buf.len() >= MAX_HEAD {
+                return Err(invalid("split: request too large"));
+            }
+            if read_more!() == 0 {
+                return match buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(ErrorKind::UnexpectedEof.into()),
+                };
+            }
+        };
+        buf.drain(..n);
+
+        let host = headers
+            .get("host")
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("");
+        let status = match route(&shared.conf, &method, host, &path, &headers) {
+            _ if headers.contains_key("transfer-encoding") => StatusCode::LENGTH_REQUIRED,
+            Ok(Call::Open(id)) => match shared.session(id, true) {
+                Ok(session) => {
+                    let head = async {
+                        io.write_all(DOWNLOAD_H1).await?;
+                        io.flush().await
+                    };
+                    if let Err(e) = head.await {
+                        shared.close(id, &session);
+                        return Err(e);
+                    }
+                    let down = Down::H1(Chunked::new(io));
+                    return Ok(Some(SplitServerStream::new(
+                        shared.clone(),
+                        id,
+                        session,
+                        down,
+                    )));
+                }
+                Err(status) => status,
+            },
+            Ok(Call::Push(id, seq)) => {
+                let len: usize = match headers.get("content-length").map(|x| x.to_str()) {
+                    None => 0,
+                    Some(Ok(x)) => x.parse().map_err(|_| invalid("split: invalid length"))?,
+                    Some(Err(_)) => return Err(invalid("split: invalid length")),
+                };
+                if len > shared.conf.max_post {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    while buf.len() < len {
+                        if read_more!() == 0 {
+                            return Err(ErrorKind::UnexpectedEof.into());
+                        }
+                    }
+                    let data = Bytes::copy_from_slice(&buf[..len]);
+                    buf.drain(..len);
+                    shared.push(id, seq, data).await
+                }
+            }
+            Err(status) => status,
+        };
+
+        // keep alive if taken
+        let close = status != StatusCode::OK;
+        let response = format!(
+            "HTTP/1.1 {} {}\r\ncontent-length: 0\r\n{}\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            if close { "connection: close\r\n" } else { "" },
+        );
+        io.write_all(response.as_bytes()).await?;
+        if close {
+            io.shutdown().await?;
+            return Ok(None);
+        }
+    }
+}
+
+const DOWNLOAD_H1: &[u8] = b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncache-control: no-store\r\nx-accel-buffering: no\r\ntransfer-encoding: chunked\r\n\r\n";
+
+fn head(req: &httparse::Request<'_, '_>) -> Result<(Method, String, HeaderMap)> {
+    let method = req
+        .method
+        .and_then(|x| Method::from_bytes(x.as_bytes()).ok());
+    let (Some(method), Some(path)) = (method, req.path) else {
+        return Err(invalid("split: invalid request"));
+    };
+
+    let mut headers = HeaderMap::new();
+    for h in req.headers.iter() {
+        let name = HeaderName::from_bytes(h.name.as_bytes());
+        let value = HeaderValue::from_bytes(h.value);
+        match (name, value) {
+            (Ok(name), Ok(value)) => headers.append(name, value),
+            _ => return Err(invalid("split: invalid header")),
+        };
+    }
+    Ok((method, String::from(path), headers))
+}
+
+// ========== stream ==========
+/// Session of a [`SplitAccept`].
+pub struct SplitServerStream<T> {
+    shared: Arc<Shared>,
+    id: String,
+    session: Arc<Session>,
+    // piece being read
+    buf: Bytes,
+    eof: bool,
+    down: Down<T>,
+}
+
+enum Down<T> {
+    H1(Chunked<T>),
+    H2(H2Stream),
+}
+
+impl<T> std::fmt::Debug for SplitServerStream<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SplitServerStream")
+            .field("id", &self.id)
+            .field("eof", &self.eof)
+            .finish()
+    }
+}
+
+impl<T> SplitServerStream<T> {
+    fn new(shared: Arc<Shared>, id: &str, session: Arc<Session>, down: Down<T>) -> Self {
+        Self {
+            shared,
+            id: String::from(id),
+            session,
+            buf: Bytes::new(),
+            eof: false,
+            down,
+        }
+    }
+}
+
+impl<T> Drop for SplitServerStream<T> {
+    fn drop(&mut self) { self.shared.close(&self.id, &self.session); }
+}
+
+impl<T> AsyncRead for SplitServerStream<T>
+where
+    T: Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        while this.buf.is_empty() && !this.eof {
+            let mut inner = this.session.inner.lock().unwrap();
+            let next = inner.next;
+            match inner.pieces.first_key_value() {
+                Some((&seq, _)) if seq == next => {
+                    let (_, data) = inner.pieces.pop_first().unwrap();
+                    inner.next += 1;
+                    inner.wake_writers();
+                    this.eof = data.is_empty();
+                    this.buf = data;
+                }
+                _ => {
+                    inner.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncWrite for SplitServerStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match &mut self.get_mut().down {
+            Down::H1(x) => Pin::new(x).poll_write(cx, buf),
+            Down::H2(x) => Pin::new(x).poll_write(cx, buf),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.get_mut().down {
+            Down::H1(x) => Pin::new(x).poll_flush(cx),
+            Down::H2(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.get_mut().down {
+            Down::H1(x) => Pin::new(x).poll_shutdown(cx),
+            Down::H2(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+// ========== chunked ==========
+/// HTTP/1.1 chunked body.
+struct Chunked<T> {
+    io: T,
+    // encoded but not written
+    buf: Vec<u8>,
+    pos: usize,
+    eof_sent: bool,
+}
+
+impl<T> Chunked<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    const fn new(io: T) -> Self {
+        Self {
+            io,
+            buf: Vec::new(),
+            pos: 0,
+            eof_sent: false,
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while self.pos < self.buf.len() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.buf[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncWrite for Chunked<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    // taken once encoded
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        ready!(this.poll_drain(cx))?;
+        this.buf
+            .extend_from_slice(format!("{:x}\r\n", buf.len()).as_bytes());
+        this.buf.extend_from_slice(buf);
+        this.buf.extend_from_slice(b"\r\n");
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if !this.eof_sent {
+            ready!(this.poll_drain(cx))?;
+            this.buf.extend_from_slice(b"0\r\n\r\n");
+            this.eof_sent = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nop::{NopAccept, NopConnect};
+
+    fn conf(path: &str, headers: &[(&str, &str)]) -> SplitConf {
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect();
+        SplitConf {
+            host: String::from("abc"),
+            path: String::from(path),
+            headers: headers.clone(),
+            require_headers: headers,
+            max_post: 0x4000,
+            max_posts: 4,
+        }
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = SplitConnect::new(NopConnect {}, conf("/up/", &[("x-token", "t0k")]));
+        let lis = SplitAccept::new(NopAccept {}, conf("/up", &[("x-token", "t0k")]));
+
+        let mut buf1 = vec![0u8; 0x1000];
+        let mut buf2 = vec![0u8; 0x1000];
+        let (c, s) = tokio::join!(
+            conn.connect(client, &mut buf1),
+            lis.accept(server, &mut buf2)
+        );
+        let (mut c, mut s) = (c.unwrap(), s.unwrap());
+
+        // pieces span h2 frames and POSTs
+        let data: Vec<u8> = (0..0x30000).map(|x| x as u8).collect();
+        macro_rules! run {
+            ($from: expr, $to: expr) => {{
+                let send = async {
+                    for chunk in data.chunks(0x5000) {
+                        $from.write_all(chunk).await.unwrap();
+                    }
+                    $from.shutdown().await.unwrap();
+                };
+                let recv = async {
+                    let mut recv = Vec::new();
+                    $to.read_to_end(&mut recv).await.unwrap();
+                    recv
+                };
+                let (_, recv) = tokio::join!(send, recv);
+                assert_eq!(recv, data);
+            }};
+        }
+
+        run!(c, s);
+        run!(s, c);
+    }
+
+    // each request on its own HTTP/1.1 connection, like through a cdn
+    #[tokio::test]
+    async fn reorder() {
+        let lis = SplitAccept::new(NopAccept {}, conf("/up", &[]));
+
+        macro_rules! request {
+            ($req: expr) => {{
+                let (mut client, server) = tokio::io::duplex(0x1000);
+                let lis = lis.clone();
+                let server = tokio::spawn(async move {
+                    let mut conn = lis.serve(server).await.unwrap();
+                    conn.accept().await.map(Result::unwrap)
+                });
+                client.write_all($req).await.unwrap();
+                (client, server)
+            }};
+        }
+
+        let (mut down, s) = request!(b"GET /up/a-1?x=y HTTP/1.1\r\nhost: abc\r\n\r\n");
+        let mut s = s.await.unwrap().unwrap();
+
+        let post = |seq: u64, data: &str| {
+            format!(
+                "POST /up/a-1/{seq} HTTP/1.1\r\nhost: abc\r\ncontent-length: {}\r\n\r\n{data}",
+                data.len()
+            )
+        };
+        let (mut c2, s2) = request!(post(2, "").as_bytes());
+        let (mut c1, s1) = request!(post(1, "world").as_bytes());
+        let (mut c0, s0) = request!(post(0, "hello ").as_bytes());
+
+        let mut recv = Vec::new();
+        s.read_to_end(&mut recv).await.unwrap();
+        assert_eq!(recv, b"hello world");
+
+        for (c, s) in [(&mut c0, s0), (&mut c1, s1), (&mut c2, s2)] {
+            let mut buf = vec![0u8; 0x100];
+            let n = c.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"HTTP/1.1 200 OK\r\n"));
+            drop(c.shutdown().await);
+            assert!(s.await.unwrap().is_none());
+        }
+
+        s.write_all(b"back").await.unwrap();
+        s.shutdown().await.unwrap();
+        let mut recv = Vec::new();
+        down.read_to_end(&mut recv).await.unwrap();
+        let recv = String::from_utf8(recv).unwrap();
+        assert!(recv.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(recv.ends_with("\r\n\r\n4\r\nback\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn reject() {
+        let lis = SplitAccept::new(NopAccept {}, conf("/up", &[("x-token", "t0k")]));
+
+        macro_rules! n {
+            ( $( ($req: expr, $status: expr), )+ ) => {
+                $({
+                    let (mut client, server) = tokio::io::duplex(0x1000);
+                    let lis = lis.clone();
+                    let server = tokio::spawn(async move {
+                        let mut conn = lis.serve(server).await.unwrap();
+                        assert!(conn.accept().await.is_none());
+                    });
+                    client.write_all($req.as_bytes()).await.unwrap();
+                    let mut recv = Vec::new();
+                    client.read_to_end(&mut recv).await.unwrap();
+                    let recv = String::from_utf8(recv).unwrap();
+                    assert!(recv.starts_with(concat!("HTTP/1.1 ", $status, "\r\n")), "{recv}");
+                    server.await.unwrap();
+                })+
+            };
+        }
+
+        n![
+            ("GET /down/a HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n", "404 Not Found"),
+            ("GET /up/a HTTP/1.1\r\nhost: xyz\r\nx-token: t0k\r\n\r\n", "404 Not Found"),
+            ("GET /up/a/b HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n", "404 Not Found"),
+            ("GET /up/a.b HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n", "404 Not Found"),
+            ("POST /up/a HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n", "404 Not Found"),
+            ("POST /up/a/x HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n", "404 Not Found"),
+            ("GET /up/a HTTP/1.1\r\nhost: abc\r\n\r\n", "403 Forbidden"),
+            ("GET /up/a HTTP/1.1\r\nhost: abc\r\nx-token: abc\r\n\r\n", "403 Forbidden"),
+            (
+                "POST /up/a/0 HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\ntransfer-encoding: chunked\r\n\r\n",
+                "411 Length Required"
+            ),
+            (
+                "POST /up/a/0 HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\ncontent-length: 16385\r\n\r\n",
+                "413 Payload Too Large"
+            ),
+            (
+                "POST /up/b/4 HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\ncontent-length: 0\r\n\r\n",
+                "400 Bad Request"
+            ),
+        ];
+
+        // taken twice
+        let (client, server) = tokio::io::duplex(0x1000);
+        let (mut c1, s1) = (client, lis.clone());
+        let s1 = tokio::spawn(async move { s1.serve(server).await.unwrap().accept().await });
+        c1.write_all(b"GET /up/c HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n")
+            .await
+            .unwrap();
+        let _s1 = s1.await.unwrap().unwrap().unwrap();
+        n![(
+            "GET /up/c HTTP/1.1\r\nhost: abc\r\nx-token: t0k\r\n\r\n",
+            "409 Conflict"
+        ),];
+    }
+
+    #[tokio::test]
+    async fn pending() {
+        let lis = SplitAccept::new(NopAccept {}, conf("/up", &[]));
+        let shared = &lis.shared;
+        for i in 0..MAX_PENDING {
+            assert!(shared.session(&format!("p-{i}"), false).is_ok());
+        }
+
+        // known ids and GETs still pass
+        assert!(shared.session("p-0", false).is_ok());
+        let status = shared.push("q", 0, Bytes::new()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(shared.session("q", true).is_ok());
+        assert!(shared.session("p-1", true).is_ok());
+        assert_eq!(
+            shared.session("p-1", true).unwrap_err(),
+            StatusCode::CONFLICT
+        );
+        assert!(shared.session("r", false).is_ok());
+
+        // stale ones are dropped by the next sweep
+        let mut sessions = shared.sessions.lock().unwrap();
+        for x in sessions.map.values_mut() {
+            Arc::get_mut(x).unwrap().created -= SESSION_TIMEOUT;
+        }
+        sessions.swept -= SWEEP_INTERVAL;
+        drop(sessions);
+        assert!(shared.session("s", false).is_ok());
+        let sessions = shared.sessions.lock().unwrap();
+        assert_eq!((sessions.map.len(), sessions.pending), (3, 1));
+    }
+
+    #[test]
+    fn limit() {
+        let lis = SplitAccept::new(NopAccept {}, conf("/up", &[]));
+        let shared = &lis.shared;
+        let mut opened: Vec<_> = (0..MAX_SESSIONS)
+            .map(|i| shared.session(&format!("o-{i}"), true).unwrap())
+            .collect();
+
+        // GETs count too
+        assert_eq!(
+            shared.session("a", true).unwrap_err(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            shared.session("a", false).unwrap_err(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        shared.close("o-0", &opened.swap_remove(0));
+        assert!(shared.session("a", true).is_ok());
+    }
+}
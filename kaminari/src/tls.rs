@@ -1,19 +1,38 @@
-use std::io::Result;
-use std::future::Future;
-use std::sync::Arc;
 use std::fmt::{Debug, Display, Formatter};
 
-use super::{IOStream, AsyncAccept, AsyncConnect};
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+use {
+    std::io::Result,
+    std::future::Future,
+    std::sync::Arc,
+    super::{IOStream, AsyncAccept, AsyncConnect},
+    tokio_rustls::rustls,
+    rustls::client::ClientConfig,
+    rustls::client::danger::ServerCertVerifier,
+    rustls::server::ServerConfig,
+    rustls::pki_types::ServerName,
+    tokio_rustls::{TlsAcceptor, TlsConnector},
+};
 
-use tokio_rustls::rustls;
-use rustls::client::ClientConfig;
-use rustls::server::ServerConfig;
-use rustls::pki_types::ServerName;
-
-use tokio_rustls::{TlsAcceptor, TlsConnector};
 pub use tokio_rustls::client::TlsStream as TlsClientStream;
 pub use tokio_rustls::server::TlsStream as TlsServerStream;
 
+pub mod ca;
+pub mod crl;
+
+#[cfg(feature = "tls-openssl")]
+pub mod openssl;
+
+#[cfg(all(
+    feature = "ktls",
+    target_os = "linux",
+    any(feature = "tls-ring", feature = "tls-awslc")
+))]
+pub mod ktls;
+
+// save a pem file with the given unix permission
+pub use utils::write_pem;
+
 pub fn install_provider() {
     #[cfg(feature = "tls-ring")]
     {
@@ -37,6 +56,10 @@ pub struct TlsClientConf {
     pub alpn: Vec<Vec<u8>>,
     pub insecure: bool,
     pub early_data: bool,
+    pub ca: String,
+    pub crt: String,
+    pub key: String,
+    pub crl: String,
 }
 
 impl Display for TlsClientConf {
@@ -60,10 +83,27 @@ impl Display for TlsClientConf {
             f,
             "sni: {}, alpn: {}, insecure: {}, early_data: {}",
             self.sni, alpn, self.insecure, self.early_data
-        )
+        )?;
+
+        if !self.ca.is_empty() {
+            write!(f, ", ca: {}", self.ca)?;
+        }
+
+        if !self.crt.is_empty() {
+            write!(f, ", cert: {}, key: {}", self.crt, self.key)?;
+        }
+
+        if !self.crl.is_empty() {
+            write!(f, ", crl: {}", self.crl)?;
+        }
+
+        Ok(())
     }
 }
 
+// the rustls layer needs one of its crypto backends,
+// left out when openssl is the only backend
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 #[derive(Clone)]
 pub struct TlsConnect<T> {
     conn: T,
@@ -71,6 +111,7 @@ pub struct TlsConnect<T> {
     cc: TlsConnector,
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<T> Display for TlsConnect<T>
 where
     T: Display,
@@ -78,6 +119,7 @@ where
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[tls]{}", self.conn) }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<T> Debug for TlsConnect<T>
 where
     T: Debug,
@@ -90,6 +132,7 @@ where
     }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<T> TlsConnect<T> {
     pub fn new(conn: T, conf: TlsClientConf) -> Self {
         let TlsClientConf {
@@ -97,20 +140,24 @@ impl<T> TlsConnect<T> {
             alpn,
             insecure,
             early_data,
+            ca,
+            crt,
+            key,
+            crl,
         } = conf;
         let sni = ServerName::try_from(sni).expect("invalid DNS name");
 
-        let mut conf = if !insecure {
-            ClientConfig::builder()
-                .with_root_certificates(utils::firefox_roots())
-                .with_no_client_auth()
+        let verifier: Arc<dyn ServerCertVerifier> = if !insecure {
+            utils::build_verifier(&ca, &crl)
         } else {
-            ClientConfig::builder()
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(utils::SkipVerify {}))
-                .with_no_client_auth()
+            Arc::new(utils::SkipVerify {})
         };
 
+        let builder = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let mut conf = utils::with_client_cert(builder, &crt, &key);
+
         conf.enable_early_data = early_data;
         conf.alpn_protocols = alpn;
 
@@ -128,14 +175,18 @@ impl<T> TlsConnect<T> {
             alpn,
             insecure,
             early_data,
+            ca,
+            crt,
+            key,
+            crl,
         } = conf;
 
         let sni = ServerName::try_from(sni).expect("invalid DNS name");
 
-        let mut conf = ClientConfig::builder()
+        let builder = ClientConfig::builder()
             .dangerous()
-            .with_custom_certificate_verifier(utils::new_verifier(insecure))
-            .with_no_client_auth();
+            .with_custom_certificate_verifier(utils::new_verifier(insecure, ca, crl));
+        let mut conf = utils::with_client_cert(builder, &crt, &key);
 
         conf.enable_early_data = early_data;
         conf.alpn_protocols = alpn;
@@ -148,6 +199,7 @@ impl<T> TlsConnect<T> {
     }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<S, T> AsyncConnect<S> for TlsConnect<T>
 where
     S: IOStream,
@@ -176,6 +228,10 @@ pub struct TlsServerConf {
     pub key: String,
     pub ocsp: String,
     pub server_name: String,
+    pub ca: String,
+    pub self_signed: SelfSignedConf,
+    pub client_ca: String,
+    pub crl: String,
 }
 
 impl Display for TlsServerConf {
@@ -184,16 +240,81 @@ impl Display for TlsServerConf {
             f,
             "cert: {}, key: {}, oscp: {}, server_name: {}",
             self.crt, self.key, self.ocsp, self.server_name
+        )?;
+
+        if !self.ca.is_empty() {
+            write!(f, ", ca: {}", self.ca)?;
+        }
+
+        if !self.server_name.is_empty() {
+            write!(f, ", {}", self.self_signed)?;
+        }
+
+        if !self.client_ca.is_empty() {
+            write!(f, ", client_ca: {}", self.client_ca)?;
+        }
+
+        if !self.crl.is_empty() {
+            write!(f, ", crl: {}", self.crl)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+    Rsa,
+}
+
+impl Display for KeyType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use KeyType::*;
+        match self {
+            EcdsaP256 => write!(f, "ecdsa-p256"),
+            EcdsaP384 => write!(f, "ecdsa-p384"),
+            Ed25519 => write!(f, "ed25519"),
+            Rsa => write!(f, "rsa"),
+        }
+    }
+}
+
+// options to generate a self signed certificate,
+// validity is measured in days, 0 means never expire;
+// if dir is set, cert and key are saved to and loaded from it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfSignedConf {
+    pub dir: String,
+    pub key_type: KeyType,
+    pub validity: u32,
+    pub san: Vec<String>,
+}
+
+impl Display for SelfSignedConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cert_dir: {}, key_type: {}, validity: {}, san: [{}]",
+            self.dir,
+            self.key_type,
+            self.validity,
+            self.san.join(", ")
         )
     }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 #[derive(Clone)]
 pub struct TlsAccept<T> {
     lis: T,
     ac: TlsAcceptor,
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<T> Display for TlsAccept<T>
 where
     T: Display,
@@ -201,6 +322,7 @@ where
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[tls]{}", self.lis) }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<T> Debug for TlsAccept<T>
 where
     T: Debug,
@@ -210,6 +332,7 @@ where
     }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<T> TlsAccept<T> {
     pub fn new(lis: T, conf: TlsServerConf) -> Self {
         let TlsServerConf {
@@ -217,6 +340,10 @@ impl<T> TlsAccept<T> {
             key,
             ocsp,
             server_name,
+            ca,
+            self_signed,
+            client_ca,
+            crl,
         } = conf;
 
         let (cert, key) = if !crt.is_empty() && !key.is_empty() {
@@ -225,7 +352,7 @@ impl<T> TlsAccept<T> {
                 utils::read_private_key(&key).expect("failed to read private key"),
             )
         } else if !server_name.is_empty() {
-            utils::generate_self_signed(&server_name)
+            utils::generate_cert_key(&server_name, &ca, &self_signed)
         } else {
             panic!("no certificate or private key supplied")
         };
@@ -237,7 +364,7 @@ impl<T> TlsAccept<T> {
         };
 
         let conf = ServerConfig::builder()
-            .with_no_client_auth()
+            .with_client_cert_verifier(utils::build_client_verifier(&client_ca, &crl))
             .with_single_cert_with_ocsp(cert, key, ocsp)
             .expect("bad certificate or key");
 
@@ -254,6 +381,10 @@ impl<T> TlsAccept<T> {
             key,
             ocsp,
             server_name,
+            ca,
+            self_signed,
+            client_ca,
+            crl,
         } = conf;
 
         let ocsp = if !ocsp.is_empty() {
@@ -265,13 +396,13 @@ impl<T> TlsAccept<T> {
         let cert_resolver = if !crt.is_empty() && !key.is_empty() {
             utils::new_crt_key_resolver(crt, key, ocsp)
         } else if !server_name.is_empty() {
-            utils::new_self_signed_resolver(server_name)
+            utils::new_self_signed_resolver(server_name, ca, self_signed)
         } else {
             panic!("no certificate or private key supplied")
         };
 
         let conf = ServerConfig::builder()
-            .with_no_client_auth()
+            .with_client_cert_verifier(utils::new_client_verifier(client_ca, crl))
             .with_cert_resolver(cert_resolver);
 
         Self {
@@ -281,6 +412,7 @@ impl<T> TlsAccept<T> {
     }
 }
 
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
 impl<S, T> AsyncAccept<S> for TlsAccept<T>
 where
     S: IOStream,
@@ -307,15 +439,17 @@ mod utils {
     pub use server::*;
 
     mod client {
-        use std::sync::Arc;
+        use std::sync::{Arc, Mutex};
         use lazy_static::lazy_static;
 
         use tokio_rustls::rustls::{self, pki_types};
         use pki_types::{CertificateDer, PrivateKeyDer, ServerName};
-        use rustls::{RootCertStore, DigitallySignedStruct, SignatureScheme};
-        use rustls::client::WebPkiServerVerifier;
+        use rustls::{RootCertStore, DigitallySignedStruct, SignatureScheme, ConfigBuilder};
+        use rustls::client::{ClientConfig, WantsClientCert, WebPkiServerVerifier};
         use rustls::client::danger::{ServerCertVerified, ServerCertVerifier, HandshakeSignatureValid};
 
+        use super::super::crl::CrlServerVerifier;
+
         pub fn firefox_roots() -> RootCertStore {
             use webpki_roots::TLS_SERVER_ROOTS;
             RootCertStore {
@@ -323,6 +457,14 @@ mod utils {
             }
         }
 
+        pub fn read_roots(path: &str) -> std::io::Result<RootCertStore> {
+            let mut roots = RootCertStore::empty();
+            for cert in super::read_certificates(path)? {
+                roots.add(cert).map_err(std::io::Error::other)?;
+            }
+            Ok(roots)
+        }
+
         #[derive(Debug)]
         pub struct SkipVerify {}
         impl ServerCertVerifier for SkipVerify {
@@ -393,25 +535,96 @@ mod utils {
             ARC.clone()
         }
 
-        pub fn new_verifier(insecure: bool) -> Arc<dyn ServerCertVerifier> {
+        // use firefox roots if ca is empty
+        pub fn build_verifier(ca: &str, crl: &str) -> Arc<dyn ServerCertVerifier> {
+            let roots = if !ca.is_empty() {
+                read_roots(ca).expect("failed to read ca")
+            } else {
+                firefox_roots()
+            };
+            let roots = Arc::new(roots);
+
+            if !crl.is_empty() {
+                Arc::new(
+                    CrlServerVerifier::new(roots, crl.to_string()).expect("failed to read crl"),
+                )
+            } else {
+                WebPkiServerVerifier::builder(roots)
+                    .build()
+                    .expect("invalid ca")
+            }
+        }
+
+        fn new_ca_verifier(ca: String, crl: String) -> Arc<dyn ServerCertVerifier> {
+            type Store = Mutex<Vec<((String, String), Arc<dyn ServerCertVerifier>)>>;
+            lazy_static! {
+                static ref STORE: Store = Mutex::new(Vec::new());
+            }
+
+            // hold the lock
+            let mut store = STORE.lock().unwrap();
+
+            // simply increase ref count
+            if let Some(x) = store.iter().find(|((x, y), _)| *x == ca && *y == crl) {
+                return x.1.clone();
+            }
+
+            let verifier = build_verifier(&ca, &crl);
+
+            store.push(((ca, crl), verifier.clone()));
+            store.shrink_to_fit();
+
+            verifier
+        }
+
+        pub fn new_verifier(
+            insecure: bool,
+            ca: String,
+            crl: String,
+        ) -> Arc<dyn ServerCertVerifier> {
             if insecure {
                 new_insecure_verifier()
+            } else if !ca.is_empty() || !crl.is_empty() {
+                new_ca_verifier(ca, crl)
             } else {
                 new_firefox_verifier()
             }
         }
+
+        pub fn with_client_cert(
+            builder: ConfigBuilder<ClientConfig, WantsClientCert>,
+            crt: &str,
+            key: &str,
+        ) -> ClientConfig {
+            if crt.is_empty() || key.is_empty() {
+                return builder.with_no_client_auth();
+            }
+
+            let crt = super::read_certificates(crt).expect("failed to read certificate");
+            let key = super::read_private_key(key).expect("failed to read private key");
+            builder
+                .with_client_auth_cert(crt, key)
+                .expect("bad certificate or key")
+        }
     }
 
     mod server {
         use std::io::{BufReader, Result};
         use std::fs::{self, File};
+        use std::path::Path;
         use std::sync::{Arc, Mutex};
 
+        use super::super::{KeyType, SelfSignedConf};
+
         use tokio_rustls::rustls::{self, pki_types};
         use pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer as Der};
         use rustls::sign;
         use rustls::server::ResolvesServerCert;
         use rustls::server::ClientHello;
+        use rustls::server::WebPkiClientVerifier;
+        use rustls::server::danger::ClientCertVerifier;
+
+        use super::super::crl::CrlClientVerifier;
 
         use rustls_pemfile::Item;
         use webpki_roots::TLS_SERVER_ROOTS;
@@ -458,21 +671,272 @@ mod utils {
 
         pub fn read_ocsp(path: &str) -> Result<Vec<u8>> { fs::read(path) }
 
+        // no client auth if client_ca is empty
+        pub fn build_client_verifier(client_ca: &str, crl: &str) -> Arc<dyn ClientCertVerifier> {
+            if client_ca.is_empty() {
+                return WebPkiClientVerifier::no_client_auth();
+            }
+
+            let roots = Arc::new(super::read_roots(client_ca).expect("failed to read client ca"));
+
+            if !crl.is_empty() {
+                Arc::new(
+                    CrlClientVerifier::new(roots, crl.to_string()).expect("failed to read crl"),
+                )
+            } else {
+                WebPkiClientVerifier::builder(roots)
+                    .build()
+                    .expect("invalid client ca")
+            }
+        }
+
+        pub fn new_client_verifier(client_ca: String, crl: String) -> Arc<dyn ClientCertVerifier> {
+            type Store = Mutex<Vec<((String, String), Arc<dyn ClientCertVerifier>)>>;
+            lazy_static! {
+                static ref STORE: Store = Mutex::new(Vec::new());
+            }
+
+            // hold the lock
+            let mut store = STORE.lock().unwrap();
+
+            // simply increase ref count
+            if let Some(x) = store
+                .iter()
+                .find(|((x, y), _)| *x == client_ca && *y == crl)
+            {
+                return x.1.clone();
+            }
+
+            let verifier = build_client_verifier(&client_ca, &crl);
+
+            store.push(((client_ca, crl), verifier.clone()));
+            store.shrink_to_fit();
+
+            verifier
+        }
+
         pub fn generate_self_signed(
             server_name: &str,
+            conf: &SelfSignedConf,
         ) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
-            use rcgen::{CertifiedKey, generate_simple_self_signed as make};
+            if conf.dir.is_empty() {
+                let (cert, key) = make_self_signed(server_name, conf);
+                return (
+                    vec![cert.der().to_owned()],
+                    Der::from(key.serialize_der()).into(),
+                );
+            }
 
-            let CertifiedKey {
-                cert,
-                signing_key: key,
-            } = make(vec![server_name.to_string()])
-                .expect("failed to generate self signed certificate and private key");
+            let dir = Path::new(&conf.dir);
+            let crt_path = dir.join(format!("{server_name}.crt"));
+            let key_path = dir.join(format!("{server_name}.key"));
+
+            // reuse a previously generated one
+            if is_reusable(&crt_path, &key_path, server_name, conf) {
+                let crt = read_certificates(crt_path.to_str().unwrap())
+                    .expect("failed to read certificate");
+                let key = read_private_key(key_path.to_str().unwrap())
+                    .expect("failed to read private key");
+                return (crt, key);
+            }
+
+            let (cert, key) = make_self_signed(server_name, conf);
+            write_pem(&crt_path, &cert.pem(), 0o644).expect("failed to save certificate");
+            write_pem(&key_path, &key.serialize_pem(), 0o600).expect("failed to save private key");
+
+            (
+                vec![cert.der().to_owned()],
+                Der::from(key.serialize_der()).into(),
+            )
+        }
+
+        // not expiring, and generated with the same options
+        fn is_reusable(
+            crt_path: &Path,
+            key_path: &Path,
+            server_name: &str,
+            conf: &SelfSignedConf,
+        ) -> bool {
+            use std::net::IpAddr;
+            use rcgen::{PublicKeyData, SanType};
+            use x509_parser::extensions::GeneralName;
+            use time::{Duration, OffsetDateTime};
+
+            let (Ok(crt), Ok(key)) = (
+                read_certificates(crt_path.to_str().unwrap()),
+                fs::read_to_string(key_path),
+            ) else {
+                return false;
+            };
+            let (Some(Ok((_, crt))), Ok(key)) = (
+                crt.first().map(|x| x509_parser::parse_x509_certificate(x)),
+                KeyPair::from_pem(&key),
+            ) else {
+                return false;
+            };
+
+            if key.algorithm() != signature_algorithm(conf.key_type) {
+                return false;
+            }
+
+            let expect = new_cert_params(server_name, conf);
+
+            // names
+            let san: Vec<String> = crt
+                .subject_alternative_name()
+                .ok()
+                .flatten()
+                .map_or(&[][..], |x| &x.value.general_names)
+                .iter()
+                .filter_map(|x| match x {
+                    GeneralName::DNSName(x) => Some(x.to_string()),
+                    GeneralName::IPAddress(&[a, b, c, d]) => {
+                        Some(IpAddr::from([a, b, c, d]).to_string())
+                    }
+                    GeneralName::IPAddress(x) => <[u8; 16]>::try_from(*x)
+                        .ok()
+                        .map(|x| IpAddr::from(x).to_string()),
+                    _ => None,
+                })
+                .collect();
+            let expect_san: Vec<String> = expect
+                .subject_alt_names
+                .iter()
+                .filter_map(|x| match x {
+                    SanType::DnsName(x) => Some(x.to_string()),
+                    SanType::IpAddress(x) => Some(x.to_string()),
+                    _ => None,
+                })
+                .collect();
+            if san != expect_san {
+                return false;
+            }
+
+            // validity
+            let not_before = crt.validity().not_before.to_datetime();
+            let not_after = crt.validity().not_after.to_datetime();
+            let lifetime = not_after - not_before;
+            if (lifetime - (expect.not_after - expect.not_before)).abs() > Duration::minutes(1) {
+                return false;
+            }
+
+            // renew when less than a third is left
+            not_after - OffsetDateTime::now_utc() > lifetime / 3
+        }
+
+        pub fn generate_cert_key(
+            server_name: &str,
+            ca: &str,
+            conf: &SelfSignedConf,
+        ) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+            use super::super::ca::Ca;
+
+            if ca.is_empty() {
+                return generate_self_signed(server_name, conf);
+            }
 
-            let key = Der::from(key.serialize_der()).into();
-            let cert = cert.der().to_owned();
+            Ca::load_or_create(ca, conf.key_type)
+                .and_then(|ca| ca.issue_server(server_name, conf))
+                .expect("failed to issue certificate from ca")
+        }
+
+        // keys are generated by the backend in use,
+        // rcgen has no crypto backend in an openssl only build
+        #[cfg(not(feature = "tls-openssl"))]
+        pub use rcgen::KeyPair;
+        #[cfg(feature = "tls-openssl")]
+        pub use super::super::openssl::KeyPair;
+
+        pub fn signature_algorithm(key_type: KeyType) -> &'static rcgen::SignatureAlgorithm {
+            match key_type {
+                KeyType::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+                KeyType::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+                KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+                KeyType::Rsa => &rcgen::PKCS_RSA_SHA256,
+            }
+        }
+
+        #[cfg(not(feature = "tls-openssl"))]
+        pub fn new_key_pair(key_type: KeyType) -> KeyPair {
+            KeyPair::generate_for(signature_algorithm(key_type))
+                .expect("failed to generate private key")
+        }
+
+        #[cfg(feature = "tls-openssl")]
+        pub fn new_key_pair(key_type: KeyType) -> KeyPair {
+            KeyPair::generate(key_type).expect("failed to generate private key")
+        }
+
+        pub fn new_cert_params(
+            server_name: &str,
+            conf: &SelfSignedConf,
+        ) -> rcgen::CertificateParams {
+            use rcgen::{CertificateParams, DnType};
+            use time::{Duration, OffsetDateTime};
+
+            let san: Vec<String> = std::iter::once(server_name)
+                .chain(conf.san.iter().map(String::as_str))
+                .map(String::from)
+                .collect();
+
+            let mut params = CertificateParams::new(san).expect("invalid subject alt name");
+            params
+                .distinguished_name
+                .push(DnType::CommonName, server_name);
+
+            if conf.validity != 0 {
+                let now = OffsetDateTime::now_utc();
+                params.not_before = now - Duration::hours(1);
+                params.not_after = now + Duration::days(conf.validity as i64);
+            }
+
+            // rcgen derives one from the key with its crypto backend
+            #[cfg(not(any(feature = "tls-ring", feature = "tls-awslc")))]
+            {
+                let mut serial = [0u8; 16];
+                ::openssl::rand::rand_bytes(&mut serial).expect("failed to generate serial number");
+                serial[0] &= 0x7f;
+                params.serial_number = Some(serial.to_vec().into());
+            }
+
+            params
+        }
+
+        fn make_self_signed(
+            server_name: &str,
+            conf: &SelfSignedConf,
+        ) -> (rcgen::Certificate, KeyPair) {
+            let key = new_key_pair(conf.key_type);
+            let cert = new_cert_params(server_name, conf)
+                .self_signed(&key)
+                .expect("failed to generate self signed certificate");
+            (cert, key)
+        }
+
+        pub fn write_pem(path: &Path, pem: &str, mode: u32) -> Result<()> {
+            use std::io::Write;
+            let mut opts = fs::OpenOptions::new();
+            opts.write(true).create(true).truncate(true);
+
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut opts, mode);
+            #[cfg(not(unix))]
+            let _ = mode;
+
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+
+            let mut file = opts.open(path)?;
+
+            // mode only applies to a new file
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
 
-            (vec![cert], key)
+            file.write_all(pem.as_bytes())
         }
 
         // copy from rustls:
@@ -486,6 +950,7 @@ mod utils {
             }
         }
 
+        #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
         pub fn new_resolver(
             cert: Vec<CertificateDer<'static>>,
             priv_key: &PrivateKeyDer,
@@ -504,8 +969,13 @@ mod utils {
             })))
         }
 
-        pub fn new_self_signed_resolver(server_name: String) -> Arc<AlwaysResolvesChain> {
-            type Store = Mutex<Vec<(String, Arc<AlwaysResolvesChain>)>>;
+        #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+        pub fn new_self_signed_resolver(
+            server_name: String,
+            ca: String,
+            conf: SelfSignedConf,
+        ) -> Arc<AlwaysResolvesChain> {
+            type Store = Mutex<Vec<((String, String), SelfSignedConf, Arc<AlwaysResolvesChain>)>>;
             lazy_static! {
                 static ref STORE: Store = { Mutex::new(Vec::new()) };
             }
@@ -514,20 +984,24 @@ mod utils {
             let mut store = STORE.lock().unwrap();
 
             // simply increase ref count
-            if let Some(x) = store.iter().find(|(x, _)| *x == server_name) {
-                return x.1.clone();
+            if let Some(x) = store
+                .iter()
+                .find(|((x, y), z, _)| *x == server_name && *y == ca && *z == conf)
+            {
+                return x.2.clone();
             }
 
             // generate a new cert
-            let (cert, key) = generate_self_signed(&server_name);
+            let (cert, key) = generate_cert_key(&server_name, &ca, &conf);
             let resolver = new_resolver(cert, &key, None);
 
-            store.push((server_name, resolver.clone()));
+            store.push(((server_name, ca), conf, resolver.clone()));
             store.shrink_to_fit();
 
             resolver
         }
 
+        #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
         pub fn new_crt_key_resolver(
             crt: String,
             key: String,
@@ -559,3 +1033,342 @@ mod utils {
         }
     }
 }
+
+#[cfg(test)]
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+mod test {
+    use super::*;
+    use crate::nop::{NopAccept, NopConnect};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    // other tests may have installed one
+    pub fn install_provider() {
+        use tokio_rustls::rustls::crypto;
+
+        #[cfg(feature = "tls-awslc")]
+        let _ = crypto::aws_lc_rs::default_provider().install_default();
+
+        #[cfg(all(feature = "tls-ring", not(feature = "tls-awslc")))]
+        let _ = crypto::ring::default_provider().install_default();
+    }
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("kaminari-{}-{}", name, std::process::id()));
+        dir.to_str().unwrap().to_string()
+    }
+
+    pub async fn echo<C, A>(cc: &C, ac: &A) -> Result<()>
+    where
+        C: AsyncConnect<DuplexStream>,
+        A: AsyncAccept<DuplexStream>,
+    {
+        let (client, server) = tokio::io::duplex(0x4000);
+
+        let server = async move {
+            let mut buf = vec![0u8; 0x2000];
+            let mut stream = ac.accept(server, &mut buf).await?;
+            let n = stream.read(&mut buf).await?;
+            stream.write_all(&buf[..n]).await?;
+            stream.flush().await
+        };
+
+        let client = async move {
+            let mut buf = vec![0u8; 0x2000];
+            let mut stream = cc.connect(client, &mut buf).await?;
+            stream.write_all(b"kaminari").await?;
+            stream.flush().await?;
+            let n = stream.read(&mut buf).await?;
+            if &buf[..n] != b"kaminari" {
+                return Err(std::io::Error::other("bad echo"));
+            }
+            Ok(())
+        };
+
+        let (x, y) = tokio::join!(server, client);
+        x.and(y)
+    }
+
+    // a root ca that issues certificates with known serial numbers
+    pub struct Pki {
+        dir: String,
+        issuer: rcgen::Issuer<'static, utils::KeyPair>,
+    }
+
+    impl Pki {
+        pub fn new(name: &str) -> Self {
+            use rcgen::{CertificateParams, IsCa, BasicConstraints, KeyUsagePurpose};
+
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+
+            let key = utils::new_key_pair(KeyType::EcdsaP256);
+            let cert = params.self_signed(&key).unwrap();
+
+            let this = Self {
+                dir: temp_dir(name),
+                issuer: rcgen::Issuer::new(params, key),
+            };
+            utils::write_pem(&this.path("ca.crt"), &cert.pem(), 0o644).unwrap();
+            this
+        }
+
+        pub fn path(&self, file: &str) -> std::path::PathBuf {
+            std::path::Path::new(&self.dir).join(file)
+        }
+
+        pub fn ca(&self) -> String { self.path("ca.crt").to_str().unwrap().to_string() }
+
+        // returns cert and key path
+        pub fn issue(&self, name: &str, serial: u64) -> (String, String) {
+            use rcgen::ExtendedKeyUsagePurpose::{ClientAuth, ServerAuth};
+
+            let mut params = utils::new_cert_params(name, &SelfSignedConf::default());
+            params.serial_number = Some(serial.into());
+            params.use_authority_key_identifier_extension = true;
+            params.extended_key_usages = vec![ServerAuth, ClientAuth];
+
+            let key = utils::new_key_pair(KeyType::EcdsaP256);
+            let cert = params.signed_by(&key, &self.issuer).unwrap();
+
+            let crt_path = self.path(&format!("{name}-{serial}.crt"));
+            let key_path = self.path(&format!("{name}-{serial}.key"));
+            utils::write_pem(&crt_path, &cert.pem(), 0o644).unwrap();
+            utils::write_pem(&key_path, &key.serialize_pem(), 0o600).unwrap();
+
+            let path = |x: std::path::PathBuf| x.to_str().unwrap().to_string();
+            (path(crt_path), path(key_path))
+        }
+
+        // (re)write crl.pem, returns its path
+        pub fn revoke(&self, serials: &[u64]) -> String {
+            use rcgen::{CertificateRevocationListParams, RevokedCertParams, KeyIdMethod};
+            use time::{Duration, OffsetDateTime};
+
+            let now = OffsetDateTime::now_utc();
+            let revoked_certs = serials
+                .iter()
+                .map(|x| RevokedCertParams {
+                    serial_number: (*x).into(),
+                    revocation_time: now,
+                    reason_code: None,
+                    invalidity_date: None,
+                })
+                .collect();
+
+            let crl = CertificateRevocationListParams {
+                this_update: now - Duration::hours(1),
+                next_update: now + Duration::days(1),
+                crl_number: 1.into(),
+                issuing_distribution_point: None,
+                revoked_certs,
+                key_identifier_method: KeyIdMethod::Sha256,
+            }
+            .signed_by(&self.issuer)
+            .unwrap();
+
+            let crl_path = self.path("crl.pem");
+            utils::write_pem(&crl_path, &crl.pem().unwrap(), 0o644).unwrap();
+            crl_path.to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.dir); }
+    }
+
+    pub fn client_conf(pki: &Pki, crt_key: Option<(String, String)>, crl: &str) -> TlsClientConf {
+        let (crt, key) = crt_key.unwrap_or_default();
+        TlsClientConf {
+            sni: String::from("a.b.c"),
+            alpn: Vec::new(),
+            insecure: false,
+            early_data: false,
+            ca: pki.ca(),
+            crt,
+            key,
+            crl: String::from(crl),
+        }
+    }
+
+    pub fn server_conf(pki: &Pki, (crt, key): (String, String), crl: &str) -> TlsServerConf {
+        TlsServerConf {
+            crt,
+            key,
+            ocsp: String::new(),
+            server_name: String::new(),
+            ca: String::new(),
+            self_signed: SelfSignedConf::default(),
+            client_ca: if crl.is_empty() {
+                String::new()
+            } else {
+                pki.ca()
+            },
+            crl: String::from(crl),
+        }
+    }
+
+    #[tokio::test]
+    async fn crl_server_revoked() {
+        install_provider();
+        let pki = Pki::new("crl-server");
+        let crl = pki.revoke(&[1]);
+
+        let revoked = server_conf(&pki, pki.issue("a.b.c", 1), "");
+        let good = server_conf(&pki, pki.issue("a.b.c", 2), "");
+
+        let revoked = TlsAccept::new(NopAccept {}, revoked);
+        let good = TlsAccept::new(NopAccept {}, good);
+
+        let cc = TlsConnect::new(NopConnect {}, client_conf(&pki, None, &crl));
+        assert!(echo(&cc, &revoked).await.is_err());
+        echo(&cc, &good).await.unwrap();
+
+        // the verifier is shared, while the session cache is not
+        let new_shared = || TlsConnect::new_shared(NopConnect {}, client_conf(&pki, None, &crl));
+        assert!(echo(&new_shared(), &revoked).await.is_err());
+        echo(&new_shared(), &good).await.unwrap();
+
+        // reload
+        pki.revoke(&[2]);
+        echo(&new_shared(), &revoked).await.unwrap();
+        assert!(echo(&new_shared(), &good).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn crl_client_revoked() {
+        install_provider();
+        let pki = Pki::new("crl-client");
+        let crl = pki.revoke(&[1]);
+
+        let conf = server_conf(&pki, pki.issue("a.b.c", 100), &crl);
+        let ac = TlsAccept::new(NopAccept {}, conf.clone());
+        let ac_shared = TlsAccept::new_shared(NopAccept {}, conf);
+
+        let revoked = client_conf(&pki, Some(pki.issue("x", 1)), "");
+        let good = client_conf(&pki, Some(pki.issue("y", 2)), "");
+        let anonymous = client_conf(&pki, None, "");
+
+        // a new client each time, so that sessions are not resumed
+        let connect = |conf: &TlsClientConf| TlsConnect::new(NopConnect {}, conf.clone());
+
+        for ac in [&ac, &ac_shared] {
+            assert!(echo(&connect(&revoked), ac).await.is_err());
+            assert!(echo(&connect(&anonymous), ac).await.is_err());
+            echo(&connect(&good), ac).await.unwrap();
+        }
+
+        // reload
+        pki.revoke(&[2]);
+        for ac in [&ac, &ac_shared] {
+            echo(&connect(&revoked), ac).await.unwrap();
+            assert!(echo(&connect(&good), ac).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn ca_issue_and_verify() {
+        install_provider();
+        let dir = temp_dir("ca");
+
+        let server_conf = |server_name: &str| TlsServerConf {
+            crt: String::new(),
+            key: String::new(),
+            ocsp: String::new(),
+            server_name: String::from(server_name),
+            ca: dir.clone(),
+            self_signed: SelfSignedConf::default(),
+            client_ca: String::new(),
+            crl: String::new(),
+        };
+
+        let client_conf = |ca: &str| TlsClientConf {
+            sni: String::from("a.b.c"),
+            alpn: Vec::new(),
+            insecure: false,
+            early_data: false,
+            ca: String::from(ca),
+            crt: String::new(),
+            key: String::new(),
+            crl: String::new(),
+        };
+
+        let ac = TlsAccept::new(NopAccept {}, server_conf("a.b.c"));
+        let ca_crt = ca::Ca::cert_path(&dir);
+        let ca_crt = ca_crt.to_str().unwrap();
+
+        // trust the exported root
+        let cc = TlsConnect::new(NopConnect {}, client_conf(ca_crt));
+        echo(&cc, &ac).await.unwrap();
+        let cc = TlsConnect::new_shared(NopConnect {}, client_conf(ca_crt));
+        echo(&cc, &ac).await.unwrap();
+
+        // reload the same root, issue another leaf
+        let ac = TlsAccept::new_shared(NopAccept {}, server_conf("a.b.c"));
+        echo(&cc, &ac).await.unwrap();
+
+        // name mismatch
+        let ac = TlsAccept::new(NopAccept {}, server_conf("d.e.f"));
+        assert!(echo(&cc, &ac).await.is_err());
+
+        // unknown issuer
+        let cc = TlsConnect::new(NopConnect {}, client_conf(""));
+        let ac = TlsAccept::new(NopAccept {}, server_conf("a.b.c"));
+        assert!(echo(&cc, &ac).await.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn self_signed_persist() {
+        let dir = temp_dir("persist");
+        let conf = SelfSignedConf {
+            dir: dir.clone(),
+            key_type: KeyType::Ed25519,
+            validity: 30,
+            san: vec![String::from("127.0.0.1"), String::from("d.e.f")],
+        };
+
+        let (crt1, key1) = utils::generate_self_signed("a.b.c", &conf);
+        let (crt2, key2) = utils::generate_self_signed("a.b.c", &conf);
+
+        assert_eq!(crt1, crt2);
+        assert_eq!(key1.secret_der(), key2.secret_der());
+
+        // options changed
+        let mut other = conf.clone();
+        other.san.pop();
+        let (crt3, _) = utils::generate_self_signed("a.b.c", &other);
+        assert_ne!(crt2, crt3);
+
+        let mut other = conf.clone();
+        other.key_type = KeyType::EcdsaP256;
+        let (crt4, _) = utils::generate_self_signed("a.b.c", &other);
+        assert_ne!(crt3, crt4);
+        assert_eq!(crt4, utils::generate_self_signed("a.b.c", &other).0);
+
+        let mut other = conf.clone();
+        other.validity = 0;
+        let (crt5, _) = utils::generate_self_signed("a.b.c", &other);
+        assert_ne!(crt4, crt5);
+        assert_eq!(crt5, utils::generate_self_signed("a.b.c", &other).0);
+
+        // about to expire
+        {
+            use time::{Duration, OffsetDateTime};
+            let key = utils::new_key_pair(conf.key_type);
+            let mut params = utils::new_cert_params("a.b.c", &conf);
+            params.not_before = OffsetDateTime::now_utc() - Duration::days(25);
+            params.not_after = params.not_before + Duration::days(30) + Duration::hours(1);
+            let cert = params.self_signed(&key).unwrap();
+            let path = std::path::Path::new(&dir);
+            utils::write_pem(&path.join("a.b.c.crt"), &cert.pem(), 0o644).unwrap();
+            utils::write_pem(&path.join("a.b.c.key"), &key.serialize_pem(), 0o600).unwrap();
+
+            let (crt6, _) = utils::generate_self_signed("a.b.c", &conf);
+            assert_ne!(crt6[0].as_ref(), cert.der().as_ref());
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
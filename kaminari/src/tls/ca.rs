@@ -0,0 +1,114 @@
+//! Kaminari managed root CA.
+//!
+//! The root certificate and its private key are kept in a directory
+//! as `ca.crt` and `ca.key`, which are created on the first use.
+//! Server and client certificates are issued from it on demand.
+//!
+//! Export `ca.crt` to clients, then they can verify the server
+//! without `insecure`.
+
+use std::io::{Error, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rcgen::{CertificateParams, Issuer, DnType, IsCa, BasicConstraints};
+use rcgen::{KeyUsagePurpose, ExtendedKeyUsagePurpose};
+
+use super::{KeyType, SelfSignedConf};
+use super::utils::{KeyPair, new_key_pair, new_cert_params, write_pem};
+
+pub const CA_CERT_FILE: &str = "ca.crt";
+pub const CA_KEY_FILE: &str = "ca.key";
+
+pub struct Ca {
+    pem: String,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl std::fmt::Debug for Ca {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ca").field("issuer", &self.issuer).finish()
+    }
+}
+
+impl Ca {
+    /// Load the root CA from `dir`, or create a new one if it does not exist.
+    pub fn load_or_create(dir: &str, key_type: KeyType) -> Result<Self> {
+        let crt_path = Self::cert_path(dir);
+        let key_path = Path::new(dir).join(CA_KEY_FILE);
+
+        if crt_path.exists() && key_path.exists() {
+            let pem = fs::read_to_string(&crt_path)?;
+            let key = KeyPair::from_pem(&fs::read_to_string(&key_path)?).map_err(Error::other)?;
+            let issuer = Issuer::from_ca_cert_pem(&pem, key).map_err(Error::other)?;
+            return Ok(Self { pem, issuer });
+        }
+
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "kaminari root ca");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+
+        let key = new_key_pair(key_type);
+        let pem = params.self_signed(&key).map_err(Error::other)?.pem();
+
+        write_pem(&key_path, &key.serialize_pem(), 0o600)?;
+        write_pem(&crt_path, &pem, 0o644)?;
+
+        Ok(Self {
+            pem,
+            issuer: Issuer::new(params, key),
+        })
+    }
+
+    /// Path of the root certificate, which should be exported to clients.
+    pub fn cert_path(dir: &str) -> PathBuf { Path::new(dir).join(CA_CERT_FILE) }
+
+    /// Pem encoded root certificate.
+    pub fn pem(&self) -> &str { &self.pem }
+
+    /// Issue a server certificate, returns the full chain and the private key.
+    pub fn issue_server(
+        &self,
+        server_name: &str,
+        conf: &SelfSignedConf,
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let (cert, key) = self.issue(server_name, conf, ExtendedKeyUsagePurpose::ServerAuth)?;
+        let ca = rustls_pemfile::certs(&mut self.pem.as_bytes()).collect::<Result<Vec<_>>>()?;
+
+        let chain = std::iter::once(cert.der().to_owned()).chain(ca).collect();
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der()).into();
+
+        Ok((chain, key))
+    }
+
+    /// Issue a client certificate, returns pem encoded certificate and private key.
+    pub fn issue_client(&self, name: &str, conf: &SelfSignedConf) -> Result<(String, String)> {
+        let (cert, key) = self.issue(name, conf, ExtendedKeyUsagePurpose::ClientAuth)?;
+        Ok((cert.pem(), key.serialize_pem()))
+    }
+
+    fn issue(
+        &self,
+        name: &str,
+        conf: &SelfSignedConf,
+        usage: ExtendedKeyUsagePurpose,
+    ) -> Result<(rcgen::Certificate, KeyPair)> {
+        let mut params = new_cert_params(name, conf);
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![usage];
+
+        let key = new_key_pair(conf.key_type);
+        let cert = params.signed_by(&key, &self.issuer).map_err(Error::other)?;
+
+        Ok((cert, key))
+    }
+}
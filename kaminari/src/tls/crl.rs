@@ -0,0 +1,267 @@
+//! Certificate revocation lists.
+//!
+//! CRL files (pem or der) are checked against the leaf certificate
+//! of the peer. A file is read again on a handshake, at most once a
+//! second, and loaded once its content changes, so that a revocation
+//! takes effect without restarting. If the new content is broken, the
+//! previous list is still in use.
+//!
+//! A certificate whose issuer has no list is not rejected.
+//! Resumed sessions are not checked again.
+
+use std::fs;
+use std::io::{Error, Result};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use std::fmt::{Debug, Formatter};
+
+use tokio_rustls::rustls::{self, pki_types};
+use pki_types::{CertificateDer, CertificateRevocationListDer, ServerName, UnixTime};
+use rustls::{RootCertStore, DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+
+pub fn parse_crls(data: &[u8]) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+    // pem
+    let crls = rustls_pemfile::crls(&mut &data[..]).collect::<Result<Vec<_>>>()?;
+
+    // der
+    if crls.is_empty() {
+        return Ok(vec![CertificateRevocationListDer::from(data.to_vec())]);
+    }
+
+    Ok(crls)
+}
+
+// ========== reload ==========
+type Load<T> = Box<dyn Fn(&[u8]) -> Result<T> + Send + Sync>;
+
+// tests rewrite the file right before a handshake
+const CHECK_INTERVAL: Duration = match cfg!(test) {
+    true => Duration::ZERO,
+    false => Duration::from_secs(1),
+};
+
+/// Content loaded from a file, which is loaded again
+/// when the content of the file changes.
+pub struct Reload<T> {
+    path: String,
+    load: Load<T>,
+    cache: RwLock<Cache<T>>,
+}
+
+struct Cache<T> {
+    checked: Instant,
+    data: Vec<u8>,
+    value: T,
+}
+
+impl<T> Debug for Reload<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reload").field("path", &self.path).finish()
+    }
+}
+
+impl<T: Clone> Reload<T> {
+    pub fn new<F>(path: String, load: F) -> Result<Self>
+    where
+        F: Fn(&[u8]) -> Result<T> + Send + Sync + 'static,
+    {
+        let data = fs::read(&path)?;
+        let value = load(&data)?;
+        Ok(Self {
+            path,
+            load: Box::new(load),
+            cache: RwLock::new(Cache {
+                checked: Instant::now(),
+                data,
+                value,
+            }),
+        })
+    }
+
+    /// Loaded content, without checking the file.
+    pub fn get(&self) -> T { self.cache.read().unwrap().value.clone() }
+
+    /// Check the file if not checked within the interval, then [`get`](Self::get).
+    pub fn refresh(&self) -> T {
+        {
+            let cache = self.cache.read().unwrap();
+            if cache.checked.elapsed() < CHECK_INTERVAL {
+                return cache.value.clone();
+            }
+        }
+
+        let mut cache = self.cache.write().unwrap();
+
+        // check again, someone else may have reloaded it
+        if cache.checked.elapsed() >= CHECK_INTERVAL {
+            cache.checked = Instant::now();
+            match fs::read(&self.path) {
+                Ok(data) if data != cache.data => {
+                    if let Ok(value) = (self.load)(&data) {
+                        cache.value = value;
+                    }
+                    cache.data = data;
+                }
+                _ => {}
+            }
+        }
+
+        cache.value.clone()
+    }
+}
+
+// ========== client ==========
+/// Verify server certificates with a reloadable CRL.
+#[derive(Debug)]
+pub struct CrlServerVerifier(Reload<Arc<WebPkiServerVerifier>>);
+
+impl CrlServerVerifier {
+    pub fn new(roots: Arc<RootCertStore>, crl: String) -> Result<Self> {
+        let load = move |data: &[u8]| {
+            WebPkiServerVerifier::builder(roots.clone())
+                .with_crls(parse_crls(data)?)
+                .only_check_end_entity_revocation()
+                .allow_unknown_revocation_status()
+                .build()
+                .map_err(Error::other)
+        };
+        Reload::new(crl, load).map(Self)
+    }
+}
+
+impl ServerCertVerifier for CrlServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        self.0.refresh().verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.get().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.get().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.get().supported_verify_schemes()
+    }
+}
+
+// ========== server ==========
+/// Verify client certificates with a reloadable CRL.
+#[derive(Debug)]
+pub struct CrlClientVerifier {
+    // roots never change
+    hints: Vec<DistinguishedName>,
+    inner: Reload<Arc<dyn ClientCertVerifier>>,
+}
+
+impl CrlClientVerifier {
+    pub fn new(roots: Arc<RootCertStore>, crl: String) -> Result<Self> {
+        let hints = roots.subjects();
+        let load = move |data: &[u8]| {
+            WebPkiClientVerifier::builder(roots.clone())
+                .with_crls(parse_crls(data)?)
+                .only_check_end_entity_revocation()
+                .allow_unknown_revocation_status()
+                .build()
+                .map_err(Error::other)
+        };
+        Reload::new(crl, load).map(|inner| Self { hints, inner })
+    }
+}
+
+impl ClientCertVerifier for CrlClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] { &self.hints }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        self.inner
+            .refresh()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.get().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.get().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.get().supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reload() {
+        let path = std::env::temp_dir().join(format!("kaminari-reload-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "1").unwrap();
+
+        let parse = |data: &[u8]| {
+            std::str::from_utf8(data)
+                .map_err(Error::other)?
+                .parse::<u8>()
+                .map_err(Error::other)
+        };
+        let reload = Reload::new(path.clone(), parse).unwrap();
+
+        // same length, maybe the same mtime
+        fs::write(&path, "2").unwrap();
+        assert_eq!(reload.get(), 1);
+        assert_eq!(reload.refresh(), 2);
+
+        // broken, or gone
+        fs::write(&path, "x").unwrap();
+        assert_eq!(reload.refresh(), 2);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reload.refresh(), 2);
+    }
+}
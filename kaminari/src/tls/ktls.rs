@@ -0,0 +1,1055 @@
+//! Kernel TLS offload, linux only.
+//!
+//! [`KtlsConnect`] and [`KtlsAccept`] complete the handshake in userspace,
+//! then hand the session keys to the kernel with `setsockopt(TLS_TX/TLS_RX)`.
+//! After that, reads and writes on the socket are plaintext, so that data
+//! can be spliced without any userspace crypto.
+//!
+//! If the `tls` module is unavailable or the cipher suite is not supported
+//! by the kernel, the stream stays in userspace. Support of each version
+//! and cipher is probed once on a loopback socket, before the keys are
+//! taken out of rustls.
+//!
+//! The server does not send session tickets, since post-handshake messages
+//! can not be spliced. Such messages sent by other servers are skipped.
+//! A TLS 1.3 key update from the peer can not be followed by the kernel,
+//! the stream fails with an error instead.
+//!
+//! On shutdown, a close_notify alert is sent before the socket is closed.
+
+use std::io::{Error, Result};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::task::{Context, Poll, ready};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::fmt::{Debug, Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use realm_io::AsyncRawIO;
+
+use tokio_rustls::rustls::{self, Connection, ConnectionTrafficSecrets, CipherSuite};
+use rustls::{ClientConfig, ServerConfig, ProtocolVersion};
+
+use super::{TlsConnect, TlsAccept, TlsClientConf, TlsServerConf};
+use crate::{IOStream, AsyncAccept, AsyncConnect};
+
+// ========== client ==========
+#[derive(Clone)]
+pub struct KtlsConnect<T> {
+    tls: TlsConnect<Cork<T>>,
+    probe: Probe,
+}
+
+impl<T> Display for KtlsConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[ktls]{}", self.tls.conn.0)
+    }
+}
+
+impl<T> Debug for KtlsConnect<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KtlsConnect")
+            .field("conn", &self.tls.conn.0)
+            .field("sni", &self.tls.sni)
+            .finish()
+    }
+}
+
+impl<T> KtlsConnect<T> {
+    pub fn new(conn: T, conf: TlsClientConf) -> Self {
+        Self::from_tls(TlsConnect::new(Cork(conn), conf))
+    }
+
+    // use shared roots
+    pub fn new_shared(conn: T, conf: TlsClientConf) -> Self {
+        Self::from_tls(TlsConnect::new_shared(Cork(conn), conf))
+    }
+
+    fn from_tls(mut tls: TlsConnect<Cork<T>>) -> Self {
+        let mut conf = ClientConfig::clone(tls.cc.config());
+        conf.enable_secret_extraction = true;
+        conf.enable_early_data = false;
+        tls.cc = Arc::new(conf).into();
+        Self {
+            tls,
+            probe: supported,
+        }
+    }
+}
+
+impl<S, T> AsyncConnect<S> for KtlsConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+    T::Stream: AsyncRawIO,
+{
+    type Stream = KtlsStream<T::Stream>;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.tls.connect(stream, buf).await?;
+            offload(stream.into(), self.probe)
+        }
+    }
+}
+
+// ========== server ==========
+#[derive(Clone)]
+pub struct KtlsAccept<T> {
+    tls: TlsAccept<Cork<T>>,
+    probe: Probe,
+}
+
+impl<T> Display for KtlsAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[ktls]{}", self.tls.lis.0)
+    }
+}
+
+impl<T> Debug for KtlsAccept<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KtlsAccept")
+            .field("lis", &self.tls.lis.0)
+            .finish()
+    }
+}
+
+impl<T> KtlsAccept<T> {
+    pub fn new(lis: T, conf: TlsServerConf) -> Self {
+        Self::from_tls(TlsAccept::new(Cork(lis), conf))
+    }
+
+    // use shared cert, key
+    pub fn new_shared(lis: T, conf: TlsServerConf) -> Self {
+        Self::from_tls(TlsAccept::new_shared(Cork(lis), conf))
+    }
+
+    fn from_tls(mut tls: TlsAccept<Cork<T>>) -> Self {
+        let mut conf = ServerConfig::clone(tls.ac.config());
+        conf.enable_secret_extraction = true;
+        conf.send_tls13_tickets = 0;
+        tls.ac = Arc::new(conf).into();
+        Self {
+            tls,
+            probe: supported,
+        }
+    }
+}
+
+impl<S, T> AsyncAccept<S> for KtlsAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+    T::Stream: AsyncRawIO,
+{
+    type Stream = KtlsStream<T::Stream>;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let stream = self.tls.accept(stream, buf).await?;
+            offload(stream.into(), self.probe)
+        }
+    }
+}
+
+// ========== stream ==========
+pub enum KtlsStream<S> {
+    Kernel(KernelStream<S>),
+    User(Box<tokio_rustls::TlsStream<CorkStream<S>>>),
+}
+
+impl<S> KtlsStream<S> {
+    #[inline]
+    pub fn is_kernel(&self) -> bool { matches!(self, Self::Kernel(_)) }
+}
+
+impl<S> AsyncRead for KtlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + AsyncRawIO + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Kernel(x) => Pin::new(x).poll_read(cx, buf),
+            Self::User(x) => Pin::new(x).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for KtlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + AsyncRawIO + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Self::Kernel(x) => Pin::new(x).poll_write(cx, buf),
+            Self::User(x) => Pin::new(x).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Kernel(x) => Pin::new(x).poll_flush(cx),
+            Self::User(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Kernel(x) => Pin::new(x).poll_shutdown(cx),
+            Self::User(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Socket with keys installed, which reads and writes plaintext.
+pub struct KernelStream<S> {
+    io: S,
+    close_notify: bool,
+}
+
+impl<S> KernelStream<S> {
+    #[inline]
+    pub fn get_ref(&self) -> &S { &self.io }
+}
+
+impl<S: AsRawFd> AsRawFd for KernelStream<S> {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd { self.io.as_raw_fd() }
+}
+
+impl<S> AsyncRead for KernelStream<S>
+where
+    S: AsyncRead + AsyncRawIO + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let fd = this.as_raw_fd();
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            ready!(this.io.x_poll_read_ready(cx))?;
+
+            let dst = buf.initialize_unfilled();
+            let res = this
+                .io
+                .x_try_io(Interest::READABLE, || record::recv(fd, dst, 0));
+
+            match res {
+                Ok((n, record::APPLICATION_DATA)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok((n, record::ALERT)) => {
+                    return Poll::Ready(record::alert(&buf.initialize_unfilled()[..n]));
+                }
+                // post-handshake messages, e.g. session tickets
+                Ok((n, record::HANDSHAKE)) => {
+                    record::handshake(&buf.initialize_unfilled()[..n])?;
+                    continue;
+                }
+                Ok((_, kind)) => return Poll::Ready(Err(record::unexpected(kind))),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for KernelStream<S>
+where
+    S: AsyncWrite + AsyncRawIO + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    // send close_notify first, or the peer sees a truncated stream
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let fd = this.as_raw_fd();
+
+        while !this.close_notify {
+            ready!(this.io.x_poll_write_ready(cx))?;
+
+            let res = this.io.x_try_io(Interest::WRITABLE, || {
+                record::send(fd, &record::CLOSE_NOTIFY, record::ALERT)
+            });
+
+            match res {
+                Ok(_) => this.close_notify = true,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+impl<S> AsyncRawIO for KernelStream<S>
+where
+    S: AsyncRawIO,
+{
+    #[inline]
+    fn x_poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.io.x_poll_read_ready(cx)
+    }
+
+    #[inline]
+    fn x_poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.io.x_poll_write_ready(cx)
+    }
+
+    #[inline]
+    fn x_try_io<R>(&self, interest: Interest, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        self.io.x_try_io(interest, f)
+    }
+
+    // splice refuses to read a control record, handle it here
+    fn poll_read_raw<F>(&self, cx: &mut Context<'_>, mut syscall: F) -> Poll<Result<usize>>
+    where
+        F: FnMut() -> isize,
+    {
+        let fd = self.as_raw_fd();
+        self.io.poll_read_raw(cx, || loop {
+            let n = syscall();
+            if n >= 0 {
+                return n;
+            }
+            match record::control(fd) {
+                Some(record::Control::Skip) => continue,
+                Some(record::Control::Eof) => return 0,
+                None => return n,
+            }
+        })
+    }
+}
+
+// ========== offload ==========
+// whether the kernel takes keys of a version and cipher
+type Probe = fn(u16, u16) -> bool;
+
+fn cipher_of(suite: CipherSuite) -> Option<u16> {
+    use CipherSuite::*;
+    match suite {
+        TLS13_AES_128_GCM_SHA256
+        | TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+        | TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => Some(libc::TLS_CIPHER_AES_GCM_128),
+        TLS13_AES_256_GCM_SHA384
+        | TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+        | TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 => Some(libc::TLS_CIPHER_AES_GCM_256),
+        TLS13_CHACHA20_POLY1305_SHA256
+        | TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+        | TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 => Some(libc::TLS_CIPHER_CHACHA20_POLY1305),
+        _ => None,
+    }
+}
+
+// probed once for each version and cipher
+static SUPPORTED: Mutex<Vec<((u16, u16), bool)>> = Mutex::new(Vec::new());
+
+fn supported(version: u16, cipher: u16) -> bool {
+    let mut cache = SUPPORTED.lock().unwrap();
+    if let Some((_, x)) = cache.iter().find(|(k, _)| *k == (version, cipher)) {
+        return *x;
+    }
+
+    let ok = probe(version, cipher).is_ok();
+    cache.push(((version, cipher), ok));
+    ok
+}
+
+// install zero keys in both directions on a loopback connection
+fn probe(version: u16, cipher: u16) -> Result<()> {
+    let lis = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let stream = TcpStream::connect(lis.local_addr()?)?;
+    let _peer = lis.accept()?;
+
+    let fd = stream.as_raw_fd();
+    let (key, iv) = ([0u8; 32], [0u8; 12]);
+    let key = match cipher {
+        libc::TLS_CIPHER_AES_GCM_128 => &key[..16],
+        _ => &key[..],
+    };
+    sys::set_ulp(fd)?;
+    sys::set_crypto(fd, libc::TLS_TX, version, cipher, 0, key, &iv)?;
+    sys::set_crypto(fd, libc::TLS_RX, version, cipher, 0, key, &iv)
+}
+
+fn offload<S>(
+    mut stream: tokio_rustls::TlsStream<CorkStream<S>>,
+    probe: Probe,
+) -> Result<KtlsStream<S>>
+where
+    S: AsRawFd,
+{
+    let (io, conn) = stream.get_ref();
+
+    let version = match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => Some(libc::TLS_1_2_VERSION),
+        Some(ProtocolVersion::TLSv1_3) => Some(libc::TLS_1_3_VERSION),
+        _ => None,
+    };
+    let cipher = conn
+        .negotiated_cipher_suite()
+        .and_then(|x| cipher_of(x.suite()));
+    let supported = match (version, cipher) {
+        (Some(version), Some(cipher)) => probe(version, cipher),
+        _ => false,
+    };
+
+    // keep it in userspace
+    if !supported || conn.wants_write() || sys::set_ulp(io.as_raw_fd()).is_err() {
+        stream.get_mut().0.corked = false;
+        return Ok(KtlsStream::User(Box::new(stream)));
+    }
+
+    let (io, conn) = match stream {
+        tokio_rustls::TlsStream::Client(x) => {
+            let (io, conn) = x.into_inner();
+            (io, Connection::from(conn))
+        }
+        tokio_rustls::TlsStream::Server(x) => {
+            let (io, conn) = x.into_inner();
+            (io, Connection::from(conn))
+        }
+    };
+
+    let version = version.unwrap();
+    let secrets = conn.dangerous_extract_secrets().map_err(Error::other)?;
+
+    let fd = io.as_raw_fd();
+    for (direction, (seq, secrets)) in [(libc::TLS_TX, secrets.tx), (libc::TLS_RX, secrets.rx)] {
+        let (cipher, key, iv) = sys::split_secrets(&secrets)?;
+        sys::set_crypto(fd, direction, version, cipher, seq, key, iv)?;
+    }
+
+    Ok(KtlsStream::Kernel(KernelStream {
+        io: io.io,
+        close_notify: false,
+    }))
+}
+
+// ========== cork ==========
+// read at most one record at a time during the handshake,
+// so that no data is left in rustls after that.
+#[derive(Debug, Clone, Copy)]
+struct Cork<T>(T);
+
+pub struct CorkStream<S> {
+    io: S,
+    corked: bool,
+    header: [u8; 5],
+    hlen: usize,
+    left: usize,
+}
+
+impl<S, T> AsyncConnect<S> for Cork<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+{
+    type Stream = CorkStream<T::Stream>;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move { self.0.connect(stream, buf).await.map(CorkStream::new) }
+    }
+}
+
+impl<S, T> AsyncAccept<S> for Cork<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+{
+    type Stream = CorkStream<T::Stream>;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move { self.0.accept(stream, buf).await.map(CorkStream::new) }
+    }
+}
+
+impl<S> CorkStream<S> {
+    fn new(io: S) -> Self {
+        Self {
+            io,
+            corked: true,
+            header: [0; 5],
+            hlen: 0,
+            left: 0,
+        }
+    }
+}
+
+impl<S: AsRawFd> AsRawFd for CorkStream<S> {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd { self.io.as_raw_fd() }
+}
+
+impl<S> AsyncRead for CorkStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if !this.corked {
+            return Pin::new(&mut this.io).poll_read(cx, buf);
+        }
+
+        // header or payload
+        let want = if this.left != 0 {
+            this.left
+        } else {
+            this.header.len() - this.hlen
+        };
+
+        let mut limited = buf.take(want);
+        ready!(Pin::new(&mut this.io).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+
+        if this.left != 0 {
+            this.left -= n;
+        } else {
+            this.header[this.hlen..this.hlen + n].copy_from_slice(limited.filled());
+            this.hlen += n;
+            if this.hlen == this.header.len() {
+                this.left = u16::from_be_bytes([this.header[3], this.header[4]]) as usize;
+                this.hlen = 0;
+            }
+        }
+
+        // SAFETY: filled by the inner reader
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for CorkStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// ========== syscall ==========
+mod sys {
+    use std::io::{Error, Result};
+    use std::os::unix::io::RawFd;
+    use std::mem::size_of;
+    use libc::{c_int, c_void, socklen_t};
+    use libc::{tls_crypto_info, tls12_crypto_info_aes_gcm_128};
+    use libc::{tls12_crypto_info_aes_gcm_256, tls12_crypto_info_chacha20_poly1305};
+
+    use super::ConnectionTrafficSecrets;
+
+    unsafe fn setsockopt<T>(fd: RawFd, level: c_int, name: c_int, value: &T) -> Result<()> {
+        let ptr = value as *const T as *const c_void;
+        match libc::setsockopt(fd, level, name, ptr, size_of::<T>() as socklen_t) {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    // fails with ENOENT if the module is unavailable
+    pub fn set_ulp(fd: RawFd) -> Result<()> {
+        unsafe { setsockopt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls") }
+    }
+
+    // cipher type, key and iv
+    pub fn split_secrets(secrets: &ConnectionTrafficSecrets) -> Result<(u16, &[u8], &[u8])> {
+        use ConnectionTrafficSecrets::*;
+        match secrets {
+            Aes128Gcm { key, iv } => Ok((libc::TLS_CIPHER_AES_GCM_128, key.as_ref(), iv.as_ref())),
+            Aes256Gcm { key, iv } => Ok((libc::TLS_CIPHER_AES_GCM_256, key.as_ref(), iv.as_ref())),
+            Chacha20Poly1305 { key, iv } => Ok((
+                libc::TLS_CIPHER_CHACHA20_POLY1305,
+                key.as_ref(),
+                iv.as_ref(),
+            )),
+            _ => Err(Error::other("unsupported cipher")),
+        }
+    }
+
+    pub fn set_crypto(
+        fd: RawFd,
+        direction: c_int,
+        version: u16,
+        cipher: u16,
+        seq: u64,
+        key: &[u8],
+        iv: &[u8],
+    ) -> Result<()> {
+        let rec_seq = seq.to_be_bytes();
+        let info = tls_crypto_info {
+            version,
+            cipher_type: cipher,
+        };
+        let invalid = |_| Error::other("invalid key length");
+
+        unsafe {
+            match cipher {
+                libc::TLS_CIPHER_AES_GCM_128 => {
+                    let (salt, iv) = iv.split_at(4);
+                    let crypto = tls12_crypto_info_aes_gcm_128 {
+                        info,
+                        iv: iv.try_into().map_err(invalid)?,
+                        key: key.try_into().map_err(invalid)?,
+                        salt: salt.try_into().map_err(invalid)?,
+                        rec_seq,
+                    };
+                    setsockopt(fd, libc::SOL_TLS, direction, &crypto)
+                }
+                libc::TLS_CIPHER_AES_GCM_256 => {
+                    let (salt, iv) = iv.split_at(4);
+                    let crypto = tls12_crypto_info_aes_gcm_256 {
+                        info,
+                        iv: iv.try_into().map_err(invalid)?,
+                        key: key.try_into().map_err(invalid)?,
+                        salt: salt.try_into().map_err(invalid)?,
+                        rec_seq,
+                    };
+                    setsockopt(fd, libc::SOL_TLS, direction, &crypto)
+                }
+                libc::TLS_CIPHER_CHACHA20_POLY1305 => {
+                    let crypto = tls12_crypto_info_chacha20_poly1305 {
+                        info,
+                        iv: iv.try_into().map_err(invalid)?,
+                        key: key.try_into().map_err(invalid)?,
+                        salt: [],
+                        rec_seq,
+                    };
+                    setsockopt(fd, libc::SOL_TLS, direction, &crypto)
+                }
+                _ => Err(Error::other("unsupported cipher")),
+            }
+        }
+    }
+}
+
+mod record {
+    use std::io::{Error, Result};
+    use std::os::unix::io::RawFd;
+    use std::mem::{size_of_val, zeroed};
+
+    pub const ALERT: u8 = 21;
+    pub const HANDSHAKE: u8 = 22;
+    pub const APPLICATION_DATA: u8 = 23;
+
+    // level warning, description close_notify
+    pub const CLOSE_NOTIFY: [u8; 2] = [1, 0];
+
+    const NEW_SESSION_TICKET: u8 = 4;
+    const KEY_UPDATE: u8 = 24;
+
+    const MAX_RECORD: usize = 0x4000 + 0x100;
+
+    pub enum Control {
+        Skip,
+        Eof,
+    }
+
+    // returns the length and type of a record
+    pub fn recv(fd: RawFd, buf: &mut [u8], flags: libc::c_int) -> Result<(usize, u8)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let mut cmsg = [0u64; 8];
+
+        unsafe {
+            let mut msg: libc::msghdr = zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = cmsg.as_mut_ptr().cast();
+            msg.msg_controllen = size_of_val(&cmsg) as _;
+
+            let n = libc::recvmsg(fd, &mut msg, flags);
+            if n < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let mut kind = APPLICATION_DATA;
+            let mut hdr = libc::CMSG_FIRSTHDR(&msg);
+            while !hdr.is_null() {
+                if (*hdr).cmsg_level == libc::SOL_TLS
+                    && (*hdr).cmsg_type == libc::TLS_GET_RECORD_TYPE
+                {
+                    kind = *libc::CMSG_DATA(hdr);
+                }
+                hdr = libc::CMSG_NXTHDR(&msg, hdr);
+            }
+
+            Ok((n as usize, kind))
+        }
+    }
+
+    // send one record of the given type
+    pub fn send(fd: RawFd, buf: &[u8], kind: u8) -> Result<usize> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len(),
+        };
+        let mut cmsg = [0u64; 4];
+
+        unsafe {
+            let mut msg: libc::msghdr = zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = cmsg.as_mut_ptr().cast();
+            msg.msg_controllen = libc::CMSG_SPACE(1) as _;
+
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_level = libc::SOL_TLS;
+            (*hdr).cmsg_type = libc::TLS_SET_RECORD_TYPE;
+            (*hdr).cmsg_len = libc::CMSG_LEN(1) as _;
+            *libc::CMSG_DATA(hdr) = kind;
+
+            let n = libc::sendmsg(fd, &msg, 0);
+            if n < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(n as usize)
+        }
+    }
+
+    // close_notify is the end of stream
+    pub fn alert(buf: &[u8]) -> Result<()> {
+        match buf {
+            [1, 0] => Ok(()),
+            _ => Err(Error::other(format!("tls alert: {buf:?}"))),
+        }
+    }
+
+    // only session tickets can be skipped, the kernel
+    // does not follow a key update
+    pub fn handshake(mut buf: &[u8]) -> Result<()> {
+        while let [kind, a, b, c, rest @ ..] = buf {
+            match *kind {
+                NEW_SESSION_TICKET => {}
+                KEY_UPDATE => return Err(Error::other("tls key update is not supported by ktls")),
+                x => {
+                    return Err(Error::other(format!(
+                        "unexpected tls handshake message: {x}"
+                    )))
+                }
+            }
+            let len = u32::from_be_bytes([0, *a, *b, *c]) as usize;
+            buf = rest.get(len..).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn unexpected(kind: u8) -> Error { Error::other(format!("unexpected tls record: {kind}")) }
+
+    #[inline]
+    fn set_errno(errno: i32) { unsafe { *libc::__errno_location() = errno } }
+
+    // called after a failed splice, errno is kept if
+    // the error is not caused by a control record
+    pub fn control(fd: RawFd) -> Option<Control> {
+        let errno = Error::last_os_error().raw_os_error().unwrap_or(0);
+        if errno != libc::EINVAL && errno != libc::EIO {
+            return None;
+        }
+
+        match recv(fd, &mut [0u8; 1], libc::MSG_PEEK) {
+            Ok((_, kind)) if kind != APPLICATION_DATA => {}
+            _ => {
+                set_errno(errno);
+                return None;
+            }
+        }
+
+        let mut buf = vec![0u8; MAX_RECORD];
+        match recv(fd, &mut buf, 0) {
+            Ok((n, HANDSHAKE)) if handshake(&buf[..n]).is_ok() => Some(Control::Skip),
+            Ok((n, ALERT)) if alert(&buf[..n]).is_ok() => Some(Control::Eof),
+            Ok(_) => {
+                set_errno(libc::EPROTO);
+                None
+            }
+            Err(e) => {
+                set_errno(e.raw_os_error().unwrap_or(libc::EIO));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "proxy")]
+impl<S> crate::proxy::ClientAddr for KtlsStream<S>
+where
+    S: crate::proxy::ClientAddr,
+{
+    fn client_addr(&self, peer: std::net::SocketAddr) -> std::net::SocketAddr {
+        match self {
+            Self::Kernel(x) => x.io.client_addr(peer),
+            Self::User(x) => x.get_ref().0.io.client_addr(peer),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+mod test {
+    use super::*;
+    use super::super::test::install_provider;
+    use super::super::SelfSignedConf;
+    use crate::nop::{NopAccept, NopConnect};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn client_conf() -> TlsClientConf {
+        TlsClientConf {
+            sni: String::from("localhost"),
+            alpn: Vec::new(),
+            insecure: true,
+            early_data: false,
+            ca: String::new(),
+            crt: String::new(),
+            key: String::new(),
+            crl: String::new(),
+        }
+    }
+
+    fn server_conf() -> TlsServerConf {
+        TlsServerConf {
+            crt: String::new(),
+            key: String::new(),
+            ocsp: String::new(),
+            server_name: String::from("localhost"),
+            ca: String::new(),
+            self_signed: SelfSignedConf::default(),
+            client_ca: String::new(),
+            crl: String::new(),
+        }
+    }
+
+    // the tls module may be missing, e.g. in containers
+    async fn has_ulp() -> bool {
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(lis.local_addr().unwrap()).await.unwrap();
+        let _accepted = lis.accept().await.unwrap();
+        sys::set_ulp(stream.as_raw_fd()).is_ok()
+    }
+
+    async fn handshake<C, A>(cc: &C, ac: &A) -> (C::Stream, A::Stream)
+    where
+        C: AsyncConnect<TcpStream>,
+        A: AsyncAccept<TcpStream>,
+    {
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = lis.local_addr().unwrap();
+
+        let server = async {
+            let mut buf = vec![0u8; 0x2000];
+            let (stream, _) = lis.accept().await.unwrap();
+            ac.accept(stream, &mut buf).await.unwrap()
+        };
+
+        let client = async {
+            let mut buf = vec![0u8; 0x2000];
+            let stream = TcpStream::connect(addr).await.unwrap();
+            cc.connect(stream, &mut buf).await.unwrap()
+        };
+
+        let (server, client) = tokio::join!(server, client);
+        (client, server)
+    }
+
+    // send until eof, then receive until eof
+    async fn echo<C, A>(mut client: C, mut server: A)
+    where
+        C: IOStream,
+        A: IOStream,
+    {
+        let data: Vec<u8> = (0..0x20000).map(|x| x as u8).collect();
+
+        let server = async {
+            let mut recv = Vec::new();
+            server.read_to_end(&mut recv).await.unwrap();
+            server.write_all(&recv).await.unwrap();
+            server.shutdown().await.unwrap();
+        };
+
+        let client = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut recv = Vec::new();
+            client.read_to_end(&mut recv).await.unwrap();
+            assert_eq!(recv, data);
+        };
+
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn ktls_to_ktls() {
+        install_provider();
+        let cc = KtlsConnect::new(NopConnect {}, client_conf());
+        let ac = KtlsAccept::new(NopAccept {}, server_conf());
+        let (client, server) = handshake(&cc, &ac).await;
+        echo(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn ktls_to_tls() {
+        install_provider();
+        let cc = KtlsConnect::new(NopConnect {}, client_conf());
+        let ac = TlsAccept::new(NopAccept {}, server_conf());
+        let (client, server) = handshake(&cc, &ac).await;
+        echo(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn tls_to_ktls() {
+        install_provider();
+        let cc = TlsConnect::new(NopConnect {}, client_conf());
+        let ac = KtlsAccept::new_shared(NopAccept {}, server_conf());
+        let (client, server) = handshake(&cc, &ac).await;
+        echo(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn ktls_kernel() {
+        if !has_ulp().await {
+            eprintln!("tls ulp is unavailable, skipped");
+            return;
+        }
+
+        install_provider();
+        let cc = KtlsConnect::new(NopConnect {}, client_conf());
+        let ac = KtlsAccept::new(NopAccept {}, server_conf());
+
+        let (client, server) = handshake(&cc, &ac).await;
+        assert!(client.is_kernel() && server.is_kernel());
+        echo(client, server).await;
+
+        // rustls sends session tickets, and expects close_notify
+        let (client, server) = handshake(&cc, &TlsAccept::new(NopAccept {}, server_conf())).await;
+        assert!(client.is_kernel());
+        echo(client, server).await;
+
+        let (client, server) = handshake(&TlsConnect::new(NopConnect {}, client_conf()), &ac).await;
+        assert!(server.is_kernel());
+        echo(client, server).await;
+
+        // the kernel does not know the cipher
+        assert!(probe(libc::TLS_1_3_VERSION, 0).is_err());
+    }
+
+    // keys the kernel would refuse stay in userspace
+    #[tokio::test]
+    async fn ktls_unsupported() {
+        install_provider();
+        let mut cc = KtlsConnect::new(NopConnect {}, client_conf());
+        let mut ac = KtlsAccept::new(NopAccept {}, server_conf());
+        cc.probe = |_, _| false;
+        ac.probe = |_, _| false;
+
+        let (client, server) = handshake(&cc, &ac).await;
+        assert!(!client.is_kernel() && !server.is_kernel());
+        echo(client, server).await;
+    }
+
+    #[test]
+    fn post_handshake() {
+        let ticket = [4, 0, 0, 2, 0xaa, 0xbb];
+        assert!(record::handshake(&ticket).is_ok());
+        assert!(record::handshake(&[ticket, ticket].concat()).is_ok());
+
+        let key_update = [24, 0, 0, 1, 0];
+        assert!(record::handshake(&key_update).is_err());
+        assert!(record::handshake(&[&ticket[..], &key_update].concat()).is_err());
+    }
+
+    #[tokio::test]
+    async fn cork_stream() {
+        let (mut w, r) = tokio::io::duplex(0x100);
+        let mut r = CorkStream::new(r);
+
+        // two records in one write
+        w.write_all(&[23, 3, 3, 0, 2, b'a', b'b', 23, 3, 3, 0, 1, b'c'])
+            .await
+            .unwrap();
+
+        // one record at a time
+        let mut buf = [0u8; 0x100];
+        for expect in [&[23, 3, 3, 0, 2][..], b"ab", &[23, 3, 3, 0, 1], b"c"] {
+            let n = r.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], expect);
+        }
+    }
+}
@@ -0,0 +1,676 @@
+//! OpenSSL backend.
+//!
+//! Provides [`TlsConnect`] and [`TlsAccept`] which share the same
+//! [`TlsClientConf`] and [`TlsServerConf`] with the rustls backend.
+//!
+//! Client side early data is not supported, `0rtt` is ignored.
+//!
+//! Revocation is checked in the verify callback, since the `openssl`
+//! crate does not expose a safe way to add CRLs to a certificate store.
+
+use std::io::{Error, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::Mutex;
+use std::fmt::{Debug, Display, Formatter};
+
+use super::{TlsClientConf, TlsServerConf};
+use crate::{IOStream, AsyncAccept, AsyncConnect};
+
+use ::openssl::ssl::{self, Ssl, SslMethod, SslVerifyMode};
+use ::openssl::ssl::{SslAcceptor, SslConnector};
+
+use lazy_static::lazy_static;
+
+pub use tokio_openssl::SslStream as TlsClientStream;
+pub use tokio_openssl::SslStream as TlsServerStream;
+
+pub fn install_provider() { ::openssl::init() }
+
+#[inline]
+fn ssl_error(e: ssl::Error) -> Error { e.into_io_error().unwrap_or_else(Error::other) }
+
+// ========== client ==========
+#[derive(Clone)]
+pub struct TlsConnect<T> {
+    conn: T,
+    sni: String,
+    insecure: bool,
+    cc: SslConnector,
+}
+
+impl<T> Display for TlsConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[tls]{}", self.conn) }
+}
+
+impl<T> Debug for TlsConnect<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConnect")
+            .field("conn", &self.conn)
+            .field("sni", &self.sni)
+            .finish()
+    }
+}
+
+impl<T> TlsConnect<T> {
+    pub fn new(conn: T, conf: TlsClientConf) -> Self {
+        let TlsClientConf {
+            sni,
+            alpn,
+            insecure,
+            ca,
+            crt,
+            key,
+            crl,
+            ..
+        } = conf;
+
+        let mut builder =
+            SslConnector::builder(SslMethod::tls_client()).expect("failed to init openssl");
+
+        if insecure {
+            builder.set_verify(SslVerifyMode::NONE);
+        } else {
+            if !ca.is_empty() {
+                builder.set_cert_store(utils::read_roots(&ca).expect("failed to read ca"));
+            }
+            if !crl.is_empty() {
+                builder.set_verify_callback(SslVerifyMode::PEER, utils::new_crl_callback(crl));
+            }
+        }
+
+        if !crt.is_empty() && !key.is_empty() {
+            let crt = super::utils::read_certificates(&crt).expect("failed to read certificate");
+            let key = super::utils::read_private_key(&key).expect("failed to read private key");
+            utils::set_cert_key(&mut builder, &crt, &key);
+        }
+
+        if !alpn.is_empty() {
+            builder
+                .set_alpn_protos(&utils::encode_alpn(&alpn))
+                .expect("invalid alpn");
+        }
+
+        Self {
+            conn,
+            sni,
+            insecure,
+            cc: builder.build(),
+        }
+    }
+
+    // use shared context
+    pub fn new_shared(conn: T, conf: TlsClientConf) -> Self {
+        type Store = Mutex<Vec<(TlsClientConf, SslConnector)>>;
+        lazy_static! {
+            static ref STORE: Store = Mutex::new(Vec::new());
+        }
+
+        // hold the lock
+        let mut store = STORE.lock().unwrap();
+
+        // simply increase ref count
+        if let Some((conf, cc)) = store.iter().find(|(x, _)| *x == conf) {
+            return Self {
+                conn,
+                sni: conf.sni.clone(),
+                insecure: conf.insecure,
+                cc: cc.clone(),
+            };
+        }
+
+        let this = Self::new(conn, conf.clone());
+
+        store.push((conf, this.cc.clone()));
+        store.shrink_to_fit();
+
+        this
+    }
+}
+
+impl<S, T> AsyncConnect<S> for TlsConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+{
+    type Stream = TlsClientStream<T::Stream>;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.conn.connect(stream, buf).await?;
+
+            let ssl = self
+                .cc
+                .configure()?
+                .verify_hostname(!self.insecure)
+                .into_ssl(&self.sni)?;
+
+            let mut stream = TlsClientStream::new(ssl, stream)?;
+            Pin::new(&mut stream).connect().await.map_err(ssl_error)?;
+            Ok(stream)
+        }
+    }
+}
+
+// ========== server ==========
+#[derive(Clone)]
+pub struct TlsAccept<T> {
+    lis: T,
+    ac: SslAcceptor,
+}
+
+impl<T> Display for TlsAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[tls]{}", self.lis) }
+}
+
+impl<T> Debug for TlsAccept<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsAccept").field("lis", &self.lis).finish()
+    }
+}
+
+impl<T> TlsAccept<T> {
+    pub fn new(lis: T, conf: TlsServerConf) -> Self {
+        let TlsServerConf {
+            crt,
+            key,
+            ocsp,
+            server_name,
+            ca,
+            self_signed,
+            client_ca,
+            crl,
+        } = conf;
+
+        let (cert, key) = if !crt.is_empty() && !key.is_empty() {
+            (
+                super::utils::read_certificates(&crt).expect("failed to read certificate"),
+                super::utils::read_private_key(&key).expect("failed to read private key"),
+            )
+        } else if !server_name.is_empty() {
+            super::utils::generate_cert_key(&server_name, &ca, &self_signed)
+        } else {
+            panic!("no certificate or private key supplied")
+        };
+
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+            .expect("failed to init openssl");
+
+        utils::set_cert_key(&mut builder, &cert, &key);
+
+        if !client_ca.is_empty() {
+            // also send them as hints
+            for x in utils::read_x509(&client_ca).expect("failed to read client ca") {
+                builder.add_client_ca(&x).expect("bad client ca");
+            }
+            builder
+                .set_cert_store(utils::read_roots(&client_ca).expect("failed to read client ca"));
+
+            let mode = SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            if !crl.is_empty() {
+                builder.set_verify_callback(mode, utils::new_crl_callback(crl));
+            } else {
+                builder.set_verify(mode);
+            }
+        }
+
+        if !ocsp.is_empty() {
+            let ocsp = super::utils::read_ocsp(&ocsp).expect("failed to read ocsp");
+            builder
+                .set_status_callback(move |ssl| ssl.set_ocsp_status(&ocsp).map(|_| true))
+                .expect("failed to set ocsp");
+        }
+
+        Self {
+            lis,
+            ac: builder.build(),
+        }
+    }
+
+    // use shared context
+    pub fn new_shared(lis: T, conf: TlsServerConf) -> Self {
+        type Store = Mutex<Vec<(TlsServerConf, SslAcceptor)>>;
+        lazy_static! {
+            static ref STORE: Store = Mutex::new(Vec::new());
+        }
+
+        // hold the lock
+        let mut store = STORE.lock().unwrap();
+
+        // simply increase ref count
+        if let Some((_, ac)) = store.iter().find(|(x, _)| *x == conf) {
+            return Self {
+                lis,
+                ac: ac.clone(),
+            };
+        }
+
+        let this = Self::new(lis, conf.clone());
+
+        store.push((conf, this.ac.clone()));
+        store.shrink_to_fit();
+
+        this
+    }
+}
+
+impl<S, T> AsyncAccept<S> for TlsAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+{
+    type Stream = TlsServerStream<T::Stream>;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let stream = self.lis.accept(stream, buf).await?;
+
+            let ssl = Ssl::new(self.ac.context())?;
+            let mut stream = TlsServerStream::new(ssl, stream)?;
+            Pin::new(&mut stream).accept().await.map_err(ssl_error)?;
+            Ok(stream)
+        }
+    }
+}
+
+// ========== key ==========
+// generate keys for self signed or issued certificates,
+// so that they are always accepted by this backend
+pub use key::KeyPair;
+
+mod key {
+    use std::io::{Error, ErrorKind, Result};
+    use std::fmt::{Debug, Formatter};
+
+    use ::openssl::bn::BigNumContext;
+    use ::openssl::ec::{EcGroup, EcKey, PointConversionForm};
+    use ::openssl::hash::MessageDigest;
+    use ::openssl::nid::Nid;
+    use ::openssl::pkey::{Id, PKey, Private};
+    use ::openssl::rsa::Rsa;
+    use ::openssl::sign::Signer;
+
+    use rcgen::{PublicKeyData, SigningKey, SignatureAlgorithm};
+
+    use super::super::KeyType;
+
+    pub struct KeyPair {
+        key: PKey<Private>,
+        alg: &'static SignatureAlgorithm,
+        digest: Option<MessageDigest>,
+        public: Vec<u8>,
+    }
+
+    impl Debug for KeyPair {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("KeyPair").field("alg", &self.alg).finish()
+        }
+    }
+
+    impl KeyPair {
+        pub fn generate(key_type: KeyType) -> Result<Self> {
+            let ec = |nid| -> Result<_> {
+                let group = EcGroup::from_curve_name(nid)?;
+                Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+            };
+
+            let key = match key_type {
+                KeyType::EcdsaP256 => ec(Nid::X9_62_PRIME256V1)?,
+                KeyType::EcdsaP384 => ec(Nid::SECP384R1)?,
+                KeyType::Ed25519 => PKey::generate_ed25519()?,
+                KeyType::Rsa => PKey::from_rsa(Rsa::generate(2048)?)?,
+            };
+
+            Self::from_pkey(key)
+        }
+
+        pub fn from_pem(pem: &str) -> Result<Self> {
+            Self::from_pkey(PKey::private_key_from_pem(pem.as_bytes())?)
+        }
+
+        fn from_pkey(key: PKey<Private>) -> Result<Self> {
+            let (alg, digest, public) = match key.id() {
+                Id::EC => {
+                    let ec = key.ec_key()?;
+                    let (alg, digest) = match ec.group().curve_name() {
+                        Some(Nid::X9_62_PRIME256V1) => {
+                            (&rcgen::PKCS_ECDSA_P256_SHA256, MessageDigest::sha256())
+                        }
+                        Some(Nid::SECP384R1) => {
+                            (&rcgen::PKCS_ECDSA_P384_SHA384, MessageDigest::sha384())
+                        }
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "unsupported curve")),
+                    };
+                    let mut ctx = BigNumContext::new()?;
+                    let public = ec.public_key().to_bytes(
+                        ec.group(),
+                        PointConversionForm::UNCOMPRESSED,
+                        &mut ctx,
+                    )?;
+                    (alg, Some(digest), public)
+                }
+                Id::ED25519 => (&rcgen::PKCS_ED25519, None, key.raw_public_key()?),
+                Id::RSA => (
+                    &rcgen::PKCS_RSA_SHA256,
+                    Some(MessageDigest::sha256()),
+                    key.rsa()?.public_key_to_der_pkcs1()?,
+                ),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "unsupported key type")),
+            };
+
+            Ok(Self {
+                key,
+                alg,
+                digest,
+                public,
+            })
+        }
+
+        pub fn serialize_pem(&self) -> String {
+            let pem = self
+                .key
+                .private_key_to_pem_pkcs8()
+                .expect("bad private key");
+            String::from_utf8(pem).expect("bad private key")
+        }
+
+        pub fn serialize_der(&self) -> Vec<u8> {
+            self.key.private_key_to_pkcs8().expect("bad private key")
+        }
+    }
+
+    impl PublicKeyData for KeyPair {
+        fn der_bytes(&self) -> &[u8] { &self.public }
+
+        fn algorithm(&self) -> &'static SignatureAlgorithm { self.alg }
+    }
+
+    impl SigningKey for KeyPair {
+        fn sign(&self, msg: &[u8]) -> std::result::Result<Vec<u8>, rcgen::Error> {
+            let signer = match self.digest {
+                Some(digest) => Signer::new(digest, &self.key),
+                None => Signer::new_without_digest(&self.key),
+            };
+            signer
+                .and_then(|mut x| x.sign_oneshot_to_vec(msg))
+                .map_err(|_| rcgen::Error::RemoteKeyError)
+        }
+    }
+}
+
+mod utils {
+    use std::io::Result;
+    use std::sync::Arc;
+    use ::openssl::ssl::SslContextBuilder;
+    use ::openssl::pkey::PKey;
+    use ::openssl::x509::{X509, X509Ref, X509Crl, CrlStatus, X509StoreContextRef};
+    use ::openssl::x509::store::{X509Store, X509StoreBuilder};
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    use super::super::crl::{self, Reload};
+
+    pub fn read_x509(path: &str) -> Result<Vec<X509>> {
+        super::super::utils::read_certificates(path)?
+            .iter()
+            .map(|x| Ok(X509::from_der(x)?))
+            .collect()
+    }
+
+    pub fn read_roots(path: &str) -> Result<X509Store> {
+        let mut store = X509StoreBuilder::new()?;
+        for cert in read_x509(path)? {
+            store.add_cert(cert)?;
+        }
+        Ok(store.build())
+    }
+
+    pub fn set_cert_key(
+        builder: &mut SslContextBuilder,
+        cert: &[CertificateDer<'static>],
+        key: &PrivateKeyDer<'static>,
+    ) {
+        let mut chain = cert
+            .iter()
+            .map(|x| X509::from_der(x).expect("bad certificate"));
+        let leaf = chain.next().expect("empty certificate chain");
+        builder.set_certificate(&leaf).expect("bad certificate");
+        for x in chain {
+            builder.add_extra_chain_cert(x).expect("bad certificate");
+        }
+
+        let key = PKey::private_key_from_der(key.secret_der()).expect("bad private key");
+        builder.set_private_key(&key).expect("bad private key");
+        builder.check_private_key().expect("bad certificate or key");
+    }
+
+    fn parse_crls(data: &[u8]) -> Result<Arc<Vec<X509Crl>>> {
+        let crls = crl::parse_crls(data)?
+            .iter()
+            .map(|x| Ok(X509Crl::from_der(x)?))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Arc::new(crls))
+    }
+
+    fn is_revoked(crls: &[X509Crl], cert: &X509Ref, issuer: &X509Ref) -> bool {
+        let Ok(key) = issuer.public_key() else {
+            return false;
+        };
+        let cert = cert.to_owned();
+
+        crls.iter()
+            .filter(|x| {
+                x.issuer_name()
+                    .try_cmp(issuer.subject_name())
+                    .is_ok_and(|x| x.is_eq())
+            })
+            .filter(|x| x.verify(&key).unwrap_or(false))
+            .any(|x| matches!(x.get_by_cert(&cert), CrlStatus::Revoked(_)))
+    }
+
+    // only check the leaf, see also rustls backend
+    pub fn new_crl_callback(
+        crl: String,
+    ) -> impl Fn(bool, &mut X509StoreContextRef) -> bool + Send + Sync + 'static {
+        let crls = Reload::new(crl, parse_crls).expect("failed to read crl");
+
+        move |ok, ctx| {
+            if !ok || ctx.error_depth() != 0 {
+                return ok;
+            }
+
+            let cert = ctx.current_cert();
+            let issuer = ctx.chain().and_then(|x| x.get(1));
+
+            match (cert, issuer) {
+                (Some(cert), Some(issuer)) => !is_revoked(&crls.refresh(), cert, issuer),
+                _ => ok,
+            }
+        }
+    }
+
+    // wire format: len + proto + len + proto ..
+    pub fn encode_alpn(alpn: &[Vec<u8>]) -> Vec<u8> {
+        let mut wire = Vec::with_capacity(alpn.iter().map(|x| x.len() + 1).sum());
+        for proto in alpn {
+            wire.push(proto.len() as u8);
+            wire.extend_from_slice(proto);
+        }
+        wire
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nop::{NopAccept, NopConnect};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // other tests may have installed one
+    #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+    fn install_rustls_provider() {
+        use tokio_rustls::rustls::crypto;
+
+        #[cfg(feature = "tls-awslc")]
+        let _ = crypto::aws_lc_rs::default_provider().install_default();
+
+        #[cfg(all(feature = "tls-ring", not(feature = "tls-awslc")))]
+        let _ = crypto::ring::default_provider().install_default();
+    }
+
+    fn client_conf() -> TlsClientConf {
+        TlsClientConf {
+            sni: String::from("localhost"),
+            alpn: vec![Vec::from("h2"), Vec::from("http/1.1")],
+            insecure: true,
+            early_data: false,
+            ca: String::new(),
+            crt: String::new(),
+            key: String::new(),
+            crl: String::new(),
+        }
+    }
+
+    fn server_conf() -> TlsServerConf {
+        TlsServerConf {
+            crt: String::new(),
+            key: String::new(),
+            ocsp: String::new(),
+            server_name: String::from("localhost"),
+            ca: String::new(),
+            self_signed: Default::default(),
+            client_ca: String::new(),
+            crl: String::new(),
+        }
+    }
+
+    async fn echo<C, A>(cc: C, ac: A)
+    where
+        C: AsyncConnect<tokio::io::DuplexStream>,
+        A: AsyncAccept<tokio::io::DuplexStream>,
+    {
+        let (client, server) = tokio::io::duplex(0x4000);
+
+        let server = async move {
+            let mut buf = vec![0u8; 0x2000];
+            let mut stream = ac.accept(server, &mut buf).await.unwrap();
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        };
+
+        let client = async move {
+            let mut buf = vec![0u8; 0x2000];
+            let mut stream = cc.connect(client, &mut buf).await.unwrap();
+            stream.write_all(b"kaminari").await.unwrap();
+            stream.flush().await.unwrap();
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"kaminari");
+        };
+
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn openssl_to_openssl() {
+        install_provider();
+        let cc = TlsConnect::new(NopConnect {}, client_conf());
+        let ac = TlsAccept::new(NopAccept {}, server_conf());
+        echo(cc, ac).await;
+    }
+
+    #[tokio::test]
+    async fn openssl_key_type() {
+        use super::super::{KeyType, SelfSignedConf};
+        install_provider();
+        for key_type in [KeyType::EcdsaP256, KeyType::EcdsaP384, KeyType::Ed25519] {
+            let conf = TlsServerConf {
+                self_signed: SelfSignedConf {
+                    key_type,
+                    ..Default::default()
+                },
+                ..server_conf()
+            };
+            let cc = TlsConnect::new(NopConnect {}, client_conf());
+            let ac = TlsAccept::new(NopAccept {}, conf);
+            echo(cc, ac).await;
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+    async fn openssl_to_rustls() {
+        install_rustls_provider();
+        let cc = TlsConnect::new(NopConnect {}, client_conf());
+        let ac = super::super::TlsAccept::new(NopAccept {}, server_conf());
+        echo(cc, ac).await;
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+    async fn openssl_crl() {
+        use super::super::test::{self as pki, Pki};
+        install_rustls_provider();
+        let pki = Pki::new("openssl-crl");
+        let crl = pki.revoke(&[1]);
+
+        let server = |serial| {
+            let conf = pki::server_conf(&pki, pki.issue("a.b.c", serial), "");
+            super::super::TlsAccept::new(NopAccept {}, conf)
+        };
+        let client = |serial| {
+            let conf = pki::client_conf(&pki, Some(pki.issue("x", serial)), "");
+            super::super::TlsConnect::new(NopConnect {}, conf)
+        };
+
+        // verify server
+        let cc = TlsConnect::new(NopConnect {}, pki::client_conf(&pki, None, &crl));
+        assert!(pki::echo(&cc, &server(1)).await.is_err());
+        pki::echo(&cc, &server(2)).await.unwrap();
+
+        // verify client
+        let ac = TlsAccept::new(
+            NopAccept {},
+            pki::server_conf(&pki, pki.issue("a.b.c", 3), &crl),
+        );
+        assert!(pki::echo(&client(1), &ac).await.is_err());
+        pki::echo(&client(2), &ac).await.unwrap();
+
+        // reload
+        pki.revoke(&[2]);
+        pki::echo(&cc, &server(1)).await.unwrap();
+        assert!(pki::echo(&cc, &server(2)).await.is_err());
+        pki::echo(&client(1), &ac).await.unwrap();
+        assert!(pki::echo(&client(2), &ac).await.is_err());
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "tls-ring", feature = "tls-awslc"))]
+    async fn rustls_to_openssl() {
+        install_rustls_provider();
+        let cc = super::super::TlsConnect::new(NopConnect {}, client_conf());
+        let ac = TlsAccept::new(NopAccept {}, server_conf());
+        echo(cc, ac).await;
+    }
+}
@@ -0,0 +1,520 @@
+//! HTTP/1.1 upgrade, without websocket framing.
+//!
+//! The client asks to switch `path` to `protocol` with an `Upgrade`
+//! request, or sends `CONNECT host` if `connect` is set. Once the server
+//! answers `101`, or `200` to `CONNECT`, both sides pass raw bytes. This
+//! saves the framing and masking of websocket, and still goes through
+//! proxies that pass upgrades.
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{Context, Poll};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use super::{IOStream, AsyncAccept, AsyncConnect};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeConf {
+    pub host: String,
+    pub path: String,
+    // token of the upgrade header
+    pub protocol: String,
+    // client: extra request headers, sent in order
+    pub headers: Vec<(String, String)>,
+    // server: required request headers
+    pub require_headers: Vec<(String, String)>,
+    // client: send CONNECT instead, path is not used
+    pub connect: bool,
+}
+
+impl Display for UpgradeConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "host: {}, path: {}, protocol: {}",
+            self.host, self.path, self.protocol
+        )?;
+
+        // values may carry credentials
+        if !self.headers.is_empty() {
+            let names: Vec<_> = self.headers.iter().map(|(k, _)| k.as_str()).collect();
+            write!(f, ", headers: {names:?}")?;
+        }
+
+        if !self.require_headers.is_empty() {
+            let names: Vec<_> = (self.require_headers.iter())
+                .map(|(k, _)| k.as_str())
+                .collect();
+            write!(f, ", require_headers: {names:?}")?;
+        }
+
+        write!(f, ", connect: {}", self.connect)
+    }
+}
+
+#[inline]
+fn invalid(msg: &'static str) -> Error { Error::new(ErrorKind::InvalidData, msg) }
+
+// ========== stream ==========
+/// Stream after the handshake, with the bytes read past the head.
+#[derive(Debug)]
+pub struct UpgradeStream<T> {
+    io: T,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<T> UpgradeStream<T> {
+    #[inline]
+    fn new(io: T, buf: &[u8]) -> Self {
+        Self {
+            io,
+            buf: buf.to_vec(),
+            pos: 0,
+        }
+    }
+
+    #[inline]
+    pub const fn get_ref(&self) -> &T { &self.io }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T { &mut self.io }
+}
+
+impl<T> AsyncRead for UpgradeStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if this.pos < this.buf.len() {
+            let n = (this.buf.len() - this.pos).min(buf.remaining());
+            buf.put_slice(&this.buf[this.pos..this.pos + n]);
+            this.pos += n;
+            if this.pos == this.buf.len() {
+                this.buf = Vec::new();
+                this.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for UpgradeStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool { self.io.is_write_vectored() }
+}
+
+// read until a complete head, return its length and the length read
+async fn recv_head<IO, F>(io: &mut IO, buf: &mut [u8], parse: F) -> Result<(usize, usize)>
+where
+    IO: AsyncRead + Unpin,
+    F: Fn(&[u8]) -> httparse::Result<usize>,
+{
+    let mut offset = 0;
+    loop {
+        if offset == buf.len() {
+            return Err(invalid("upgrade: head too large"));
+        }
+        let n = io.read(&mut buf[offset..]).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        offset += n;
+
+        match parse(&buf[..offset]) {
+            Ok(httparse::Status::Complete(n)) => return Ok((n, offset)),
+            Ok(httparse::Status::Partial) => continue,
+            Err(_) => return Err(invalid("upgrade: invalid http")),
+        }
+    }
+}
+
+// a comma separated header, e.g. connection: keep-alive, upgrade
+fn has_token(headers: &[httparse::Header<'_>], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .flat_map(|v| v.split(','))
+        .any(|x| x.trim().eq_ignore_ascii_case(token))
+}
+
+fn get_header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value)
+}
+
+// ========== client ==========
+#[derive(Debug, Clone)]
+pub struct HttpUpgradeConnect<T> {
+    conn: T,
+    conf: UpgradeConf,
+}
+
+impl<T> Display for HttpUpgradeConnect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[upgrade]{}", self.conn) }
+}
+
+impl<T> HttpUpgradeConnect<T> {
+    #[inline]
+    pub const fn new(conn: T, conf: UpgradeConf) -> Self { Self { conn, conf } }
+}
+
+impl<S, T> AsyncConnect<S> for HttpUpgradeConnect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+{
+    type Stream = UpgradeStream<T::Stream>;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.conn.connect(stream, buf).await?;
+            handshake(stream, buf, &self.conf).await
+        }
+    }
+}
+
+fn encode_request(conf: &UpgradeConf) -> String {
+    let mut req = match conf.connect {
+        true => format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", conf.host),
+        false => format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: {}\r\n",
+            conf.path, conf.host, conf.protocol
+        ),
+    };
+    for (k, v) in conf.headers.iter() {
+        req.push_str(&format!("{k}: {v}\r\n"));
+    }
+    req.push_str("\r\n");
+    req
+}
+
+async fn handshake<IO>(mut io: IO, buf: &mut [u8], conf: &UpgradeConf) -> Result<UpgradeStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    // send
+    io.write_all(encode_request(conf).as_bytes()).await?;
+
+    // recv
+    let parse = |buf: &[u8]| {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        httparse::Response::new(&mut headers).parse(buf)
+    };
+    let (n, offset) = recv_head(&mut io, buf, parse).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut response = httparse::Response::new(&mut headers);
+    let _ = response.parse(&buf[..n]);
+
+    // check, some proxies also answer CONNECT with 101
+    let code = response.code.unwrap_or_default();
+    let is_ok = match conf.connect {
+        true => code == 101 || (200..300).contains(&code),
+        false => code == 101 && has_token(response.headers, "upgrade", &conf.protocol),
+    };
+    if !is_ok {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "upgrade: unexpected status {} {}",
+                code,
+                response.reason.unwrap_or_default()
+            ),
+        ));
+    }
+
+    Ok(UpgradeStream::new(io, &buf[n..offset]))
+}
+
+// ========== server ==========
+#[derive(Debug, Clone)]
+pub struct HttpUpgradeAccept<T> {
+    lis: T,
+    conf: UpgradeConf,
+}
+
+impl<T> Display for HttpUpgradeAccept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[upgrade]{}", self.lis) }
+}
+
+impl<T> HttpUpgradeAccept<T> {
+    #[inline]
+    pub const fn new(lis: T, conf: UpgradeConf) -> Self { Self { lis, conf } }
+}
+
+impl<S, T> AsyncAccept<S> for HttpUpgradeAccept<T>
+where
+    S: IOStream,
+    T: AsyncAccept<S>,
+{
+    type Stream = UpgradeStream<T::Stream>;
+
+    type AcceptFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn accept<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::AcceptFut<'a> {
+        async move {
+            let stream = self.lis.accept(stream, buf).await?;
+            accept_handshake(stream, buf, &self.conf).await
+        }
+    }
+}
+
+// the status to answer with, 101 or 200 if accepted
+fn check(conf: &UpgradeConf, req: &httparse::Request<'_, '_>) -> u16 {
+    let host = get_header(req.headers, "host").unwrap_or_default();
+    let path = req.path.unwrap_or_default();
+
+    let is_connect = match req.method {
+        Some("GET") if host == conf.host.as_bytes() && path == conf.path => false,
+        Some("CONNECT") if path == conf.host => true,
+        _ => return 404,
+    };
+
+    let missing = (conf.require_headers.iter())
+        .any(|(k, v)| get_header(req.headers, k).is_none_or(|x| x != v.as_bytes()));
+    if missing {
+        return 403;
+    }
+
+    if is_connect {
+        return 200;
+    }
+
+    match has_token(req.headers, "connection", "upgrade")
+        && has_token(req.headers, "upgrade", &conf.protocol)
+    {
+        true => 101,
+        false => 426,
+    }
+}
+
+async fn accept_handshake<IO>(
+    mut io: IO,
+    buf: &mut [u8],
+    conf: &UpgradeConf,
+) -> Result<UpgradeStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    // recv
+    let parse = |buf: &[u8]| {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        httparse::Request::new(&mut headers).parse(buf)
+    };
+    let (n, offset) = recv_head(&mut io, buf, parse).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut request = httparse::Request::new(&mut headers);
+    let _ = request.parse(&buf[..n]);
+
+    // send
+    let response = match check(conf, &request) {
+        101 => format!(
+            "HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: {}\r\n\r\n",
+            conf.protocol
+        ),
+        200 => String::from("HTTP/1.1 200 Connection Established\r\n\r\n"),
+        status => {
+            let reason = match status {
+                403 => "Forbidden",
+                404 => "Not Found",
+                _ => "Upgrade Required",
+            };
+            let upgrade = match status {
+                426 => format!("upgrade: {}\r\n", conf.protocol),
+                _ => String::new(),
+            };
+            let response = format!(
+                "HTTP/1.1 {status} {reason}\r\n{upgrade}content-length: 0\r\nconnection: close\r\n\r\n"
+            );
+            io.write_all(response.as_bytes()).await?;
+            let _ = io.shutdown().await;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("upgrade: rejected with {status}"),
+            ));
+        }
+    };
+    io.write_all(response.as_bytes()).await?;
+
+    Ok(UpgradeStream::new(io, &buf[n..offset]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nop::{NopAccept, NopConnect};
+
+    fn conf(path: &str, headers: &[(&str, &str)], connect: bool) -> UpgradeConf {
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect();
+        UpgradeConf {
+            host: String::from("abc"),
+            path: String::from(path),
+            protocol: String::from("tun"),
+            headers: headers.clone(),
+            require_headers: headers,
+            connect,
+        }
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        for connect in [false, true] {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let conn =
+                HttpUpgradeConnect::new(NopConnect {}, conf("/up", &[("x-token", "t0k")], connect));
+            let lis =
+                HttpUpgradeAccept::new(NopAccept {}, conf("/up", &[("x-token", "t0k")], false));
+
+            let mut buf1 = vec![0u8; 0x1000];
+            let mut buf2 = vec![0u8; 0x1000];
+            let (c, s) = tokio::join!(
+                conn.connect(client, &mut buf1),
+                lis.accept(server, &mut buf2)
+            );
+            let (mut c, mut s) = (c.unwrap(), s.unwrap());
+
+            // no framing at all
+            c.write_all(b"ping").await.unwrap();
+            s.write_all(b"pong").await.unwrap();
+            let mut buf = [0u8; 4];
+            s.get_mut().read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            c.get_mut().read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        }
+    }
+
+    // sent along with the head
+    #[tokio::test]
+    async fn rest() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let lis = HttpUpgradeAccept::new(NopAccept {}, conf("/up", &[], false));
+
+        let req = "GET /up HTTP/1.1\r\nhost: abc\r\nconnection: keep-alive, Upgrade\r\nupgrade: TUN\r\n\r\nhello";
+        client.write_all(req.as_bytes()).await.unwrap();
+
+        let mut buf = vec![0u8; 0x1000];
+        let mut s = lis.accept(server, &mut buf).await.unwrap();
+        let mut recv = [0u8; 5];
+        s.read_exact(&mut recv).await.unwrap();
+        assert_eq!(&recv, b"hello");
+
+        let mut recv = vec![0u8; 0x100];
+        let n = client.read(&mut recv).await.unwrap();
+        let recv = std::str::from_utf8(&recv[..n]).unwrap();
+        assert_eq!(
+            recv,
+            "HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: tun\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject() {
+        let lis = HttpUpgradeAccept::new(NopAccept {}, conf("/up", &[("x-token", "t0k")], false));
+
+        macro_rules! n {
+            ( $( ($conf: expr, $status: expr), )+ ) => {
+                $({
+                    let (client, server) = tokio::io::duplex(0x1000);
+                    let conn = HttpUpgradeConnect::new(NopConnect {}, $conf);
+                    let mut buf1 = vec![0u8; 0x1000];
+                    let mut buf2 = vec![0u8; 0x1000];
+                    let (c, s) = tokio::join!(
+                        conn.connect(client, &mut buf1),
+                        lis.accept(server, &mut buf2)
+                    );
+
+                    let err = c.unwrap_err();
+                    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+                    assert_eq!(err.to_string(), format!("upgrade: unexpected status {}", $status));
+                    assert_eq!(s.unwrap_err().kind(), ErrorKind::PermissionDenied);
+                })+
+            };
+        }
+
+        n![
+            (conf("/down", &[("x-token", "t0k")], false), "404 Not Found"),
+            (
+                UpgradeConf {
+                    host: String::from("xyz"),
+                    ..conf("/up", &[("x-token", "t0k")], false)
+                },
+                "404 Not Found"
+            ),
+            (
+                UpgradeConf {
+                    host: String::from("xyz"),
+                    ..conf("/up", &[("x-token", "t0k")], true)
+                },
+                "404 Not Found"
+            ),
+            (conf("/up", &[], false), "403 Forbidden"),
+            (conf("/up", &[("x-token", "abc")], true), "403 Forbidden"),
+            (
+                UpgradeConf {
+                    protocol: String::from("websocket"),
+                    ..conf("/up", &[("x-token", "t0k")], false)
+                },
+                "426 Upgrade Required"
+            ),
+        ];
+    }
+}
@@ -1,29 +1,266 @@
 use std::io::Result;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use std::future::Future;
+use std::task::{Context, Poll};
 use std::marker::PhantomData;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
 use super::{IOStream, AsyncAccept, AsyncConnect};
 
 use lightws::endpoint::Endpoint;
 use lightws::role::{Server, Client, StandardClient, FixedMaskClient, ClientRole};
-use lightws::stream::{Guarded, Stream};
+use lightws::handshake::{HttpHeader, Request, Response, new_sec_key, derive_accept_key};
+use lightws::error::HandshakeError;
+
+pub mod policy;
+pub mod realip;
+pub mod route;
+pub mod fallback;
+pub mod early;
+pub mod deflate;
+pub mod stream;
+#[cfg(feature = "ws2")]
+pub mod http2;
+use fallback::Fallback;
+use early::EarlyConnect;
+use deflate::{DeflateConf, Params, EXTENSIONS};
+use policy::{Policy, RequestHead, Verdict};
+use realip::{Cidr, Forwarded};
+use route::{Route, Routed};
+use stream::{Role, DynamicClient, WsStream};
 
-pub(crate) type WsStream<T, R> = Stream<T, R, Guarded>;
-pub type WsServerStream<T> = WsStream<T, Server>;
+pub type WsServerStream<T> = WsStream<Accepted<Forwarded<T>>, Server>;
 pub type WsClientStream<T> = WsStream<T, Client>;
 pub type WsStandardClientStream<T> = WsStream<T, StandardClient>;
 pub type WsFixedClientStream<T> = WsStream<T, FixedMaskClient>;
+pub type WsDynamicClientStream<T> = WsStream<T, DynamicClient>;
+
+/// How a client masks the payload, see [`Mode`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MaskMode {
+    // an empty key, nothing to mask
+    #[default]
+    Skip,
+    // a new key for each frame
+    Standard,
+    // the same key for a connection
+    Fixed,
+}
+
+impl Display for MaskMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MaskMode::Skip => "skip",
+            MaskMode::Standard => "standard",
+            MaskMode::Fixed => "fixed",
+        };
+        write!(f, "{s}")
+    }
+}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct WsConf {
     pub host: String,
     pub path: String,
+    // bearer token in authorization
+    pub auth: String,
+    // client: max bytes of early data
+    // server: decode early data up to this size if not 0
+    pub early_data: usize,
+    // header of early data, empty to append it to the path
+    pub early_data_header: String,
+    // send pings if not zero
+    pub ping_interval: Duration,
+    // wait for pongs, zero for ping_interval
+    pub ping_timeout: Duration,
+    // wait for the peer's close on shutdown if not zero
+    pub close_timeout: Duration,
+    // permessage-deflate if some
+    pub deflate: Option<DeflateConf>,
+    // max payload of a frame to send, no limit if 0
+    pub max_frame_size: usize,
+    // reject larger frames if not 0
+    pub max_recv_frame_size: usize,
+    // gather smaller writes into one frame if not 0
+    pub coalesce: usize,
+    // flush gathered writes after the delay
+    pub coalesce_delay: Duration,
+    // base64 in text frames
+    pub text: bool,
+    pub client: WsClientConf,
+    pub server: WsServerConf,
+}
+
+/// Options only used by [`WsConnect`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WsClientConf {
+    // extra request headers, sent in order
+    pub headers: Vec<(String, String)>,
+    // used by the dynamic mode
+    pub mask: MaskMode,
+}
+
+/// Options only used by [`WsAccept`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WsServerConf {
+    // required request headers
+    pub headers: Vec<(String, String)>,
+    // allowed origins
+    pub origins: Vec<String>,
+    // headers carrying the client address, in order
+    pub real_ip: Vec<String>,
+    // proxies allowed to set these headers
+    pub trusted: Vec<Cidr>,
+    // matched in order if host or path mismatch
+    pub routes: Vec<Route>,
+    // non-tunnel requests
+    pub fallback: Fallback,
 }
 
 impl Display for WsConf {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "host: {}, path: {}", self.host, self.path)
+        write!(f, "host: {}, path: {}", self.host, self.path)?;
+
+        // values may carry credentials
+        let names =
+            |x: &[(String, String)]| -> Vec<String> { x.iter().map(|(k, _)| k.clone()).collect() };
+        if !self.client.headers.is_empty() {
+            write!(f, ", headers: {:?}", names(&self.client.headers))?;
+        }
+
+        if !self.server.headers.is_empty() {
+            write!(f, ", require_headers: {:?}", names(&self.server.headers))?;
+        }
+
+        if !self.auth.is_empty() {
+            write!(f, ", auth: ***")?;
+        }
+
+        let server = &self.server;
+        if !server.origins.is_empty() {
+            write!(f, ", origins: {:?}", server.origins)?;
+        }
+
+        if !server.real_ip.is_empty() {
+            let trusted: Vec<_> = server.trusted.iter().map(|x| x.to_string()).collect();
+            write!(f, ", real_ip: {:?}, trusted: {:?}", server.real_ip, trusted)?;
+        }
+
+        if !server.routes.is_empty() {
+            let routes: Vec<_> = server.routes.iter().map(|x| x.to_string()).collect();
+            write!(f, ", routes: {routes:?}")?;
+        }
+
+        if server.fallback != Fallback::Close {
+            write!(f, ", fallback: {}", server.fallback)?;
+        }
+
+        if self.early_data != 0 {
+            match self.early_data_header.as_str() {
+                "" => write!(f, ", early_data: {} (path)", self.early_data)?,
+                x => write!(f, ", early_data: {} ({})", self.early_data, x)?,
+            }
+        }
+
+        if !self.ping_interval.is_zero() {
+            let timeout = match self.ping_timeout {
+                Duration::ZERO => self.ping_interval,
+                x => x,
+            };
+            write!(
+                f,
+                ", ping: {:?}, timeout: {:?}",
+                self.ping_interval, timeout
+            )?;
+        }
+
+        if !self.close_timeout.is_zero() {
+            write!(f, ", close_timeout: {:?}", self.close_timeout)?;
+        }
+
+        if let Some(deflate) = &self.deflate {
+            write!(f, ", deflate: [{deflate}]")?;
+        }
+
+        if self.max_frame_size != 0 || self.max_recv_frame_size != 0 {
+            write!(
+                f,
+                ", frame_size: {}/{}",
+                self.max_frame_size, self.max_recv_frame_size
+            )?;
+        }
+
+        if self.text {
+            write!(f, ", text")?;
+        }
+
+        if self.coalesce != 0 {
+            write!(
+                f,
+                ", coalesce: {} ({:?})",
+                self.coalesce, self.coalesce_delay
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// settings of established streams
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StreamConf {
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    close_timeout: Duration,
+    max_send: usize,
+    max_recv: usize,
+    coalesce: usize,
+    coalesce_delay: Duration,
+    text: bool,
+    mask: MaskMode,
+    // agreed in the handshake
+    deflate: Option<Params>,
+}
+
+impl From<&WsConf> for StreamConf {
+    fn from(conf: &WsConf) -> Self {
+        Self {
+            ping_interval: conf.ping_interval,
+            ping_timeout: conf.ping_timeout,
+            close_timeout: conf.close_timeout,
+            max_send: conf.max_frame_size,
+            max_recv: conf.max_recv_frame_size,
+            coalesce: conf.coalesce,
+            coalesce_delay: conf.coalesce_delay,
+            text: conf.text,
+            mask: conf.client.mask,
+            deflate: None,
+        }
+    }
+}
+
+impl StreamConf {
+    #[inline]
+    pub(crate) const fn with_deflate(mut self, deflate: Option<Params>) -> Self {
+        self.deflate = deflate;
+        self
+    }
+
+    pub(crate) fn build<IO, R: Role>(self, io: IO) -> WsStream<IO, R> {
+        let stream = WsStream::new(io, R::with_mode(self.mask))
+            .with_keepalive(self.ping_interval, self.ping_timeout)
+            .with_close_timeout(self.close_timeout)
+            .with_frame_size(self.max_send, self.max_recv)
+            .with_coalesce(self.coalesce, self.coalesce_delay)
+            .with_text(self.text);
+        match self.deflate {
+            Some(params) => stream.with_deflate(params),
+            None => stream,
+        }
     }
 }
 
@@ -38,7 +275,7 @@ pub struct Standard {}
 pub struct Fixed {}
 
 pub trait Mode {
-    type ClientType: ClientRole;
+    type ClientType: ClientRole + Role;
 }
 
 impl Mode for Simple {
@@ -53,6 +290,14 @@ impl Mode for Fixed {
     type ClientType = FixedMaskClient;
 }
 
+/// Mask by [`WsClientConf::mask`].
+#[derive(Debug, Clone, Copy)]
+pub struct Dynamic {}
+
+impl Mode for Dynamic {
+    type ClientType = DynamicClient;
+}
+
 #[derive(Debug, Clone)]
 pub struct WsConnect<T, M = Simple> {
     conn: T,
@@ -94,15 +339,34 @@ impl<T> WsConnect<T> {
             _marker: PhantomData,
         }
     }
+
+    #[inline]
+    pub fn dynamic(self) -> WsConnect<T, Dynamic> {
+        WsConnect {
+            conn: self.conn,
+            conf: self.conf,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, M> WsConnect<T, M> {
+    /// Send the first write within the upgrade request, see [`early`].
+    #[inline]
+    pub const fn early(self) -> EarlyConnect<T, M> { EarlyConnect::new(self) }
+
+    /// Carry each tunnel in a stream of a shared h2 connection, see [`http2`].
+    #[cfg(feature = "ws2")]
+    #[inline]
+    pub fn http2(self) -> http2::Ws2Connect<T> { http2::Ws2Connect::new(self.conn, self.conf) }
 }
 
 impl<S, T, M: Mode> AsyncConnect<S> for WsConnect<T, M>
 where
     S: IOStream,
     T: AsyncConnect<S>,
-    M::ClientType: Unpin + 'static,
 {
-    type Stream = Stream<T::Stream, M::ClientType, Guarded>;
+    type Stream = WsStream<T::Stream, M::ClientType>;
 
     type ConnectFut<'a>
         = impl Future<Output = Result<Self::Stream>> + 'a
@@ -112,24 +376,191 @@ where
     fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
         async move {
             let stream = self.conn.connect(stream, buf).await?;
-            let stream = Endpoint::<_, M::ClientType>::connect_async(
-                stream,
-                buf,
-                &self.conf.host,
-                &self.conf.path,
-            )
-            .await?
-            .guard();
-            Ok(stream)
+            handshake::<_, M::ClientType>(stream, buf, &self.conf).await
         }
     }
 }
 
+// encode the upgrade request with extra headers, return the length
+fn encode_request(conf: &WsConf, sec_key: &[u8], buf: &mut [u8]) -> Result<usize> {
+    let mut headers: Vec<_> = (conf.client.headers.iter())
+        .map(|(k, v)| HttpHeader::new(k.as_bytes(), v.as_bytes()))
+        .collect();
+    let auth = format!("Bearer {}", conf.auth);
+    if !conf.auth.is_empty() {
+        headers.push(HttpHeader::new(b"Authorization", auth.as_bytes()));
+    }
+    let offer = conf.deflate.as_ref().map(deflate::offer);
+    if let Some(offer) = &offer {
+        headers.push(HttpHeader::new(EXTENSIONS.as_bytes(), offer.as_bytes()));
+    }
+    let request = Request::new_with_headers(
+        conf.path.as_bytes(),
+        conf.host.as_bytes(),
+        sec_key,
+        &mut headers,
+    );
+    Ok(request.encode(buf)?)
+}
+
+// same as Endpoint::connect_async, with extra headers
+async fn handshake<IO, R>(mut io: IO, buf: &mut [u8], conf: &WsConf) -> Result<WsStream<IO, R>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: ClientRole + Role,
+{
+    let sec_key = new_sec_key();
+    let sec_accept = derive_accept_key(&sec_key);
+
+    // send
+    let n = encode_request(conf, &sec_key, buf)?;
+    io.write_all(&buf[..n]).await?;
+
+    // recv
+    let mut other_headers = HttpHeader::new_storage();
+    let mut response = Response::new_storage(&mut other_headers);
+    // this is safe since we do not modify response.
+    let _ = unsafe { Endpoint::<_, R>::recv_response_async(&mut io, buf, &mut response) }.await?;
+
+    // check
+    if response.sec_accept != sec_accept {
+        return Err(HandshakeError::SecWebSocketAccept.into());
+    }
+    let deflate = confirm_deflate(conf, response.other_headers)?;
+
+    Ok(StreamConf::from(conf).with_deflate(deflate).build(io))
+}
+
+// none if not offered or declined
+fn confirm_deflate(conf: &WsConf, headers: &[HttpHeader]) -> Result<Option<Params>> {
+    match &conf.deflate {
+        Some(deflate) => deflate::confirm(deflate, headers),
+        None => Ok(None),
+    }
+}
+
 // ========== server ==========
-#[derive(Debug, Clone)]
+// boxed, rarely set
+#[derive(Debug, Default)]
+struct Extra {
+    route: Option<usize>,
+    // a frame carrying early data, read first
+    early: Vec<u8>,
+}
+
+/// Accepted stream with the matched route, and early data from the request.
+#[derive(Debug)]
+pub struct Accepted<T> {
+    io: T,
+    extra: Option<Box<Extra>>,
+}
+
+impl<T> Accepted<T> {
+    #[inline]
+    pub const fn new(io: T) -> Self { Self { io, extra: None } }
+
+    #[inline]
+    pub(crate) fn with_route(mut self, route: Option<usize>) -> Self {
+        if route.is_some() {
+            self.extra.get_or_insert_default().route = route;
+        }
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_early(mut self, data: &[u8], text: bool) -> Self {
+        if !data.is_empty() {
+            self.extra.get_or_insert_default().early = early::frame(data, text);
+        }
+        self
+    }
+
+    #[inline]
+    pub const fn get_ref(&self) -> &T { &self.io }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T { &mut self.io }
+
+    #[inline]
+    pub fn into_inner(self) -> T { self.io }
+}
+
+impl<T> Routed for Accepted<T> {
+    #[inline]
+    fn route(&self) -> Option<usize> { self.extra.as_ref()?.route }
+}
+
+impl<T> AsyncRead for Accepted<T>
+where
+    T: AsyncRead + Unpin,
+{
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let Some(extra) = this.extra.as_mut().filter(|x| !x.early.is_empty()) {
+            let n = extra.early.len().min(buf.remaining());
+            buf.put_slice(&extra.early[..n]);
+            extra.early.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for Accepted<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool { self.io.is_write_vectored() }
+}
+
+#[derive(Clone)]
 pub struct WsAccept<T> {
     lis: T,
     conf: WsConf,
+    policy: Option<Arc<dyn Policy>>,
+}
+
+impl<T> Debug for WsAccept<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsAccept")
+            .field("lis", &self.lis)
+            .field("conf", &self.conf)
+            .field("policy", &self.policy.is_some())
+            .finish()
+    }
 }
 
 impl<T> Display for WsAccept<T>
@@ -141,13 +572,27 @@ where
 
 impl<T> WsAccept<T> {
     #[inline]
-    pub const fn new(lis: T, conf: WsConf) -> Self { Self { lis, conf } }
+    pub const fn new(lis: T, conf: WsConf) -> Self {
+        Self {
+            lis,
+            conf,
+            policy: None,
+        }
+    }
+
+    /// Run a custom check on each request, after the rules from [`WsConf`].
+    #[inline]
+    pub fn with_policy<P: Policy + 'static>(mut self, policy: P) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
 }
 
 impl<S, T> AsyncAccept<S> for WsAccept<T>
 where
     S: IOStream,
     T: AsyncAccept<S>,
+    T::Stream: Send,
 {
     type Stream = WsServerStream<T::Stream>;
 
@@ -160,12 +605,457 @@ where
         async move {
             let stream = self.lis.accept(stream, buf).await?;
 
-            let stream =
-                Endpoint::<_, Server>::accept_async(stream, buf, &self.conf.host, &self.conf.path)
-                    .await?
-                    .guard();
+            accept_handshake(stream, buf, &self.conf, self.policy.as_deref()).await
+        }
+    }
+}
+
+// read until a complete http request, return the length
+async fn recv_request<IO>(io: &mut IO, buf: &mut [u8]) -> Result<usize>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut offset = 0;
+    while offset < buf.len() {
+        let n = io.read(&mut buf[offset..]).await?;
+        if n == 0 {
+            return Err(HandshakeError::NotEnoughData.into());
+        }
+        offset += n;
 
-            Ok(stream)
+        // decode again later, borrowing buf
+        let mut other_headers = HttpHeader::new_storage();
+        let mut request = Request::new_storage(&mut other_headers);
+        match request.decode(&buf[..offset]) {
+            Err(HandshakeError::NotEnoughData) => continue,
+            _ => return Ok(offset),
         }
     }
+
+    Err(HandshakeError::NotEnoughCapacity.into())
+}
+
+// route, path, and the segment of early data
+type Target<'a> = (Option<usize>, &'a [u8], Option<&'a [u8]>);
+
+// route by the whole path, or by the path without early data
+fn find_route<'a>(conf: &WsConf, host: &[u8], path: &'a [u8]) -> Result<Target<'a>> {
+    if let Some(route) = route::find(conf, host, path) {
+        return Ok((route, path, None));
+    }
+
+    let split = early::split_path(conf, path);
+    let routed =
+        split.and_then(|(base, segment)| Some((route::find(conf, host, base)?, base, segment)));
+    match routed {
+        Some((route, base, segment)) => Ok((route, base, Some(segment))),
+        None if host != conf.host.as_bytes() => Err(HandshakeError::Manual("host mismatch").into()),
+        None => Err(HandshakeError::Manual("path mismatch").into()),
+    }
+}
+
+// same as Endpoint::accept_async, with request inspection
+async fn accept_handshake<IO>(
+    mut io: IO,
+    buf: &mut [u8],
+    conf: &WsConf,
+    policy: Option<&dyn Policy>,
+) -> Result<WsServerStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // recv
+    let n = recv_request(&mut io, buf).await?;
+    let mut other_headers = HttpHeader::new_storage();
+    let mut request = Request::new_storage(&mut other_headers);
+
+    // check, early data only once it is a websocket request with a route
+    let decoded = request.decode(&buf[..n]);
+    let found = match decoded {
+        // not http at all
+        Err(e @ HandshakeError::Httparse(_)) => return Err(e.into()),
+        // not websocket
+        Err(e) => Err(e.into()),
+        Ok(_) => find_route(conf, request.host, request.path).and_then(|(route, path, segment)| {
+            let early = early::find(conf, segment, request.other_headers)?;
+            Ok((route, path, early))
+        }),
+    };
+
+    let (route, path, early) = match found {
+        Ok(x) => x,
+        Err(e) => return Err(conf.server.fallback.run(io, &buf[..n], e).await),
+    };
+
+    let head = RequestHead {
+        host: request.host,
+        path,
+        headers: request.other_headers,
+    };
+    let verdict = match policy::check(conf, &head) {
+        Verdict::Accept => policy.map_or(Verdict::Accept, |p| p.check(&head)),
+        x => x,
+    };
+
+    if verdict != Verdict::Accept {
+        io.write_all(verdict.response()).await?;
+        let _ = io.shutdown().await;
+        return Err(verdict.into_error());
+    }
+
+    let client_ip = realip::candidate(&head, &conf.server.real_ip, &conf.server.trusted);
+
+    // send
+    let sec_accept = derive_accept_key(request.sec_key);
+    let (early, protocol) = early.map_or((Vec::new(), None), |x| (x.data, x.protocol));
+    let deflate = (conf.deflate.as_ref()).and_then(|x| deflate::accept(x, request.other_headers));
+    let mut headers: Vec<_> = protocol
+        .iter()
+        .map(|x| HttpHeader::new(b"Sec-WebSocket-Protocol", x))
+        .collect();
+    if let Some((_, response)) = &deflate {
+        headers.push(HttpHeader::new(EXTENSIONS.as_bytes(), response.as_bytes()));
+    }
+    let response = Response::new_with_headers(&sec_accept, &mut headers);
+    let _ = Endpoint::<_, Server>::send_response_async(&mut io, buf, &response).await?;
+
+    let io = Forwarded::with_candidate(io, client_ip, &conf.server.trusted);
+    let io = Accepted::new(io)
+        .with_route(route)
+        .with_early(&early, conf.text);
+    let deflate = deflate.map(|(params, _)| params);
+    Ok(StreamConf::from(conf).with_deflate(deflate).build(io))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::ErrorKind;
+    use crate::fallback::is_handled;
+    use crate::nop::{NopAccept, NopConnect};
+
+    #[tokio::test]
+    async fn connect_with_headers() {
+        let (client, mut server) = tokio::io::duplex(0x1000);
+
+        let conf = WsConf {
+            host: String::from("abc"),
+            path: String::from("/chat"),
+            client: WsClientConf {
+                headers: vec![
+                    (String::from("User-Agent"), String::from("Mozilla/5.0")),
+                    (String::from("X-Token"), String::from("a=b; c=d")),
+                ],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let conn = WsConnect::new(NopConnect {}, conf);
+
+        let server = async move {
+            let mut buf = vec![0u8; 0x1000];
+            let mut other_headers = HttpHeader::new_storage();
+            let mut request = Request::new_storage(&mut other_headers);
+            let _ = unsafe {
+                Endpoint::<_, Server>::recv_request_async(&mut server, &mut buf, &mut request)
+            }
+            .await
+            .unwrap();
+
+            assert_eq!(request.host, b"abc");
+            assert_eq!(request.path, b"/chat");
+            assert_eq!(
+                request.other_headers[0],
+                HttpHeader::new(b"User-Agent", b"Mozilla/5.0")
+            );
+            assert_eq!(
+                request.other_headers[1],
+                HttpHeader::new(b"X-Token", b"a=b; c=d")
+            );
+
+            let sec_accept = derive_accept_key(request.sec_key);
+            let response = Response::new(&sec_accept);
+            let mut buf = vec![0u8; 0x1000];
+            Endpoint::<_, Server>::send_response_async(&mut server, &mut buf, &response)
+                .await
+                .unwrap();
+        };
+
+        let client = async {
+            let mut buf = vec![0u8; 0x1000];
+            conn.connect(client, &mut buf).await.unwrap();
+        };
+
+        tokio::join!(server, client);
+    }
+
+    fn ws_conf(auth: &str) -> WsConf {
+        WsConf {
+            host: String::from("abc"),
+            path: String::from("/chat"),
+            auth: String::from(auth),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_with_auth() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = WsConnect::new(NopConnect {}, ws_conf("t0k"));
+        let lis = WsAccept::new(NopAccept {}, ws_conf("t0k"));
+
+        let mut buf1 = vec![0u8; 0x1000];
+        let mut buf2 = vec![0u8; 0x1000];
+        let (c, s) = tokio::join!(
+            conn.connect(client, &mut buf1),
+            lis.accept(server, &mut buf2)
+        );
+        assert!(c.is_ok() && s.is_ok());
+    }
+
+    #[tokio::test]
+    async fn accept_with_real_ip() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = WsConnect::new(
+            NopConnect {},
+            WsConf {
+                client: WsClientConf {
+                    headers: vec![
+                        (
+                            String::from("X-Forwarded-For"),
+                            String::from("1.1.1.1, 10.0.0.2"),
+                        ),
+                        (String::from("X-Forwarded-Port"), String::from("5000, 443")),
+                    ],
+                    ..Default::default()
+                },
+                ..ws_conf("")
+            },
+        );
+        let lis = WsAccept::new(
+            NopAccept {},
+            WsConf {
+                server: WsServerConf {
+                    real_ip: vec![String::from("x-forwarded-for")],
+                    trusted: vec!["10.0.0.0/8".parse().unwrap()],
+                    ..Default::default()
+                },
+                ..ws_conf("")
+            },
+        );
+
+        let mut buf1 = vec![0u8; 0x1000];
+        let mut buf2 = vec![0u8; 0x1000];
+        let (c, s) = tokio::join!(
+            conn.connect(client, &mut buf1),
+            lis.accept(server, &mut buf2)
+        );
+        assert!(c.is_ok());
+
+        let s = s.unwrap();
+        let ip = |x: &str| x.parse::<std::net::IpAddr>().unwrap();
+        let forwarded = s.as_ref().get_ref();
+        assert_eq!(forwarded.forwarded_ip(), Some(ip("1.1.1.1")));
+        assert_eq!(forwarded.client_ip(ip("10.0.0.1")), ip("1.1.1.1"));
+        assert_eq!(forwarded.client_ip(ip("2.2.2.2")), ip("2.2.2.2"));
+        assert_eq!(forwarded.forwarded_addr(), "1.1.1.1:5000".parse().ok());
+    }
+
+    #[tokio::test]
+    async fn accept_with_route() {
+        let route = |host: &str, path: &str, target: &str| Route {
+            host: String::from(host),
+            path: String::from(path),
+            target: String::from(target),
+        };
+        let lis = WsAccept::new(
+            NopAccept {},
+            WsConf {
+                server: WsServerConf {
+                    routes: vec![
+                        route("", "/ssh", "127.0.0.1:22"),
+                        route("*.abc", "/*", "127.0.0.1:80"),
+                    ],
+                    ..Default::default()
+                },
+                ..ws_conf("")
+            },
+        );
+
+        macro_rules! y {
+            ( $( ($host: expr, $path: expr, $route: expr); )+ ) => {
+                $(
+                    let (client, server) = tokio::io::duplex(0x1000);
+                    let conn = WsConnect::new(
+                        NopConnect {},
+                        WsConf {
+                            host: String::from($host),
+                            path: String::from($path),
+                            ..ws_conf("")
+                        },
+                    );
+
+                    let mut buf1 = vec![0u8; 0x1000];
+                    let mut buf2 = vec![0u8; 0x1000];
+                    let (c, s) = tokio::join!(
+                        conn.connect(client, &mut buf1),
+                        lis.accept(server, &mut buf2)
+                    );
+                    let route: Option<Option<usize>> = $route;
+                    match route {
+                        Some(route) => {
+                            assert!(c.is_ok());
+                            assert_eq!(s.unwrap().route(), route);
+                        }
+                        None => assert!(s.is_err()),
+                    }
+                )+
+            }
+        }
+
+        y![
+            ("abc", "/chat", Some(None));
+            ("abc", "/ssh", Some(Some(0)));
+            ("x.abc", "/ssh", Some(Some(0)));
+            ("x.abc", "/chat", Some(Some(1)));
+            ("abc", "/rdp", None);
+            ("abd", "/chat", None);
+        ];
+    }
+
+    // send raw bytes, return the raw response
+    async fn raw_send(mut io: tokio::io::DuplexStream, req: &[u8]) -> Vec<u8> {
+        io.write_all(req).await.unwrap();
+        let mut resp = Vec::new();
+        io.read_to_end(&mut resp).await.unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn fallback_static() {
+        let lis = WsAccept::new(
+            NopAccept {},
+            WsConf {
+                server: WsServerConf {
+                    fallback: Fallback::Static {
+                        status: 404,
+                        body: b"oops".to_vec(),
+                    },
+                    ..Default::default()
+                },
+                ..ws_conf("")
+            },
+        );
+
+        macro_rules! y {
+            ( $( ($req: expr, $ok: expr); )+ ) => {
+                $(
+                    let (client, server) = tokio::io::duplex(0x1000);
+                    let mut buf = vec![0u8; 0x1000];
+                    let (resp, res) = tokio::join!(
+                        raw_send(client, $req),
+                        lis.accept(server, &mut buf)
+                    );
+                    assert_eq!(is_handled(&res.unwrap_err()), $ok);
+                    if $ok {
+                        assert_eq!(resp, b"HTTP/1.1 404 Not Found\r\ncontent-type: text/html\r\ncontent-length: 4\r\nconnection: close\r\n\r\noops");
+                    } else {
+                        assert!(resp.is_empty());
+                    }
+                )+
+            }
+        }
+
+        y![
+            (b"GET / HTTP/1.1\r\nHost: abc\r\n\r\n", true);
+            (b"POST /chat HTTP/1.1\r\nHost: abc\r\nContent-Length: 0\r\n\r\n", true);
+            (b"GET /chat HTTP/1.1\r\nHost: abd\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", true);
+            (b"\x16\x03\x01\x00\x05hello\r\n\r\n", false);
+        ];
+    }
+
+    #[tokio::test]
+    async fn fallback_proxy() {
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let lis = WsAccept::new(
+            NopAccept {},
+            WsConf {
+                server: WsServerConf {
+                    fallback: Fallback::Proxy(backend.local_addr().unwrap()),
+                    ..Default::default()
+                },
+                ..ws_conf("")
+            },
+        );
+
+        let req = b"GET /index.html HTTP/1.1\r\nHost: abc\r\n\r\n";
+        let backend = async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buf = vec![0u8; req.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, req);
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        };
+
+        let (client, server) = tokio::io::duplex(0x1000);
+        let mut buf = vec![0u8; 0x1000];
+        let (resp, res, _) =
+            tokio::join!(raw_send(client, req), lis.accept(server, &mut buf), backend);
+        assert!(is_handled(&res.unwrap_err()));
+        assert_eq!(resp, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    // send a request, return the raw response
+    async fn raw_request(mut io: tokio::io::DuplexStream, headers: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0u8; 0x1000];
+        let sec_key = new_sec_key();
+        let mut headers: Vec<_> = headers.iter().map(|(k, v)| HttpHeader::new(k, v)).collect();
+        let request = Request::new_with_headers(b"/chat", b"abc", &sec_key, &mut headers);
+        let n = request.encode(&mut buf).unwrap();
+        io.write_all(&buf[..n]).await.unwrap();
+
+        let mut resp = Vec::new();
+        io.read_to_end(&mut resp).await.unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn reject_with_status() {
+        macro_rules! n {
+            ( $( ($lis: expr, [ $( ($k: expr, $v: expr) ),* ], $status: expr); )+ ) => {
+                $(
+                    let (client, server) = tokio::io::duplex(0x1000);
+                    let lis = $lis;
+                    let mut buf = vec![0u8; 0x1000];
+                    let headers: &[(&[u8], &[u8])] = &[ $( (&$k[..], &$v[..]) ),* ];
+                    let (resp, res) = tokio::join!(
+                        raw_request(client, headers),
+                        lis.accept(server, &mut buf)
+                    );
+                    assert_eq!(res.unwrap_err().kind(), ErrorKind::PermissionDenied);
+                    assert!(resp.starts_with($status), "{}", String::from_utf8_lossy(&resp));
+                )+
+            }
+        }
+
+        let origin = WsConf {
+            server: WsServerConf {
+                origins: vec![String::from("https://abc")],
+                ..Default::default()
+            },
+            ..ws_conf("")
+        };
+        let hook = |req: &RequestHead<'_>| match req.header("x-id") {
+            Some(b"1") => Verdict::Accept,
+            _ => Verdict::Forbidden,
+        };
+
+        n![
+            (WsAccept::new(NopAccept {}, ws_conf("t0k")), [], b"HTTP/1.1 401");
+            (WsAccept::new(NopAccept {}, ws_conf("t0k")), [(b"Authorization", b"Bearer t1k")], b"HTTP/1.1 401");
+            (WsAccept::new(NopAccept {}, origin.clone()), [], b"HTTP/1.1 403");
+            (WsAccept::new(NopAccept {}, origin.clone()), [(b"Origin", b"https://abd")], b"HTTP/1.1 403");
+            (WsAccept::new(NopAccept {}, ws_conf("")).with_policy(hook), [(b"X-Id", b"2")], b"HTTP/1.1 403");
+        ];
+    }
 }
@@ -0,0 +1,535 @@
+//! Per-message compression, see [RFC 7692](https://www.rfc-editor.org/rfc/rfc7692).
+//!
+//! The client offers `permessage-deflate` with the parameters from
+//! [`DeflateConf`], the server accepts the first offer it can satisfy,
+//! otherwise the stream goes on uncompressed.
+//!
+//! Each write is a message. Those shorter than
+//! [`DeflateConf::threshold`] are sent uncompressed.
+//!
+//! Window bits limit the LZ77 window of the compressor on each side,
+//! from 9 to 15. Without context takeover, the compressor is reset
+//! after each message, which saves memory and costs ratio.
+//!
+//! A received message may not inflate beyond [`MAX_MESSAGE`].
+
+use std::io::{Error, ErrorKind, Result};
+use std::fmt::{Display, Formatter};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use lightws::handshake::HttpHeader;
+use lightws::error::HandshakeError;
+
+/// Name of the extension header.
+pub const EXTENSIONS: &str = "Sec-WebSocket-Extensions";
+
+const NAME: &str = "permessage-deflate";
+
+/// Max inflated size of a received message.
+pub const MAX_MESSAGE: usize = 0x40_0000;
+
+// removed from the end of each message
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeflateConf {
+    // window of each side, 9 to 15
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+    // reset the compressor of each side after each message
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    // smaller messages are sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for DeflateConf {
+    fn default() -> Self {
+        Self {
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            threshold: 64,
+        }
+    }
+}
+
+impl Display for DeflateConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "window_bits: {}/{}, threshold: {}",
+            self.server_max_window_bits, self.client_max_window_bits, self.threshold
+        )?;
+
+        match (
+            self.server_no_context_takeover,
+            self.client_no_context_takeover,
+        ) {
+            (true, true) => write!(f, ", no_context_takeover: server/client"),
+            (true, false) => write!(f, ", no_context_takeover: server"),
+            (false, true) => write!(f, ", no_context_takeover: client"),
+            (false, false) => Ok(()),
+        }
+    }
+}
+
+/// Agreed parameters of our compressor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub window_bits: u8,
+    pub no_context_takeover: bool,
+    pub threshold: usize,
+}
+
+// parameters of an offer or a response
+#[derive(Debug, Default)]
+struct Offer {
+    server_max_window_bits: Option<u8>,
+    // may come without a value
+    client_max_window_bits: Option<Option<u8>>,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+// none on unknown, duplicate or invalid parameters
+fn parse_offer<'a>(params: impl Iterator<Item = &'a str>) -> Option<Offer> {
+    let mut offer = Offer::default();
+
+    let bits = |v: Option<&str>| -> Option<u8> {
+        let v = v?.trim().trim_matches('"');
+        match v.parse() {
+            Ok(x @ 8..=15) if !v.starts_with('0') => Some(x),
+            _ => None,
+        }
+    };
+
+    for param in params {
+        let (k, v) = match param.split_once('=') {
+            Some((k, v)) => (k.trim(), Some(v)),
+            None => (param.trim(), None),
+        };
+        match k {
+            "server_max_window_bits" if offer.server_max_window_bits.is_none() => {
+                offer.server_max_window_bits = Some(bits(v)?);
+            }
+            "client_max_window_bits" if offer.client_max_window_bits.is_none() => {
+                offer.client_max_window_bits = match v {
+                    Some(_) => Some(Some(bits(v)?)),
+                    None => Some(None),
+                };
+            }
+            "server_no_context_takeover" if v.is_none() && !offer.server_no_context_takeover => {
+                offer.server_no_context_takeover = true;
+            }
+            "client_no_context_takeover" if v.is_none() && !offer.client_no_context_takeover => {
+                offer.client_no_context_takeover = true;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(offer)
+}
+
+// name and parameters of each extension
+fn extensions<'a>(
+    headers: &'a [HttpHeader<'a>],
+) -> impl Iterator<Item = (&'a str, std::str::Split<'a, char>)> {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(EXTENSIONS.as_bytes()))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .flat_map(|v| v.split(','))
+        .map(|ext| {
+            let mut params = ext.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            (name, params)
+        })
+}
+
+// ========== client ==========
+/// Offer from the client.
+pub(crate) fn offer(conf: &DeflateConf) -> String {
+    let mut offer = String::from(NAME);
+
+    match conf.client_max_window_bits {
+        15 => offer.push_str("; client_max_window_bits"),
+        x => offer.push_str(&format!("; client_max_window_bits={x}")),
+    }
+
+    if conf.server_max_window_bits < 15 {
+        offer.push_str(&format!(
+            "; server_max_window_bits={}",
+            conf.server_max_window_bits
+        ));
+    }
+
+    if conf.server_no_context_takeover {
+        offer.push_str("; server_no_context_takeover");
+    }
+
+    if conf.client_no_context_takeover {
+        offer.push_str("; client_no_context_takeover");
+    }
+
+    offer
+}
+
+/// Check the response from the server, none if declined.
+pub(crate) fn confirm(conf: &DeflateConf, headers: &[HttpHeader]) -> Result<Option<Params>> {
+    let invalid = || HandshakeError::Manual("invalid extension response").into();
+
+    let mut response = None;
+    for (name, params) in extensions(headers) {
+        if name != NAME || response.is_some() {
+            return Err(invalid());
+        }
+        response = Some(parse_offer(params).ok_or_else(invalid)?);
+    }
+
+    let Some(response) = response else {
+        return Ok(None);
+    };
+
+    // larger than offered
+    if response
+        .server_max_window_bits
+        .is_some_and(|x| x > conf.server_max_window_bits)
+    {
+        return Err(invalid());
+    }
+
+    let window_bits = match response.client_max_window_bits {
+        Some(Some(x)) => x.min(conf.client_max_window_bits),
+        Some(None) => return Err(invalid()),
+        None => conf.client_max_window_bits,
+    };
+
+    // not supported by zlib
+    if window_bits < 9 {
+        return Err(invalid());
+    }
+
+    Ok(Some(Params {
+        window_bits,
+        no_context_takeover: conf.client_no_context_takeover || response.client_no_context_takeover,
+        threshold: conf.threshold,
+    }))
+}
+
+// ========== server ==========
+/// Accept an offer from the client, with the response.
+pub(crate) fn accept(conf: &DeflateConf, headers: &[HttpHeader]) -> Option<(Params, String)> {
+    extensions(headers)
+        .filter(|(name, _)| *name == NAME)
+        .find_map(|(_, params)| accept_offer(conf, parse_offer(params)?))
+}
+
+fn accept_offer(conf: &DeflateConf, offer: Offer) -> Option<(Params, String)> {
+    let window_bits = offer
+        .server_max_window_bits
+        .map_or(conf.server_max_window_bits, |x| {
+            x.min(conf.server_max_window_bits)
+        });
+
+    // not supported by zlib
+    if window_bits < 9 {
+        return None;
+    }
+
+    let no_context_takeover = conf.server_no_context_takeover || offer.server_no_context_takeover;
+
+    let mut response = String::from(NAME);
+
+    if no_context_takeover {
+        response.push_str("; server_no_context_takeover");
+    }
+
+    if conf.client_no_context_takeover {
+        response.push_str("; client_no_context_takeover");
+    }
+
+    if offer.server_max_window_bits.is_some() || window_bits < 15 {
+        response.push_str(&format!("; server_max_window_bits={window_bits}"));
+    }
+
+    // only if the client supports it
+    if let Some(x) = offer.client_max_window_bits {
+        let bits = x.unwrap_or(15).min(conf.client_max_window_bits);
+        if bits < 15 {
+            response.push_str(&format!("; client_max_window_bits={bits}"));
+        }
+    }
+
+    let params = Params {
+        window_bits,
+        no_context_takeover,
+        threshold: conf.threshold,
+    };
+    Some((params, response))
+}
+
+// ========== codec ==========
+/// Compressor and decompressor of a stream.
+pub(crate) struct Codec {
+    compress: Compress,
+    decompress: Decompress,
+    no_context_takeover: bool,
+    threshold: usize,
+    // inflated size of the message being read
+    inflated: usize,
+}
+
+impl std::fmt::Debug for Codec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Codec")
+            .field("no_context_takeover", &self.no_context_takeover)
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+impl Codec {
+    pub(crate) fn new(params: Params) -> Self {
+        Self {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.window_bits,
+            ),
+            // the peer may use any window
+            decompress: Decompress::new_with_window_bits(false, 15),
+            no_context_takeover: params.no_context_takeover,
+            threshold: params.threshold,
+            inflated: 0,
+        }
+    }
+
+    #[inline]
+    pub(crate) const fn should_compress(&self, len: usize) -> bool { len >= self.threshold }
+
+    /// Append a compressed message to `out`.
+    pub(crate) fn compress(&mut self, mut input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        loop {
+            out.reserve(input.len() / 2 + 64);
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(input, out, FlushCompress::Sync)
+                .map_err(Error::other)?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+
+            // flushed if there is room left
+            if input.is_empty() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        debug_assert!(out.ends_with(&TAIL));
+        out.truncate(out.len() - TAIL.len());
+
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(())
+    }
+
+    /// Append decompressed payload to `out`.
+    pub(crate) fn decompress(&mut self, mut input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        loop {
+            out.reserve(input.len() * 2 + 64);
+            let before = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(input, out, FlushDecompress::Sync)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let n = (self.decompress.total_in() - before.0) as usize;
+            input = &input[n..];
+            self.inflated += (self.decompress.total_out() - before.1) as usize;
+
+            // a final block would stop the decompressor for good
+            if status == Status::StreamEnd {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "ws: final deflate block",
+                ));
+            }
+            if self.inflated > MAX_MESSAGE {
+                return Err(Error::new(ErrorKind::InvalidData, "ws: message too large"));
+            }
+
+            if input.is_empty() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// End of a compressed message.
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        self.decompress(&TAIL, out)?;
+        self.inflated = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ext(value: &str) -> [HttpHeader<'_>; 1] {
+        [HttpHeader::new(EXTENSIONS.as_bytes(), value.as_bytes())]
+    }
+
+    #[test]
+    fn negotiate() {
+        macro_rules! y {
+            ( $( ($server: expr, $client: expr, $response: expr, $server_bits: expr, $client_bits: expr); )+ ) => {
+                $(
+                    let (server, client): (DeflateConf, DeflateConf) = ($server, $client);
+                    let request = offer(&client);
+                    let (params, response) = accept(&server, &ext(&request)).unwrap();
+                    assert_eq!(response, $response);
+                    assert_eq!(params.window_bits, $server_bits);
+
+                    let params = confirm(&client, &ext(&response)).unwrap().unwrap();
+                    assert_eq!(params.window_bits, $client_bits);
+                )+
+            }
+        }
+
+        let conf = DeflateConf::default;
+        y![
+            (conf(), conf(), "permessage-deflate", 15, 15);
+            (
+                DeflateConf { server_max_window_bits: 10, ..conf() },
+                conf(),
+                "permessage-deflate; server_max_window_bits=10",
+                10, 15
+            );
+            (
+                conf(),
+                DeflateConf { server_max_window_bits: 12, client_max_window_bits: 11, ..conf() },
+                "permessage-deflate; server_max_window_bits=12; client_max_window_bits=11",
+                12, 11
+            );
+            (
+                DeflateConf { client_max_window_bits: 9, client_no_context_takeover: true, ..conf() },
+                DeflateConf { server_no_context_takeover: true, ..conf() },
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover; client_max_window_bits=9",
+                15, 9
+            );
+        ];
+    }
+
+    #[test]
+    fn negotiate_fallback() {
+        let conf = DeflateConf::default();
+
+        // the first acceptable offer
+        let headers = ext("x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=8, permessage-deflate");
+        let (_, response) = accept(&conf, &headers).unwrap();
+        assert_eq!(response, "permessage-deflate");
+
+        // nothing acceptable
+        for offer in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; client_max_window_bits=16",
+            "permessage-deflate; unknown",
+        ] {
+            assert!(accept(&conf, &ext(offer)).is_none());
+        }
+
+        // declined, or an invalid response
+        assert_eq!(confirm(&conf, &[]).unwrap(), None);
+        for response in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate, permessage-deflate",
+            "permessage-deflate; client_max_window_bits",
+            "permessage-deflate; client_max_window_bits=8",
+        ] {
+            assert!(confirm(&conf, &ext(response)).is_err());
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let params = |no_context_takeover| Params {
+            window_bits: 9,
+            no_context_takeover,
+            threshold: 0,
+        };
+
+        for x in [false, true] {
+            let mut a = Codec::new(params(x));
+            let mut b = Codec::new(params(x));
+
+            for i in 0..4 {
+                let data = format!("{i} hello hello hello hello").repeat(100);
+                let mut compressed = Vec::new();
+                a.compress(data.as_bytes(), &mut compressed).unwrap();
+                assert!(compressed.len() < data.len() / 10);
+
+                // split anywhere
+                let mut out = Vec::new();
+                let (x, y) = compressed.split_at(compressed.len() / 3);
+                b.decompress(x, &mut out).unwrap();
+                b.decompress(y, &mut out).unwrap();
+                b.finish(&mut out).unwrap();
+                assert_eq!(out, data.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn codec_reject() {
+        let params = Params {
+            window_bits: 15,
+            no_context_takeover: false,
+            threshold: 0,
+        };
+
+        // bytes behind a final block
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut data = Vec::with_capacity(0x100);
+        compress
+            .compress_vec(b"hello", &mut data, FlushCompress::Finish)
+            .unwrap();
+        data.extend_from_slice(b"trailing");
+        let err = Codec::new(params)
+            .decompress(&data, &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // inflated too much, even split into frames
+        let mut a = Codec::new(params);
+        let mut data = Vec::new();
+        a.compress(&vec![0u8; MAX_MESSAGE + 1], &mut data).unwrap();
+        assert!(data.len() < 0x4000);
+
+        let mut b = Codec::new(params);
+        let mut out = Vec::new();
+        let err = data
+            .chunks(0x100)
+            .try_for_each(|x| b.decompress(x, &mut out))
+            .and_then(|_| b.finish(&mut out))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "ws: message too large");
+        assert!(out.len() < MAX_MESSAGE + 0x10000);
+
+        // the limit is for each message
+        let mut a = Codec::new(params);
+        let mut b = Codec::new(params);
+        for _ in 0..2 {
+            let mut data = Vec::new();
+            a.compress(&vec![0u8; MAX_MESSAGE], &mut data).unwrap();
+            let mut out = Vec::new();
+            b.decompress(&data, &mut out).unwrap();
+            b.finish(&mut out).unwrap();
+            assert_eq!(out.len(), MAX_MESSAGE);
+        }
+    }
+}
@@ -0,0 +1,740 @@
+//! Early data, compatible with the `ed` option of v2ray.
+//!
+//! [`EarlyConnect`] waits for the first write, and sends up to
+//! [`WsConf::early_data`](super::WsConf::early_data) bytes of it
+//! within the upgrade request, encoded as base64url, in the header
+//! [`WsConf::early_data_header`](super::WsConf::early_data_header),
+//! or as the last segment of the path if the header is empty,
+//! e.g. `/chat/<data>`.
+//!
+//! [`WsAccept`](super::WsAccept) decodes them once the request matches
+//! a route, and delivers them as the first read, which saves a round
+//! trip. A path is only split if it has no route as a whole. Early data
+//! larger than its own limit, or invalid in the path, fails the request.
+//!
+//! Since the request is not sent until the first write,
+//! this does not work with protocols where the server speaks first.
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{ready, Context, Poll, Waker};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+
+use lightws::role::ClientRole;
+use lightws::handshake::{HttpHeader, Response, new_sec_key, derive_accept_key};
+use lightws::frame::{FrameHead, Fin, OpCode, Mask, PayloadLen};
+use lightws::error::HandshakeError;
+
+use super::deflate::{self, DeflateConf, Params};
+use super::{WsConf, WsConnect, WsStream, StreamConf, Role, Mode, encode_request};
+use crate::{IOStream, AsyncConnect};
+
+const SEC_WEBSOCKET_PROTOCOL: &[u8] = b"sec-websocket-protocol";
+
+// ========== codec ==========
+#[inline]
+pub fn encode(data: &[u8]) -> String { URL_SAFE_NO_PAD.encode(data) }
+
+/// Decode base64url, with or without padding.
+pub fn decode(s: &[u8]) -> Option<Vec<u8>> {
+    let s = s.trim_ascii();
+    let s = s.strip_suffix(b"==").or(s.strip_suffix(b"=")).unwrap_or(s);
+    URL_SAFE_NO_PAD.decode(s).ok()
+}
+
+/// Early data found in a request.
+#[derive(Debug)]
+pub(crate) struct Found {
+    pub data: Vec<u8>,
+    // echoed in the response
+    pub protocol: Option<Vec<u8>>,
+}
+
+/// Split early data off the path, into the path of [`WsConf`]
+/// and the segment after it.
+pub(crate) fn split_path<'a>(conf: &WsConf, path: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    if conf.early_data == 0 || !conf.early_data_header.is_empty() {
+        return None;
+    }
+
+    let rest = path.strip_prefix(conf.path.as_bytes())?;
+    let base = &path[..conf.path.len()];
+    let rest = match conf.path.ends_with('/') {
+        true => rest,
+        false => rest.strip_prefix(b"/")?,
+    };
+    (!rest.is_empty()).then_some((base, rest))
+}
+
+/// Early data from the segment split off the path, or the header.
+pub(crate) fn find(
+    conf: &WsConf,
+    segment: Option<&[u8]>,
+    headers: &[HttpHeader<'_>],
+) -> Result<Option<Found>> {
+    if let Some(x) = segment {
+        let data = decode(x).filter(|x| !x.is_empty());
+        let data =
+            data.ok_or_else(|| Error::new(ErrorKind::InvalidData, "ws: invalid early data"))?;
+        return check(
+            conf,
+            Found {
+                data,
+                protocol: None,
+            },
+        );
+    }
+
+    if conf.early_data == 0 || conf.early_data_header.is_empty() {
+        return Ok(None);
+    }
+
+    let name = conf.early_data_header.as_bytes();
+    let Some(header) = headers.iter().find(|h| h.name.eq_ignore_ascii_case(name)) else {
+        return Ok(None);
+    };
+    let Some(data) = decode(header.value).filter(|x| !x.is_empty()) else {
+        return Ok(None);
+    };
+    let protocol = name
+        .eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL)
+        .then(|| header.value.to_vec());
+    check(conf, Found { data, protocol })
+}
+
+// the client may send more than accepted here
+fn check(conf: &WsConf, found: Found) -> Result<Option<Found>> {
+    match found.data.len() > conf.early_data {
+        true => Err(Error::new(
+            ErrorKind::InvalidData,
+            "ws: early data too large",
+        )),
+        false => Ok(Some(found)),
+    }
+}
+
+/// Wrap data in a frame, which is read by the server as usual.
+/// A text frame carries base64.
+pub(crate) fn frame(data: &[u8], text: bool) -> Vec<u8> {
+    let encoded;
+    let (opcode, data) = match text {
+        true => {
+            encoded = STANDARD.encode(data);
+            (OpCode::Text, encoded.as_bytes())
+        }
+        false => (OpCode::Binary, data),
+    };
+
+    let mut head = [0u8; 14];
+    let head_len = FrameHead::new(
+        Fin::Y,
+        opcode,
+        Mask::None,
+        PayloadLen::from_num(data.len() as u64),
+    )
+    .encode(&mut head)
+    .unwrap();
+
+    let mut buf = Vec::with_capacity(head_len + data.len());
+    buf.extend_from_slice(&head[..head_len]);
+    buf.extend_from_slice(data);
+    buf
+}
+
+// ========== client ==========
+#[derive(Debug, Clone)]
+pub struct EarlyConnect<T, M> {
+    inner: WsConnect<T, M>,
+}
+
+impl<T, M> Display for EarlyConnect<T, M>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "{}", self.inner) }
+}
+
+impl<T, M> EarlyConnect<T, M> {
+    #[inline]
+    pub(crate) const fn new(inner: WsConnect<T, M>) -> Self { Self { inner } }
+}
+
+impl<S, T, M: Mode> AsyncConnect<S> for EarlyConnect<T, M>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+{
+    type Stream = EarlyStream<T::Stream, M::ClientType>;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let conf = &self.inner.conf;
+            let stream = self.inner.conn.connect(stream, buf).await?;
+
+            let sec_key = new_sec_key();
+            let n = encode_request(conf, &sec_key, buf)?;
+
+            // a segment after the path, or a header before the last CRLF
+            let (at, header) = match conf.early_data_header.as_str() {
+                "" => (b"GET ".len() + conf.path.len(), None),
+                x => (n - 2, Some(String::from(x))),
+            };
+            let sep = if conf.path.ends_with('/') { "" } else { "/" };
+
+            let idle = Idle {
+                io: stream,
+                request: buf[..n].to_vec(),
+                at,
+                header,
+                sep,
+                limit: conf.early_data,
+                sec_accept: derive_accept_key(&sec_key),
+                conf: StreamConf::from(conf),
+                deflate: conf.deflate.clone(),
+                waker: None,
+            };
+            Ok(EarlyStream {
+                state: State::Idle(Box::new(idle)),
+            })
+        }
+    }
+}
+
+// ========== stream ==========
+struct Idle<IO> {
+    io: IO,
+    request: Vec<u8>,
+    // where to put early data
+    at: usize,
+    header: Option<String>,
+    // before early data in the path
+    sep: &'static str,
+    limit: usize,
+    sec_accept: [u8; 28],
+    conf: StreamConf,
+    // offered extension
+    deflate: Option<DeflateConf>,
+    // a read before the first write
+    waker: Option<Waker>,
+}
+
+struct Handshake<IO> {
+    io: IO,
+    // request, then response
+    buf: Vec<u8>,
+    // sent or received
+    pos: usize,
+    sending: bool,
+    sec_accept: [u8; 28],
+    conf: StreamConf,
+    deflate: Option<DeflateConf>,
+    wakers: Vec<Waker>,
+}
+
+enum State<IO, R> {
+    Idle(Box<Idle<IO>>),
+    Handshake(Box<Handshake<IO>>),
+    Ready(WsStream<Rewind<IO>, R>),
+    Closed,
+}
+
+/// Client stream that sends the upgrade request on the first write.
+pub struct EarlyStream<IO, R> {
+    state: State<IO, R>,
+}
+
+impl<IO> Idle<IO> {
+    fn start(self, data: &[u8]) -> Handshake<IO> {
+        let Idle {
+            io,
+            mut request,
+            at,
+            header,
+            sep,
+            sec_accept,
+            conf,
+            deflate,
+            waker,
+            ..
+        } = self;
+
+        if !data.is_empty() {
+            let data = encode(data);
+            let insert = match header {
+                Some(name) => format!("{name}: {data}\r\n"),
+                None => format!("{sep}{data}"),
+            };
+            request.splice(at..at, insert.into_bytes());
+        }
+
+        // the reader drives the handshake as well
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Handshake {
+            io,
+            buf: request,
+            pos: 0,
+            sending: true,
+            sec_accept,
+            conf,
+            deflate,
+            wakers: Vec::new(),
+        }
+    }
+}
+
+impl<IO> Handshake<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    // return the length of the response, and the agreed extension
+    fn poll_run(&mut self, cx: &mut Context<'_>) -> Poll<Result<(usize, Option<Params>)>> {
+        if self.sending {
+            while self.pos < self.buf.len() {
+                let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.buf[self.pos..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+            }
+            ready!(Pin::new(&mut self.io).poll_flush(cx))?;
+
+            self.sending = false;
+            self.pos = 0;
+            self.buf.clear();
+            self.buf.resize(0x1000, 0);
+        }
+
+        loop {
+            if self.pos == self.buf.len() {
+                return Poll::Ready(Err(HandshakeError::NotEnoughCapacity.into()));
+            }
+
+            let mut buf = ReadBuf::new(&mut self.buf[self.pos..]);
+            ready!(Pin::new(&mut self.io).poll_read(cx, &mut buf))?;
+            let n = buf.filled().len();
+            if n == 0 {
+                return Poll::Ready(Err(HandshakeError::NotEnoughData.into()));
+            }
+            self.pos += n;
+
+            let mut other_headers = HttpHeader::new_storage();
+            let mut response = Response::new_storage(&mut other_headers);
+            match response.decode(&self.buf[..self.pos]) {
+                Err(HandshakeError::NotEnoughData) => continue,
+                Err(e) => return Poll::Ready(Err(e.into())),
+                Ok(_) if response.sec_accept != self.sec_accept => {
+                    return Poll::Ready(Err(HandshakeError::SecWebSocketAccept.into()))
+                }
+                Ok(n) => {
+                    let params = match &self.deflate {
+                        Some(conf) => deflate::confirm(conf, response.other_headers)?,
+                        None => None,
+                    };
+                    return Poll::Ready(Ok((n, params)));
+                }
+            }
+        }
+    }
+
+    fn park(&mut self, cx: &mut Context<'_>) {
+        if !self.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            self.wakers.push(cx.waker().clone());
+        }
+    }
+}
+
+impl<IO, R> EarlyStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: ClientRole + Role,
+{
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let res = match &mut self.state {
+            State::Ready(_) => return Poll::Ready(Ok(())),
+            State::Closed => return Poll::Ready(Err(ErrorKind::NotConnected.into())),
+            State::Idle(idle) => {
+                idle.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            State::Handshake(hs) => match hs.poll_run(cx) {
+                Poll::Ready(x) => x,
+                Poll::Pending => {
+                    hs.park(cx);
+                    return Poll::Pending;
+                }
+            },
+        };
+
+        let State::Handshake(hs) = std::mem::replace(&mut self.state, State::Closed) else {
+            unreachable!()
+        };
+        let Handshake {
+            io,
+            mut buf,
+            pos,
+            conf,
+            wakers,
+            ..
+        } = *hs;
+        wakers.into_iter().for_each(Waker::wake);
+
+        // the server may reply without waiting
+        let (n, params) = res?;
+        buf.truncate(pos);
+        buf.drain(..n);
+        let io = Rewind { io, buf };
+        self.state = State::Ready(conf.with_deflate(params).build(io));
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn ready_mut(&mut self) -> &mut WsStream<Rewind<IO>, R> {
+        match &mut self.state {
+            State::Ready(stream) => stream,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<IO, R> AsyncRead for EarlyStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: ClientRole + Role,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        Pin::new(this.ready_mut()).poll_read(cx, buf)
+    }
+}
+
+impl<IO, R> AsyncWrite for EarlyStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: ClientRole + Role,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+
+        if let State::Idle(idle) = &this.state {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let n = buf.len().min(idle.limit);
+            let State::Idle(idle) = std::mem::replace(&mut this.state, State::Closed) else {
+                unreachable!()
+            };
+            this.state = State::Handshake(Box::new(idle.start(&buf[..n])));
+
+            // data is taken, report errors later
+            if n != 0 {
+                if let Poll::Ready(Err(e)) = this.poll_handshake(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                return Poll::Ready(Ok(n));
+            }
+        }
+
+        ready!(this.poll_handshake(cx))?;
+        Pin::new(this.ready_mut()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let State::Idle(_) = this.state {
+            return Poll::Ready(Ok(()));
+        }
+
+        ready!(this.poll_handshake(cx))?;
+        Pin::new(this.ready_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        // nothing was sent
+        if let State::Idle(idle) = &mut this.state {
+            return Pin::new(&mut idle.io).poll_shutdown(cx);
+        }
+
+        ready!(this.poll_handshake(cx))?;
+        Pin::new(this.ready_mut()).poll_shutdown(cx)
+    }
+}
+
+// replay bytes received after the response
+struct Rewind<IO> {
+    io: IO,
+    buf: Vec<u8>,
+}
+
+impl<IO> AsyncRead for Rewind<IO>
+where
+    IO: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if this.buf.is_empty() {
+            return Pin::new(&mut this.io).poll_read(cx, buf);
+        }
+
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf[..n]);
+        this.buf.drain(..n);
+        if this.buf.is_empty() {
+            this.buf = Vec::new();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO> AsyncWrite for Rewind<IO>
+where
+    IO: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ws::{WsAccept, WsServerConf, Simple};
+    use crate::ws::fallback::Fallback;
+    use crate::ws::route::{Route, Routed};
+    use crate::fallback::is_handled;
+    use crate::AsyncAccept;
+    use crate::nop::{NopAccept, NopConnect};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn codec() {
+        macro_rules! y {
+            ( $( ($data: expr, $s: expr); )+ ) => {
+                $(
+                    assert_eq!(encode($data), $s);
+                    assert_eq!(decode($s.as_bytes()).unwrap(), $data);
+                )+
+            }
+        }
+
+        y![
+            (b"", "");
+            (b"\xfb\xff", "-_8");
+            (b"hello", "aGVsbG8");
+        ];
+
+        assert_eq!(decode(b"aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode(b"aGVsbA==").unwrap(), b"hell");
+        assert!(decode(b"a+b/").is_none());
+        assert!(decode(b"\xff").is_none());
+    }
+
+    fn ws_conf(early_data: usize, header: &str) -> WsConf {
+        WsConf {
+            host: String::from("abc"),
+            path: String::from("/chat"),
+            early_data,
+            early_data_header: String::from(header),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn early_data() {
+        macro_rules! y {
+            ( $( ($client: expr, $server: expr, $first: expr); )+ ) => {
+                $(
+                    let (client, server) = tokio::io::duplex(0x1000);
+                    let conn = WsConnect::<_, Simple>::new(NopConnect {}, $client).early();
+                    let lis = WsAccept::new(NopAccept {}, $server);
+
+                    let mut buf1 = vec![0u8; 0x1000];
+                    let mut buf2 = vec![0u8; 0x1000];
+                    let client = async {
+                        let mut stream = conn.connect(client, &mut buf1).await.unwrap();
+                        stream.write_all(b"hello world").await.unwrap();
+                        let mut buf = [0u8; 0x100];
+                        let n = stream.read(&mut buf).await.unwrap();
+                        assert_eq!(&buf[..n], b"bye");
+                    };
+                    let server = async {
+                        let mut stream = lis.accept(server, &mut buf2).await.unwrap();
+                        // replied without another read
+                        stream.write_all(b"bye").await.unwrap();
+
+                        let mut buf = [0u8; 0x100];
+                        let mut n = stream.read(&mut buf).await.unwrap();
+                        assert_eq!(&buf[..n], $first);
+                        while n < 11 {
+                            n += stream.read(&mut buf[n..]).await.unwrap();
+                        }
+                        assert_eq!(&buf[..n], b"hello world");
+                    };
+                    tokio::join!(client, server);
+                )+
+            }
+        }
+
+        let header = "Sec-WebSocket-Protocol";
+        let text = |conf| WsConf { text: true, ..conf };
+        y![
+            (ws_conf(2048, header), ws_conf(2048, header), b"hello world");
+            (ws_conf(5, header), ws_conf(2048, header), b"hello");
+            (ws_conf(2048, "X-Ed"), ws_conf(11, "x-ed"), b"hello world");
+            (ws_conf(2048, ""), ws_conf(2048, ""), b"hello world");
+            (ws_conf(0, header), ws_conf(2048, header), b"hello world");
+            (text(ws_conf(2048, header)), text(ws_conf(2048, header)), b"hello world");
+        ];
+    }
+
+    #[tokio::test]
+    async fn early_data_too_large() {
+        for header in ["Sec-WebSocket-Protocol", ""] {
+            let (client, server) = tokio::io::duplex(0x1000);
+            let conn = WsConnect::<_, Simple>::new(NopConnect {}, ws_conf(2048, header)).early();
+            let lis = WsAccept::new(NopAccept {}, ws_conf(10, header));
+
+            let mut buf1 = vec![0u8; 0x1000];
+            let mut buf2 = vec![0u8; 0x1000];
+            let client = async {
+                let mut stream = conn.connect(client, &mut buf1).await.unwrap();
+                stream.write_all(b"hello world").await.unwrap();
+                let mut buf = [0u8; 0x100];
+                assert!(stream.read(&mut buf).await.is_err());
+            };
+            let server = async {
+                let err = lis.accept(server, &mut buf2).await.unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidData);
+            };
+            tokio::join!(client, server);
+        }
+    }
+
+    #[tokio::test]
+    async fn early_data_request() {
+        let (client, mut server) = tokio::io::duplex(0x1000);
+        let conn = WsConnect::<_, Simple>::new(NopConnect {}, ws_conf(2048, "")).early();
+
+        let mut buf = vec![0u8; 0x1000];
+        let mut stream = conn.connect(client, &mut buf).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        drop(stream);
+
+        let mut req = Vec::new();
+        server.read_to_end(&mut req).await.unwrap();
+        assert!(req.starts_with(b"GET /chat/aGVsbG8 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn early_data_checked_last() {
+        let fallback = |conf| WsConf {
+            server: WsServerConf {
+                fallback: Fallback::Static {
+                    status: 404,
+                    body: Vec::new(),
+                },
+                routes: vec![Route {
+                    host: String::new(),
+                    path: String::from("/chat/r*"),
+                    target: String::new(),
+                }],
+                ..Default::default()
+            },
+            ..conf
+        };
+
+        // route, and early data of the first read
+        macro_rules! y {
+            ( $( ($conf: expr, $path: expr, $header: expr, $route: expr, $first: expr); )+ ) => {
+                $({
+                    let lis = WsAccept::new(NopAccept {}, fallback($conf));
+                    let (mut client, server) = tokio::io::duplex(0x1000);
+                    let req = format!(
+                        "GET {} HTTP/1.1\r\nHost: abc\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+                        $path, $header
+                    );
+                    client.write_all(req.as_bytes()).await.unwrap();
+                    let mut buf = vec![0u8; 0x1000];
+                    let res = lis.accept(server, &mut buf).await;
+                    let route: Option<Option<usize>> = $route;
+                    match route {
+                        Some(route) => {
+                            let mut stream = res.unwrap();
+                            assert_eq!(stream.route(), route);
+                            let first: &[u8] = $first;
+                            if !first.is_empty() {
+                                let n = stream.read(&mut buf).await.unwrap();
+                                assert_eq!(&buf[..n], first);
+                            }
+                        }
+                        None => {
+                            assert!(is_handled(&res.unwrap_err()));
+                            let mut resp = Vec::new();
+                            client.read_to_end(&mut resp).await.unwrap();
+                            assert!(resp.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+                        }
+                    }
+                })+
+            }
+        }
+
+        let path = ws_conf(2048, "");
+        let header = ws_conf(4, "Sec-WebSocket-Protocol");
+        y![
+            (path.clone(), "/chat", "", Some(None), b"");
+            (path.clone(), "/chat/aGVsbG8", "", Some(None), b"hello");
+            // a longer segment, or a route
+            (path.clone(), "/chataGVsbG8", "", None, b"");
+            (path.clone(), "/chat/room", "", Some(Some(0)), b"");
+            (path.clone(), "/chat/!", "", None, b"");
+            (path.clone(), "/chat/", "", None, b"");
+            (header.clone(), "/chat", "Sec-WebSocket-Protocol: aGVsbA\r\n", Some(None), b"hell");
+            (header.clone(), "/chat", "Sec-WebSocket-Protocol: aGVsbG8\r\n", None, b"");
+        ];
+
+        // not an upgrade, early data is not looked at
+        let lis = WsAccept::new(NopAccept {}, fallback(ws_conf(4, "Sec-WebSocket-Protocol")));
+        let (mut client, server) = tokio::io::duplex(0x4000);
+        let req = format!(
+            "GET /chat HTTP/1.1\r\nHost: abc\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+            "a".repeat(0x400)
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        assert!(is_handled(&lis.accept(server, &mut buf).await.unwrap_err()));
+    }
+}
@@ -0,0 +1,98 @@
+//! Camouflage for non-tunnel requests.
+//!
+//! A plain http request, or an upgrade request that matches neither
+//! the `host` and `path` nor any route, is answered like a regular
+//! web server would, instead of closing the socket.
+//!
+//! Garbage that is not http at all is still rejected.
+//!
+//! A handled request ends the accept with a [`Handled`] error,
+//! the relay to another server runs in its own task.
+
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::fallback::{Handled, spawn_relay};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Fallback {
+    // close the socket
+    #[default]
+    Close,
+    // reply with a fixed status and body
+    Static {
+        status: u16,
+        body: Vec<u8>,
+    },
+    // relay to an http server, including the buffered request
+    Proxy(SocketAddr),
+}
+
+impl Display for Fallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fallback::Close => write!(f, "close"),
+            Fallback::Static { status, body } => write!(f, "{status} ({} bytes)", body.len()),
+            Fallback::Proxy(addr) => write!(f, "proxy {addr}"),
+        }
+    }
+}
+
+impl Fallback {
+    /// Handle a request in `buf`, return the error to report.
+    ///
+    /// `err` is returned as is if there is no fallback.
+    pub(crate) async fn run<IO>(&self, mut io: IO, buf: &[u8], err: Error) -> Error
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            Fallback::Close => return err,
+            Fallback::Static { status, body } => {
+                if let Err(e) = reply(&mut io, *status, body).await {
+                    return e;
+                }
+            }
+            Fallback::Proxy(addr) => spawn_relay(io, buf.to_vec(), *addr),
+        };
+
+        Handled::new_error(err)
+    }
+}
+
+async fn reply<IO>(io: &mut IO, status: u16, body: &[u8]) -> Result<()>
+where
+    IO: AsyncWrite + Unpin,
+{
+    let head = format!(
+        "HTTP/1.1 {} {}\r\ncontent-type: text/html\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+        status,
+        reason(status),
+        body.len()
+    );
+    io.write_all(head.as_bytes()).await?;
+    io.write_all(body).await?;
+    io.shutdown().await
+}
+
+const fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        426 => "Upgrade Required",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
@@ -0,0 +1,506 @@
+//! WebSocket over HTTP/2, see [RFC 8441](https://www.rfc-editor.org/rfc/rfc8441).
+//!
+//! [`Ws2Connect`] opens each tunnel as an extended `CONNECT` stream with
+//! `:protocol = websocket`. Tunnels share one h2 connection: it is made
+//! by [`AsyncConnect::connect`], then reused by [`Ws2Connect::open`]
+//! until it closes.
+//!
+//! [`Ws2Accept`] takes both versions on the same port. A connection that
+//! starts with the h2 preface is served by [`Ws2Conn`], which yields a
+//! stream for each tunnel. Anything else goes through the HTTP/1.1
+//! upgrade, same as [`WsAccept`](super::WsAccept).
+//!
+//! Frames, keepalive, compression and the other stream settings work as
+//! over HTTP/1.1. Early data and fallback are HTTP/1.1 only.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::future::Future;
+use std::fmt::{Debug, Display, Formatter};
+
+use bytes::Bytes;
+use h2::ext::Protocol;
+use h2::client::SendRequest;
+use h2::server::{self, SendResponse};
+use h2::{RecvStream, SendStream};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+
+use lightws::handshake::HttpHeader;
+use lightws::role::Server;
+
+use crate::{IOStream, AsyncAccept, AsyncConnect};
+use crate::http2::{H2Stream, Pool, into_io, read_preface};
+use super::{WsConf, WsServerStream, Accepted, StreamConf, accept_handshake, confirm_deflate};
+use super::deflate::{self, EXTENSIONS};
+use super::policy::{self, Policy, RequestHead, Verdict};
+use super::realip::{self, Forwarded};
+use super::route;
+use super::stream::{DynamicClient, WsStream};
+
+pub type Ws2ClientStream = WsStream<H2Stream, DynamicClient>;
+pub type Ws2ServerStream = WsServerStream<H2Stream>;
+
+pub use crate::http2::Rewind;
+
+// borrowed as lightws headers, for the shared checks
+fn http_headers(headers: &HeaderMap) -> Vec<HttpHeader<'_>> {
+    headers
+        .iter()
+        .map(|(k, v)| HttpHeader::new(k.as_str().as_bytes(), v.as_bytes()))
+        .collect()
+}
+
+// ========== client ==========
+#[derive(Debug, Clone)]
+pub struct Ws2Connect<T> {
+    conn: T,
+    conf: WsConf,
+    pool: Pool,
+}
+
+impl<T> Display for Ws2Connect<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[ws2]{}", self.conn) }
+}
+
+impl<T> Ws2Connect<T> {
+    #[inline]
+    pub fn new(conn: T, conf: WsConf) -> Self {
+        Self {
+            conn,
+            conf,
+            pool: Pool::default(),
+        }
+    }
+
+    /// Open a tunnel on the shared connection, `None` if there is no live one.
+    pub async fn open(&self) -> Option<Result<Ws2ClientStream>> {
+        let send = self.pool.get().await?;
+        Some(request(send, &self.conf).await)
+    }
+}
+
+impl<S, T> AsyncConnect<S> for Ws2Connect<T>
+where
+    S: IOStream,
+    T: AsyncConnect<S>,
+    T::Stream: Send,
+{
+    type Stream = Ws2ClientStream;
+
+    type ConnectFut<'a>
+        = impl Future<Output = Result<Self::Stream>> + 'a
+    where
+        Self: 'a;
+
+    // make a new connection, which replaces the shared one
+    fn connect<'a>(&'a self, stream: S, buf: &'a mut [u8]) -> Self::ConnectFut<'a> {
+        async move {
+            let stream = self.conn.connect(stream, buf).await?;
+            let send = self.pool.connect(stream).await?;
+            request(send, &self.conf).await
+        }
+    }
+}
+
+// send the extended connect, wait for 200
+async fn request(mut send: SendRequest<Bytes>, conf: &WsConf) -> Result<Ws2ClientStream> {
+    let mut req = Request::builder()
+        .method(Method::CONNECT)
+        .uri(format!("https://{}{}", conf.host, conf.path))
+        .header("sec-websocket-version", "13")
+        .extension(Protocol::from_static("websocket"));
+    for (k, v) in &conf.client.headers {
+        req = req.header(k, v);
+    }
+    if !conf.auth.is_empty() {
+        req = req.header("authorization", format!("Bearer {}", conf.auth));
+    }
+    if let Some(deflate) = &conf.deflate {
+        req = req.header(EXTENSIONS, deflate::offer(deflate));
+    }
+    let req = req
+        .body(())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    let (response, send) = send.send_request(req, false).map_err(into_io)?;
+    let response = response.await.map_err(into_io)?;
+    if response.status() != StatusCode::OK {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("ws: unexpected status {}", response.status()),
+        ));
+    }
+
+    let deflate = confirm_deflate(conf, &http_headers(response.headers()))?;
+    let io = H2Stream::new(send, response.into_body());
+    Ok(StreamConf::from(conf).with_deflate(deflate).build(io))
+}
+
+// ========== server ==========
+#[derive(Clone)]
+pub struct Ws2Accept<T> {
+    lis: T,
+    conf: WsConf,
+    policy: Option<Arc<dyn Policy>>,
+}
+
+impl<T> Debug for Ws2Accept<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ws2Accept")
+            .field("lis", &self.lis)
+            .field("conf", &self.conf)
+            .field("policy", &self.policy.is_some())
+            .finish()
+    }
+}
+
+impl<T> Display for Ws2Accept<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "[ws2]{}", self.lis) }
+}
+
+/// Connection accepted by [`Ws2Accept`].
+pub enum Incoming<T> {
+    /// Upgraded HTTP/1.1 tunnel.
+    Http1(WsServerStream<Rewind<T>>),
+    /// Preface of an h2 connection, to [`serve`](Ws2Accept::serve).
+    Http2(Rewind<T>),
+}
+
+impl<T> Ws2Accept<T> {
+    #[inline]
+    pub const fn new(lis: T, conf: WsConf) -> Self {
+        Self {
+            lis,
+            conf,
+            policy: None,
+        }
+    }
+
+    /// Run a custom check on each request, after the rules from [`WsConf`].
+    #[inline]
+    pub fn with_policy<P: Policy + 'static>(mut self, policy: P) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Accept an HTTP/1.1 upgrade, or read the preface of an h2 connection.
+    pub async fn accept<S>(&self, stream: S, buf: &mut [u8]) -> Result<Incoming<T::Stream>>
+    where
+        S: IOStream,
+        T: AsyncAccept<S>,
+        T::Stream: Send,
+    {
+        let mut io = self.lis.accept(stream, buf).await?;
+        let (n, is_h2) = read_preface(&mut io, buf).await?;
+        let io = Rewind::new(io, &buf[..n]);
+
+        if is_h2 {
+            return Ok(Incoming::Http2(io));
+        }
+        accept_handshake(io, buf, &self.conf, self.policy.as_deref())
+            .await
+            .map(Incoming::Http1)
+    }
+
+    /// Start the h2 connection, then take tunnels from [`Ws2Conn::accept`].
+    pub async fn serve<IO: IOStream>(&self, io: Rewind<IO>) -> Result<Ws2Conn<'_, IO>> {
+        let conn = server::Builder::new()
+            .enable_connect_protocol()
+            .handshake(io)
+            .await
+            .map_err(into_io)?;
+
+        Ok(Ws2Conn {
+            conn,
+            conf: &self.conf,
+            policy: self.policy.as_deref(),
+        })
+    }
+}
+
+/// Tunnels of an h2 connection.
+pub struct Ws2Conn<'a, T> {
+    conn: server::Connection<Rewind<T>, Bytes>,
+    conf: &'a WsConf,
+    policy: Option<&'a dyn Policy>,
+}
+
+impl<T: IOStream> Ws2Conn<'_, T> {
+    /// Next tunnel, `None` once the connection closes.
+    ///
+    /// This also drives the connection, keep calling it while tunnels
+    /// are in use. Rejected requests are answered here and skipped.
+    pub async fn accept(&mut self) -> Option<Result<Ws2ServerStream>> {
+        loop {
+            let (req, respond) = match self.conn.accept().await? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(into_io(e))),
+            };
+            if let Some(stream) = accept_stream(req, respond, self.conf, self.policy) {
+                return Some(Ok(stream));
+            }
+        }
+    }
+
+    /// Stop taking new tunnels, open ones are served until done.
+    #[inline]
+    pub fn graceful_shutdown(&mut self) { self.conn.graceful_shutdown() }
+}
+
+// same checks as the HTTP/1.1 upgrade, none if rejected
+fn accept_stream(
+    req: Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    conf: &WsConf,
+    policy: Option<&dyn Policy>,
+) -> Option<Ws2ServerStream> {
+    let (parts, body) = req.into_parts();
+    let is_ws = parts.method == Method::CONNECT
+        && (parts.extensions.get::<Protocol>()).is_some_and(|x| x.as_str() == "websocket")
+        && (parts.headers.get("sec-websocket-version")).is_some_and(|x| x == "13");
+    if !is_ws {
+        return reject(respond, StatusCode::BAD_REQUEST);
+    }
+
+    let host = parts.uri.authority().map_or("", |x| x.as_str()).as_bytes();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |x| x.as_str())
+        .as_bytes();
+    let Some(route) = route::find(conf, host, path) else {
+        return reject(respond, StatusCode::NOT_FOUND);
+    };
+
+    let headers = http_headers(&parts.headers);
+    let head = RequestHead {
+        host,
+        path,
+        headers: &headers,
+    };
+    let verdict = match policy::check(conf, &head) {
+        Verdict::Accept => policy.map_or(Verdict::Accept, |p| p.check(&head)),
+        x => x,
+    };
+    match verdict {
+        Verdict::Accept => {}
+        Verdict::Unauthorized => return reject(respond, StatusCode::UNAUTHORIZED),
+        Verdict::Forbidden => return reject(respond, StatusCode::FORBIDDEN),
+    }
+
+    let client_ip = realip::candidate(&head, &conf.server.real_ip, &conf.server.trusted);
+
+    // send
+    let deflate = (conf.deflate.as_ref()).and_then(|x| deflate::accept(x, &headers));
+    let mut response = Response::builder().status(StatusCode::OK);
+    if let Some((_, ext)) = &deflate {
+        response = response.header(EXTENSIONS, ext);
+    }
+    let send = send_response(respond, response, false)?;
+
+    let io = H2Stream::new(send, body);
+    let io = Forwarded::with_candidate(io, client_ip, &conf.server.trusted);
+    let io = Accepted::new(io).with_route(route);
+    let deflate = deflate.map(|(params, _)| params);
+    Some(
+        StreamConf::from(conf)
+            .with_deflate(deflate)
+            .build::<_, Server>(io),
+    )
+}
+
+fn reject<T>(respond: SendResponse<Bytes>, status: StatusCode) -> Option<T> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::UNAUTHORIZED {
+        response = response.header("www-authenticate", "Bearer");
+    }
+    let _ = send_response(respond, response, true);
+    None
+}
+
+fn send_response(
+    mut respond: SendResponse<Bytes>,
+    response: http::response::Builder,
+    end: bool,
+) -> Option<SendStream<Bytes>> {
+    let response = response.body(()).ok()?;
+    respond.send_response(response, end).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::nop::{NopAccept, NopConnect};
+    use crate::ws::{MaskMode, WsConnect, WsClientConf, WsServerConf};
+    use crate::ws::route::{Route, Routed};
+
+    fn ws_conf(auth: &str) -> WsConf {
+        WsConf {
+            host: String::from("abc"),
+            path: String::from("/chat"),
+            auth: String::from(auth),
+            client: WsClientConf {
+                mask: MaskMode::Standard,
+                ..Default::default()
+            },
+            server: WsServerConf {
+                routes: vec![Route {
+                    host: String::from("*"),
+                    path: String::from("/v2"),
+                    target: String::from("127.0.0.1:8080"),
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // serve h2 tunnels with an echo, return their routes
+    async fn echo_server(
+        lis: Ws2Accept<NopAccept>,
+        io: tokio::io::DuplexStream,
+    ) -> Vec<Option<usize>> {
+        let mut buf = vec![0u8; 0x1000];
+        let Incoming::Http2(io) = lis.accept(io, &mut buf).await.unwrap() else {
+            panic!("not h2");
+        };
+        let mut conn = lis.serve(io).await.unwrap();
+
+        let mut routes = Vec::new();
+        while let Some(Ok(mut stream)) = conn.accept().await {
+            routes.push(stream.route());
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 0x100];
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    stream.write_all(&buf[..n]).await.unwrap();
+                }
+                stream.shutdown().await.unwrap();
+            });
+        }
+        routes
+    }
+
+    async fn echo(stream: &mut Ws2ClientStream, data: &[u8]) -> Vec<u8> {
+        stream.write_all(data).await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn multiplex() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let lis = Ws2Accept::new(NopAccept {}, ws_conf("t0k"));
+        let server = tokio::spawn(echo_server(lis, server));
+
+        let conf = ws_conf("t0k");
+        let routed = WsConf {
+            path: String::from("/v2"),
+            ..conf.clone()
+        };
+        let conn = Ws2Connect::new(NopConnect {}, conf);
+        assert!(conn.open().await.is_none());
+
+        let mut buf = vec![0u8; 0x1000];
+        let mut a = conn.connect(client, &mut buf).await.unwrap();
+        let mut b = conn.open().await.unwrap().unwrap();
+        let mut c = Ws2Connect {
+            conf: routed,
+            ..conn.clone()
+        }
+        .open()
+        .await
+        .unwrap()
+        .unwrap();
+
+        // interleaved, larger than the duplex buffer
+        let data: Vec<u8> = (0..0x3000).map(|x| x as u8).collect();
+        for _ in 0..3 {
+            assert_eq!(echo(&mut c, b"ccc").await, b"ccc");
+            assert_eq!(echo(&mut a, &data).await, data);
+            assert_eq!(echo(&mut b, b"bb").await, b"bb");
+        }
+
+        for mut x in [a, b, c] {
+            x.shutdown().await.unwrap();
+            assert_eq!(x.read(&mut buf).await.unwrap(), 0);
+        }
+        drop(conn);
+        assert_eq!(server.await.unwrap(), [None, None, Some(0)]);
+    }
+
+    #[tokio::test]
+    async fn accept_http1() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let conn = WsConnect::new(NopConnect {}, ws_conf("t0k"));
+        let lis = Ws2Accept::new(NopAccept {}, ws_conf("t0k"));
+
+        let mut buf1 = vec![0u8; 0x1000];
+        let mut buf2 = vec![0u8; 0x1000];
+        let (c, s) = tokio::join!(
+            conn.connect(client, &mut buf1),
+            lis.accept(server, &mut buf2)
+        );
+        let (mut c, Incoming::Http1(mut s)) = (c.unwrap(), s.unwrap()) else {
+            panic!("not http/1.1");
+        };
+
+        c.write_all(b"hello").await.unwrap();
+        let n = s.read(&mut buf2).await.unwrap();
+        assert_eq!(&buf2[..n], b"hello");
+    }
+
+    #[tokio::test]
+    async fn reject_with_status() {
+        macro_rules! n {
+            ( $( ($auth: expr, $path: expr, $status: expr); )+ ) => {
+                $(
+                    let (client, server) = tokio::io::duplex(0x1000);
+                    let lis = Ws2Accept::new(NopAccept {}, ws_conf("t0k"));
+                    let server = tokio::spawn(echo_server(lis, server));
+
+                    let conf = WsConf {
+                        path: String::from($path),
+                        ..ws_conf($auth)
+                    };
+                    let conn = Ws2Connect::new(NopConnect {}, conf);
+                    let mut buf = vec![0u8; 0x1000];
+                    let err = conn.connect(client, &mut buf).await.unwrap_err();
+                    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+                    assert_eq!(err.to_string(), format!("ws: unexpected status {}", $status));
+
+                    // the connection goes on
+                    let conn = Ws2Connect {
+                        conf: ws_conf("t0k"),
+                        ..conn
+                    };
+                    let mut stream = conn.open().await.unwrap().unwrap();
+                    assert_eq!(echo(&mut stream, b"ok").await, b"ok");
+                    stream.shutdown().await.unwrap();
+                    drop((stream, conn));
+                    assert_eq!(server.await.unwrap(), [None]);
+                )+
+            };
+        }
+
+        n! {
+            ("", "/chat", "401 Unauthorized");
+            ("bad", "/chat", "401 Unauthorized");
+            ("t0k", "/none", "404 Not Found");
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Upgrade request inspection.
+//!
+//! [`WsAccept`](super::WsAccept) checks the declarative rules from
+//! [`WsConf`], then an optional [`Policy`] hook. A rejected request
+//! gets a `401` or `403` response before the socket is closed.
+
+use std::io::{Error, ErrorKind};
+
+use lightws::handshake::HttpHeader;
+
+use super::WsConf;
+
+/// Parsed upgrade request.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestHead<'a> {
+    pub host: &'a [u8],
+    pub path: &'a [u8],
+    // other than host, upgrade, connection, sec-websocket-*
+    pub headers: &'a [HttpHeader<'a>],
+}
+
+impl<'a> RequestHead<'a> {
+    /// Value of the first header with this name, case insensitive.
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|h| h.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Unauthorized,
+    Forbidden,
+}
+
+impl Verdict {
+    pub(crate) const fn response(self) -> &'static [u8] {
+        match self {
+            Verdict::Accept => b"",
+            Verdict::Unauthorized => {
+                b"HTTP/1.1 401 Unauthorized\r\nwww-authenticate: Bearer\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            }
+            Verdict::Forbidden => {
+                b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            }
+        }
+    }
+
+    pub(crate) fn into_error(self) -> Error {
+        match self {
+            Verdict::Accept => unreachable!(),
+            Verdict::Unauthorized => Error::new(ErrorKind::PermissionDenied, "ws: unauthorized"),
+            Verdict::Forbidden => Error::new(ErrorKind::PermissionDenied, "ws: forbidden"),
+        }
+    }
+}
+
+/// Custom check on upgrade requests, after the rules from [`WsConf`].
+pub trait Policy: Send + Sync {
+    fn check(&self, req: &RequestHead<'_>) -> Verdict;
+}
+
+impl<F> Policy for F
+where
+    F: Fn(&RequestHead<'_>) -> Verdict + Send + Sync,
+{
+    fn check(&self, req: &RequestHead<'_>) -> Verdict { self(req) }
+}
+
+/// Check a request against the rules from [`WsConf`].
+pub fn check(conf: &WsConf, req: &RequestHead<'_>) -> Verdict {
+    if !conf.auth.is_empty() {
+        let ok = req
+            .header("authorization")
+            .and_then(|v| v.split_at_checked(7))
+            .is_some_and(|(scheme, token)| {
+                scheme.eq_ignore_ascii_case(b"bearer ") && ct_eq(token, conf.auth.as_bytes())
+            });
+        if !ok {
+            return Verdict::Unauthorized;
+        }
+    }
+
+    let server = &conf.server;
+    if !server.origins.is_empty() {
+        let ok = req.header("origin").is_some_and(|v| {
+            server
+                .origins
+                .iter()
+                .any(|x| x.as_bytes().eq_ignore_ascii_case(v))
+        });
+        if !ok {
+            return Verdict::Forbidden;
+        }
+    }
+
+    for (k, v) in server.headers.iter() {
+        if req.header(k) != Some(v.as_bytes()) {
+            return Verdict::Forbidden;
+        }
+    }
+
+    Verdict::Accept
+}
+
+// do not leak the length of the common prefix
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ws::WsServerConf;
+
+    fn conf(auth: &str, origins: &[&str], headers: &[(&str, &str)]) -> WsConf {
+        WsConf {
+            host: String::from("abc"),
+            path: String::from("/"),
+            auth: String::from(auth),
+            server: WsServerConf {
+                headers: headers
+                    .iter()
+                    .map(|(k, v)| (String::from(*k), String::from(*v)))
+                    .collect(),
+                origins: origins.iter().map(|x| String::from(*x)).collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_rules() {
+        use Verdict::*;
+        macro_rules! y {
+            ( $( ($conf: expr, [ $( ($k: expr, $v: expr) ),* ], $verdict: expr); )+ ) => {
+                $(
+                    let headers = [ $( HttpHeader::new($k, $v) ),* ];
+                    let req = RequestHead { host: b"abc", path: b"/", headers: &headers };
+                    assert_eq!(check(&$conf, &req), $verdict);
+                )+
+            }
+        }
+
+        y![
+            (conf("", &[], &[]), [], Accept);
+            (conf("t0k", &[], &[]), [], Unauthorized);
+            (conf("t0k", &[], &[]), [(b"Authorization", b"Bearer t0k")], Accept);
+            (conf("t0k", &[], &[]), [(b"authorization", b"bearer t0k")], Accept);
+            (conf("t0k", &[], &[]), [(b"Authorization", b"Bearer t0")], Unauthorized);
+            (conf("t0k", &[], &[]), [(b"Authorization", b"Basic t0k")], Unauthorized);
+            (conf("", &["https://a.b"], &[]), [], Forbidden);
+            (conf("", &["https://a.b"], &[]), [(b"Origin", b"https://A.b")], Accept);
+            (conf("", &["https://a.b", "https://c.d"], &[]), [(b"Origin", b"https://c.d")], Accept);
+            (conf("", &["https://a.b"], &[]), [(b"Origin", b"https://c.d")], Forbidden);
+            (conf("", &[], &[("X-Id", "1")]), [(b"x-id", b"1")], Accept);
+            (conf("", &[], &[("X-Id", "1")]), [(b"X-Id", b"2")], Forbidden);
+            (conf("t0k", &[], &[("X-Id", "1")]), [(b"X-Id", b"1")], Unauthorized);
+        ];
+    }
+}
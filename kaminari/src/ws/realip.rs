@@ -0,0 +1,347 @@
+//! Real client address behind proxies or CDNs.
+//!
+//! [`WsAccept`](super::WsAccept) reads the configured headers
+//! (e.g. `X-Forwarded-For`, `X-Real-IP`, `CF-Connecting-IP`) in order,
+//! and keeps the candidate address on [`Forwarded`]. The candidate is
+//! only used when the tcp peer is a trusted proxy, which is known by the
+//! caller, see [`Forwarded::client_ip`].
+//!
+//! A header may hold a list of addresses, which is walked from right
+//! to left, skipping trusted proxies. Elements of `Forwarded` (rfc 7239)
+//! are read by their `for` parameter. The port comes with the address,
+//! or from `X-Forwarded-Port` at the same position, 0 if unknown.
+
+use std::io::Result;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
+use std::task::{Context, Poll};
+use std::fmt::{Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::policy::RequestHead;
+
+// ========== cidr ==========
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // compare v4 mapped v6 as v4
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr = IpAddr::from_str(addr.trim()).map_err(|e| format!("{s}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.trim() {
+            "" => max,
+            x => x.parse().map_err(|e| format!("{s}: {e}"))?,
+        };
+
+        if prefix > max {
+            return Err(format!("{s}: invalid prefix"));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[inline]
+fn is_trusted(trusted: &[Cidr], ip: &IpAddr) -> bool { trusted.iter().any(|x| x.contains(ip)) }
+
+// 1.1.1.1, 1.1.1.1:80, ::1, [::1], [::1]:80, for="[::1]:80";proto=https
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let s = s.trim();
+    let s = s
+        .split(';')
+        .filter_map(|x| x.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("for"))
+        .map_or(s, |(_, v)| v.trim().trim_matches('"'));
+    let bare = s.strip_prefix('[').and_then(|x| x.strip_suffix(']'));
+    IpAddr::from_str(bare.unwrap_or(s))
+        .map(|x| SocketAddr::new(x, 0))
+        .or_else(|_| SocketAddr::from_str(s))
+        .ok()
+        .map(|x| SocketAddr::new(x.ip().to_canonical(), x.port()))
+}
+
+// multiple headers are joined as one list
+fn get_list<'a>(req: &RequestHead<'a>, name: &str) -> Vec<&'a str> {
+    req.headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name.as_bytes()))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .flat_map(|v| v.split(','))
+        .collect()
+}
+
+/// Find the client address from headers, assuming the peer is trusted.
+pub fn candidate(req: &RequestHead<'_>, names: &[String], trusted: &[Cidr]) -> Option<SocketAddr> {
+    for name in names {
+        let list = get_list(req, name);
+
+        // position from the right
+        let mut last = None;
+        for (i, x) in list.iter().rev().enumerate() {
+            // can not trust anything on the left
+            let Some(addr) = parse_addr(x) else { break };
+            last = Some((i, addr));
+            if !is_trusted(trusted, &addr.ip()) {
+                break;
+            }
+        }
+
+        match last {
+            Some((i, addr)) if addr.port() == 0 => {
+                let ports = get_list(req, "x-forwarded-port");
+                let port = (ports.iter().rev().nth(i)).and_then(|x| x.trim().parse().ok());
+                return Some(SocketAddr::new(addr.ip(), port.unwrap_or(0)));
+            }
+            Some((_, addr)) => return Some(addr),
+            None => {}
+        }
+    }
+
+    None
+}
+
+// ========== stream ==========
+#[derive(Debug, Clone)]
+struct Candidate {
+    addr: SocketAddr,
+    trusted: Vec<Cidr>,
+}
+
+/// Accepted stream with the client address from headers.
+#[derive(Debug)]
+pub struct Forwarded<T> {
+    io: T,
+    // boxed, rarely set
+    candidate: Option<Box<Candidate>>,
+}
+
+impl<T> Forwarded<T> {
+    #[inline]
+    pub const fn new(io: T) -> Self {
+        Self {
+            io,
+            candidate: None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn with_candidate(io: T, addr: Option<SocketAddr>, trusted: &[Cidr]) -> Self {
+        let candidate = addr.map(|addr| {
+            Box::new(Candidate {
+                addr,
+                trusted: trusted.to_vec(),
+            })
+        });
+        Self { io, candidate }
+    }
+
+    #[inline]
+    fn candidate(&self) -> Option<&Candidate> { self.candidate.as_deref() }
+
+    #[inline]
+    pub const fn get_ref(&self) -> &T { &self.io }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T { &mut self.io }
+
+    #[inline]
+    pub fn into_inner(self) -> T { self.io }
+
+    /// Address from headers, without checking the peer.
+    #[inline]
+    pub fn forwarded_ip(&self) -> Option<IpAddr> { self.forwarded_addr().map(|x| x.ip()) }
+
+    /// Same as [`forwarded_ip`](Self::forwarded_ip), with the port,
+    /// which is 0 if unknown.
+    #[inline]
+    pub fn forwarded_addr(&self) -> Option<SocketAddr> { self.candidate().map(|x| x.addr) }
+
+    /// Real client address, `peer` is the address of the tcp connection.
+    ///
+    /// Headers are ignored unless `peer` is a trusted proxy.
+    #[inline]
+    pub fn client_ip(&self, peer: IpAddr) -> IpAddr {
+        self.real_addr(SocketAddr::new(peer, 0)).ip()
+    }
+
+    /// Same as [`client_ip`](Self::client_ip), with the port from headers,
+    /// which is 0 if unknown.
+    pub fn real_addr(&self, peer: SocketAddr) -> SocketAddr {
+        match self.candidate() {
+            Some(x) if is_trusted(&x.trusted, &peer.ip()) => x.addr,
+            _ => peer,
+        }
+    }
+}
+
+impl<T> AsyncRead for Forwarded<T>
+where
+    T: AsyncRead + Unpin,
+{
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for Forwarded<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool { self.io.is_write_vectored() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lightws::handshake::HttpHeader;
+
+    fn cidrs(s: &[&str]) -> Vec<Cidr> { s.iter().map(|x| x.parse().unwrap()).collect() }
+
+    #[test]
+    fn cidr() {
+        macro_rules! y {
+            ( $( ($cidr: expr, $ip: expr, $ok: expr); )+ ) => {
+                $(
+                    let cidr: Cidr = $cidr.parse().unwrap();
+                    assert_eq!(cidr.contains(&$ip.parse().unwrap()), $ok, "{} {}", $cidr, $ip);
+                )+
+            }
+        }
+
+        y![
+            ("10.0.0.0/8", "10.1.2.3", true);
+            ("10.0.0.0/8", "11.1.2.3", false);
+            ("1.2.3.4", "1.2.3.4", true);
+            ("1.2.3.4", "1.2.3.5", false);
+            ("0.0.0.0/0", "8.8.8.8", true);
+            ("0.0.0.0/0", "::1", false);
+            ("::/0", "::1", true);
+            ("2001:db8::/32", "2001:db8:1::1", true);
+            ("2001:db8::/32", "2001:db9::1", false);
+            ("127.0.0.0/8", "::ffff:127.0.0.1", true);
+        ];
+
+        for x in ["1.2.3.4/33", "::1/129", "1.2.3/8", "a.b.c.d", "1.2.3.4/x"] {
+            assert!(x.parse::<Cidr>().is_err(), "{x}");
+        }
+    }
+
+    #[test]
+    fn find_candidate() {
+        macro_rules! y {
+            ( $( ([ $( ($k: expr, $v: expr) ),* ], $names: expr, $trusted: expr, $addr: expr); )+ ) => {
+                $(
+                    let headers = [ $( HttpHeader::new($k, $v) ),* ];
+                    let req = RequestHead { host: b"", path: b"", headers: &headers };
+                    let names: Vec<String> = $names.iter().map(|x: &&str| x.to_string()).collect();
+                    let addr: Option<&str> = $addr;
+                    assert_eq!(
+                        candidate(&req, &names, &cidrs(&$trusted)),
+                        addr.map(|x| x.parse().unwrap())
+                    );
+                )+
+            }
+        }
+
+        y![
+            ([], ["x-forwarded-for"], [], None);
+            ([(b"X-Forwarded-For", b"1.1.1.1")], ["x-forwarded-for"], [], Some("1.1.1.1:0"));
+            ([(b"X-Forwarded-For", b"1.1.1.1, 2.2.2.2")], ["x-forwarded-for"], [], Some("2.2.2.2:0"));
+            ([(b"X-Forwarded-For", b"1.1.1.1, 10.0.0.1")], ["x-forwarded-for"], ["10.0.0.0/8"], Some("1.1.1.1:0"));
+            ([(b"X-Forwarded-For", b"1.1.1.1"), (b"X-Forwarded-For", b"10.0.0.1")], ["x-forwarded-for"], ["10.0.0.0/8"], Some("1.1.1.1:0"));
+            ([(b"X-Forwarded-For", b"10.0.0.2, 10.0.0.1")], ["x-forwarded-for"], ["10.0.0.0/8"], Some("10.0.0.2:0"));
+            ([(b"X-Forwarded-For", b"1.1.1.1, bad, 10.0.0.1")], ["x-forwarded-for"], ["10.0.0.0/8"], Some("10.0.0.1:0"));
+            ([(b"X-Forwarded-For", b"[2001:db8::1]:443, 3.3.3.3:80")], ["x-forwarded-for"], [], Some("3.3.3.3:80"));
+            ([(b"X-Real-IP", b"4.4.4.4")], ["cf-connecting-ip", "x-real-ip"], [], Some("4.4.4.4:0"));
+            ([(b"X-Real-IP", b"4.4.4.4"), (b"CF-Connecting-IP", b"5.5.5.5")], ["cf-connecting-ip", "x-real-ip"], [], Some("5.5.5.5:0"));
+            ([(b"CF-Connecting-IP", b"bad"), (b"X-Real-IP", b"4.4.4.4")], ["cf-connecting-ip", "x-real-ip"], [], Some("4.4.4.4:0"));
+            ([(b"X-Real-IP", b"4.4.4.4"), (b"X-Forwarded-Port", b"5000")], ["x-real-ip"], [], Some("4.4.4.4:5000"));
+            ([(b"X-Forwarded-For", b"1.1.1.1, 10.0.0.1"), (b"X-Forwarded-Port", b"5000, 6000")], ["x-forwarded-for"], ["10.0.0.0/8"], Some("1.1.1.1:5000"));
+            ([(b"X-Forwarded-For", b"1.1.1.1, 10.0.0.1"), (b"X-Forwarded-Port", b"6000")], ["x-forwarded-for"], ["10.0.0.0/8"], Some("1.1.1.1:0"));
+            ([(b"X-Forwarded-For", b"1.1.1.1:80"), (b"X-Forwarded-Port", b"5000")], ["x-forwarded-for"], [], Some("1.1.1.1:80"));
+            ([(b"Forwarded", b"for=1.1.1.1;proto=https")], ["forwarded"], [], Some("1.1.1.1:0"));
+            ([(b"Forwarded", b"for=1.1.1.1, for=\"[2001:db8::1]:4711\"")], ["forwarded"], [], Some("[2001:db8::1]:4711"));
+            ([(b"Forwarded", b"for=\"[2001:db8::1]\";by=10.0.0.1, For=10.0.0.2")], ["forwarded"], ["10.0.0.0/8"], Some("[2001:db8::1]:0"));
+            ([(b"Forwarded", b"for=unknown, for=10.0.0.2")], ["forwarded"], ["10.0.0.0/8"], Some("10.0.0.2:0"));
+        ];
+    }
+
+    #[test]
+    fn client_ip() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        let addr = |x: &str| x.parse::<SocketAddr>().unwrap();
+        let trusted = cidrs(&["10.0.0.0/8"]);
+
+        let stream = Forwarded::with_candidate((), Some(addr("1.1.1.1:5000")), &trusted);
+        assert_eq!(stream.client_ip(ip("10.0.0.1")), ip("1.1.1.1"));
+        assert_eq!(stream.client_ip(ip("2.2.2.2")), ip("2.2.2.2"));
+        assert_eq!(stream.real_addr(addr("10.0.0.1:80")), addr("1.1.1.1:5000"));
+        assert_eq!(stream.real_addr(addr("2.2.2.2:80")), addr("2.2.2.2:80"));
+
+        let stream = Forwarded::with_candidate((), None, &trusted);
+        assert_eq!(stream.client_ip(ip("10.0.0.1")), ip("10.0.0.1"));
+        assert_eq!(stream.real_addr(addr("10.0.0.1:80")), addr("10.0.0.1:80"));
+    }
+}
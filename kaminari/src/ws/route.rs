@@ -0,0 +1,152 @@
+//! Host and path based routing.
+//!
+//! Requests that do not match the `host` and `path` of [`WsConf`]
+//! are matched against [`WsServerConf::routes`](super::WsServerConf::routes)
+//! in order. The index of the matched route is kept on the accepted
+//! stream, see [`Routed`].
+//!
+//! Host patterns:
+//! - empty or `*`: any host
+//! - `*.example.com`: any subdomain of `example.com`
+//! - otherwise: exact, case insensitive
+//!
+//! Path patterns, compared without the query string:
+//! - `/abc/*`: any path that starts with `/abc/`
+//! - otherwise: exact
+
+use std::fmt::{Display, Formatter};
+
+use super::stream::WsStream;
+
+use super::WsConf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub host: String,
+    pub path: String,
+    // not used by the library, e.g. a backend address
+    pub target: String,
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{} => {}", self.host, self.path, self.target)
+    }
+}
+
+impl Route {
+    pub fn matches(&self, host: &[u8], path: &[u8]) -> bool {
+        match_host(&self.host, host) && match_path(&self.path, path)
+    }
+}
+
+fn match_host(pattern: &str, host: &[u8]) -> bool {
+    let pattern = pattern.as_bytes();
+    match pattern {
+        b"" | b"*" => true,
+        [b'*', suffix @ ..] => {
+            host.len() > suffix.len()
+                && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        }
+        _ => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn match_path(pattern: &str, path: &[u8]) -> bool {
+    let path = path.split(|x| *x == b'?').next().unwrap_or_default();
+    match pattern.as_bytes() {
+        [prefix @ .., b'*'] => path.starts_with(prefix),
+        pattern => pattern == path,
+    }
+}
+
+/// Find the route, `None` for the default `host` and `path`.
+pub fn find(conf: &WsConf, host: &[u8], path: &[u8]) -> Option<Option<usize>> {
+    if host == conf.host.as_bytes() && path == conf.path.as_bytes() {
+        return Some(None);
+    }
+
+    (conf.server.routes.iter())
+        .position(|r| r.matches(host, path))
+        .map(Some)
+}
+
+/// Matched route of an accepted stream.
+pub trait Routed {
+    /// Index of [`WsServerConf::routes`](super::WsServerConf::routes),
+    /// `None` for the default `host` and `path`.
+    fn route(&self) -> Option<usize>;
+}
+
+impl<T: Routed, R> Routed for WsStream<T, R> {
+    #[inline]
+    fn route(&self) -> Option<usize> { self.as_ref().route() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ws::WsServerConf;
+
+    #[test]
+    fn match_route() {
+        macro_rules! y {
+            ( $( ($host_pat: expr, $path_pat: expr, $host: expr, $path: expr, $ok: expr); )+ ) => {
+                $(
+                    let route = Route {
+                        host: String::from($host_pat),
+                        path: String::from($path_pat),
+                        target: String::new(),
+                    };
+                    assert_eq!(route.matches($host.as_bytes(), $path.as_bytes()), $ok, "{} {} {}", route, $host, $path);
+                )+
+            }
+        }
+
+        y![
+            ("", "/ssh", "a.com", "/ssh", true);
+            ("*", "/ssh", "a.com", "/ssh?ed=2048", true);
+            ("", "/ssh", "a.com", "/ssh/", false);
+            ("", "/ss/*", "a.com", "/ss/", true);
+            ("", "/ss/*", "a.com", "/ss/abc", true);
+            ("", "/ss/*", "a.com", "/ss", false);
+            ("", "*", "a.com", "/", true);
+            ("a.com", "/", "A.COM", "/", true);
+            ("a.com", "/", "b.com", "/", false);
+            ("*.a.com", "/", "x.a.com", "/", true);
+            ("*.a.com", "/", "x.y.a.com", "/", true);
+            ("*.a.com", "/", "a.com", "/", false);
+            ("*.a.com", "/", "xa.com", "/", false);
+        ];
+    }
+
+    #[test]
+    fn find_route() {
+        let route = |host: &str, path: &str| Route {
+            host: String::from(host),
+            path: String::from(path),
+            target: String::new(),
+        };
+        let conf = WsConf {
+            host: String::from("a.com"),
+            path: String::from("/"),
+            server: WsServerConf {
+                routes: vec![route("", "/ssh"), route("*.a.com", "*"), route("", "*")],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(find(&conf, b"a.com", b"/"), Some(None));
+        assert_eq!(find(&conf, b"a.com", b"/ssh"), Some(Some(0)));
+        assert_eq!(find(&conf, b"x.a.com", b"/ssh"), Some(Some(0)));
+        assert_eq!(find(&conf, b"x.a.com", b"/abc"), Some(Some(1)));
+        assert_eq!(find(&conf, b"b.com", b"/abc"), Some(Some(2)));
+
+        let conf = WsConf {
+            server: WsServerConf::default(),
+            ..conf
+        };
+        assert_eq!(find(&conf, b"b.com", b"/abc"), None);
+    }
+}
@@ -0,0 +1,1549 @@
+//! Websocket stream after the handshake.
+//!
+//! Payload is carried by binary frames, and seen as a byte stream.
+//! Control frames never reach the caller: pings are answered with
+//! pongs, and a close frame ends the stream. Compressed messages are
+//! inflated if [`deflate`](super::deflate) is agreed.
+//!
+//! Our close frame is sent on shutdown, with [`NORMAL_CLOSURE`], after
+//! all frames taken before. Then the shutdown waits for the peer's close
+//! before closing the connection, or gives up after the close timeout.
+//! The peer's close is received by reads, which go on meanwhile.
+//!
+//! A close from the peer ends reads, and is echoed with its code once
+//! frames taken before are sent, writes fail after that. A normal close
+//! ends reads quietly, other codes are returned as [`CloseError`].
+//!
+//! In text mode, payload is carried by text frames instead, encoded as
+//! base64 with padding, so each message is valid UTF-8 and decoded on
+//! its own. Binary frames are rejected then, and vice versa.
+//!
+//! Each write becomes one frame, split if larger than the max frame
+//! size. With coalescing, small writes are gathered into one frame,
+//! which is sent once it is full, or by a flush after the delay. Frames
+//! larger than the max receive size are rejected.
+//!
+//! With a keepalive, a ping is sent after each interval, and the stream
+//! fails with [`ErrorKind::TimedOut`] if the pong does not come back in
+//! time. The timer is only driven by reads, it is checked while a read
+//! is waiting for the peer. So the stream must be read all along, which
+//! is always the case in a relay, an idle stream that nobody reads
+//! neither pings nor times out.
+
+use std::io::{Error, ErrorKind, IoSlice, Result};
+use std::pin::Pin;
+use std::future::Future;
+use std::time::Duration;
+use std::task::{ready, Context, Poll, Waker};
+use std::fmt::{Debug, Display, Formatter};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use lightws::role::{RoleHelper, ClientRole, Server, Client, StandardClient, FixedMaskClient};
+use lightws::frame::{FrameHead, Fin, OpCode, Mask, PayloadLen, new_mask_key, apply_mask4};
+use lightws::error::FrameError;
+
+use super::MaskMode;
+use super::deflate::{Codec, Params};
+
+/// Max payload of a control frame.
+pub const MAX_CTRL_PAYLOAD: usize = 125;
+
+// pongs are dropped beyond this
+const MAX_CTRL_QUEUE: usize = 0x1000;
+
+/// Status code of a normal close.
+pub const NORMAL_CLOSURE: u16 = 1000;
+
+/// Status code of an endpoint going away.
+pub const GOING_AWAY: u16 = 1001;
+
+/// Close frame from the peer, other than a normal close.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseError {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Display for CloseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ws: closed by peer with {}", self.code)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CloseError {}
+
+impl From<CloseError> for Error {
+    fn from(e: CloseError) -> Self { Error::new(ErrorKind::ConnectionAborted, e) }
+}
+
+/// Frame mask of each role.
+pub trait Role: RoleHelper + Unpin + 'static {
+    /// Role of the mask mode, which is fixed by the type except
+    /// for [`DynamicClient`].
+    #[inline]
+    fn with_mode(_: MaskMode) -> Self { Self::new() }
+
+    /// Mask of the next frame.
+    #[inline]
+    fn next_mask(&mut self) -> Mask { self.mask_key() }
+}
+
+impl Role for Server {}
+
+impl Role for Client {}
+
+impl Role for FixedMaskClient {}
+
+impl Role for StandardClient {
+    // a new key for each frame
+    #[inline]
+    fn next_mask(&mut self) -> Mask {
+        self.set_mask_key(new_mask_key());
+        self.mask_key()
+    }
+}
+
+/// Client of any mask mode, chosen at runtime.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicClient {
+    mode: MaskMode,
+    key: [u8; 4],
+}
+
+impl RoleHelper for DynamicClient {
+    const SHORT_FRAME_HEAD_LEN: u8 = <Client as RoleHelper>::SHORT_FRAME_HEAD_LEN;
+    const COMMON_FRAME_HEAD_LEN: u8 = <Client as RoleHelper>::COMMON_FRAME_HEAD_LEN;
+    const LONG_FRAME_HEAD_LEN: u8 = <Client as RoleHelper>::LONG_FRAME_HEAD_LEN;
+
+    #[inline]
+    fn new() -> Self { Self::with_mode(MaskMode::default()) }
+
+    #[inline]
+    fn mask_key(&self) -> Mask {
+        match self.mode {
+            MaskMode::Skip => Mask::Skip,
+            _ => Mask::Key(self.key),
+        }
+    }
+
+    #[inline]
+    fn set_mask_key(&mut self, key: [u8; 4]) { self.key = key; }
+}
+
+impl ClientRole for DynamicClient {}
+
+impl Role for DynamicClient {
+    #[inline]
+    fn with_mode(mode: MaskMode) -> Self {
+        Self {
+            mode,
+            key: new_mask_key(),
+        }
+    }
+
+    #[inline]
+    fn next_mask(&mut self) -> Mask {
+        if self.mode == MaskMode::Standard {
+            self.key = new_mask_key();
+        }
+        self.mask_key()
+    }
+}
+
+#[inline]
+const fn key_of(mask: Mask) -> Option<[u8; 4]> {
+    match mask {
+        Mask::Key(key) => Some(key),
+        _ => None,
+    }
+}
+
+// key for the byte at offset n
+#[inline]
+fn rotate(key: Option<[u8; 4]>, n: usize) -> Option<[u8; 4]> {
+    key.map(|mut key| {
+        key.rotate_left(n & 3);
+        key
+    })
+}
+
+// mask a copy if required
+#[inline]
+fn masked<'a>(scratch: &'a mut Vec<u8>, key: Option<[u8; 4]>, buf: &'a [u8]) -> &'a [u8] {
+    match key {
+        None => buf,
+        Some(key) => {
+            scratch.clear();
+            scratch.extend_from_slice(buf);
+            apply_mask4(key, scratch);
+            scratch
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Read {
+    // frame head, partially kept in `head`
+    Head,
+    // payload of a data frame
+    Data {
+        left: u64,
+        key: Option<[u8; 4]>,
+        fin: bool,
+    },
+    // payload of a control frame, kept in `Ctrl::recv`
+    Ctrl {
+        opcode: OpCode,
+        left: usize,
+        key: Option<[u8; 4]>,
+    },
+    // close frame or eof
+    Eof,
+}
+
+#[derive(Debug)]
+struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    timer: Pin<Box<Sleep>>,
+    // a ping is sent, waiting for the pong
+    waiting: bool,
+}
+
+#[derive(Debug)]
+struct Coalesce {
+    size: usize,
+    delay: Duration,
+    buf: Vec<u8>,
+    // since the first byte
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+#[derive(Debug, Default)]
+struct Text {
+    // not decoded yet, a partial group
+    recv: Vec<u8>,
+    // encoded message
+    send: String,
+}
+
+// boxed, rarely used
+#[derive(Debug, Default)]
+struct Ctrl {
+    // payload of the control frame being read
+    recv: Vec<u8>,
+    // frames to send: control frames, compressed frames,
+    // or the rest of a frame partially written
+    send: Vec<u8>,
+    sent: usize,
+    // control frames sent, but not flushed
+    flush: bool,
+    // masked payload
+    scratch: Vec<u8>,
+    keepalive: Option<Keepalive>,
+    // max payload of a frame to send or receive, no limit if 0
+    max_send: usize,
+    max_recv: usize,
+    coalesce: Option<Coalesce>,
+    text: Option<Text>,
+    // our close frame is queued
+    close_sent: bool,
+    // abnormal close from the peer, returned once
+    close_err: Option<CloseError>,
+    // wait for the peer's close on shutdown
+    close_timeout: Duration,
+    close_timer: Option<Pin<Box<Sleep>>>,
+    close_waker: Option<Waker>,
+    deflate: Option<Codec>,
+    // a compressed message is being read
+    inflating: bool,
+    // payload behind a compressed message, not read yet
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+/// Websocket stream, see the [module](self) docs.
+pub struct WsStream<IO, R> {
+    io: IO,
+    role: R,
+    read: Read,
+    head: [u8; 14],
+    head_len: u8,
+    ctrl: Box<Ctrl>,
+}
+
+impl<IO, R> Debug for WsStream<IO, R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsStream")
+            .field("read", &self.read)
+            .field("ctrl", &self.ctrl)
+            .finish()
+    }
+}
+
+impl<IO, R> AsRef<IO> for WsStream<IO, R> {
+    #[inline]
+    fn as_ref(&self) -> &IO { &self.io }
+}
+
+impl<IO, R> AsMut<IO> for WsStream<IO, R> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut IO { &mut self.io }
+}
+
+impl<IO, R> WsStream<IO, R> {
+    #[inline]
+    pub fn new(io: IO, role: R) -> Self {
+        Self {
+            io,
+            role,
+            read: Read::Head,
+            head: [0u8; 14],
+            head_len: 0,
+            ctrl: Box::default(),
+        }
+    }
+
+    /// Send a ping after each `interval`, and fail if the
+    /// pong does not come back within `timeout`, which defaults to
+    /// `interval` if zero. Disabled if `interval` is zero.
+    ///
+    /// Driven by reads only, see the [module](self) docs.
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        if interval.is_zero() {
+            return self;
+        }
+
+        let timeout = if timeout.is_zero() { interval } else { timeout };
+        self.ctrl.keepalive = Some(Keepalive {
+            interval,
+            timeout,
+            timer: Box::pin(tokio::time::sleep(interval)),
+            waiting: false,
+        });
+        self
+    }
+
+    /// Wait up to `timeout` for the peer's close on shutdown,
+    /// do not wait if zero.
+    #[inline]
+    pub fn with_close_timeout(mut self, timeout: Duration) -> Self {
+        self.ctrl.close_timeout = timeout;
+        self
+    }
+
+    /// Split writes larger than `send` into frames, and reject frames
+    /// larger than `recv`. No limit if zero.
+    #[inline]
+    pub fn with_frame_size(mut self, send: usize, recv: usize) -> Self {
+        self.ctrl.max_send = send;
+        self.ctrl.max_recv = recv;
+        self
+    }
+
+    /// Gather writes smaller than `size` into one frame, sent once it
+    /// is full, or flushed after `delay`. Disabled if `size` is zero.
+    pub fn with_coalesce(mut self, size: usize, delay: Duration) -> Self {
+        if size == 0 {
+            return self;
+        }
+
+        self.ctrl.coalesce = Some(Coalesce {
+            size,
+            delay,
+            buf: Vec::with_capacity(size),
+            timer: None,
+        });
+        self
+    }
+
+    /// Carry payload by text frames, encoded as base64.
+    #[inline]
+    pub fn with_text(mut self, text: bool) -> Self {
+        self.ctrl.text = text.then(Text::default);
+        self
+    }
+
+    /// Compress messages with the agreed parameters.
+    #[inline]
+    pub fn with_deflate(mut self, params: Params) -> Self {
+        self.ctrl.deflate = Some(Codec::new(params));
+        self
+    }
+
+    #[inline]
+    pub const fn get_ref(&self) -> &IO { &self.io }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut IO { &mut self.io }
+
+    #[inline]
+    pub fn into_inner(self) -> IO { self.io }
+}
+
+impl<IO, R> WsStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: Role,
+{
+    fn push_frame(&mut self, opcode: OpCode, compressed: bool, payload: &[u8]) {
+        let mask = self.role.next_mask();
+        let mut head = [0u8; 14];
+        let n = FrameHead::new(
+            Fin::Y,
+            opcode,
+            mask,
+            PayloadLen::from_num(payload.len() as u64),
+        )
+        .encode(&mut head)
+        .unwrap();
+
+        // rsv1
+        if compressed {
+            head[0] |= 0x40;
+        }
+
+        let send = &mut self.ctrl.send;
+        send.extend_from_slice(&head[..n]);
+        let beg = send.len();
+        send.extend_from_slice(payload);
+        if let Mask::Key(key) = mask {
+            apply_mask4(key, &mut send[beg..]);
+        }
+    }
+
+    // encoded in text mode
+    fn push_message(&mut self, payload: &[u8]) -> Result<()> {
+        let Some(text) = &mut self.ctrl.text else {
+            return self.push_data(OpCode::Binary, payload);
+        };
+
+        let mut encoded = std::mem::take(&mut text.send);
+        encoded.clear();
+        STANDARD.encode_string(payload, &mut encoded);
+        let res = self.push_data(OpCode::Text, encoded.as_bytes());
+        if let Some(text) = &mut self.ctrl.text {
+            text.send = encoded;
+        }
+        res
+    }
+
+    // compressed if worth it
+    fn push_data(&mut self, opcode: OpCode, payload: &[u8]) -> Result<()> {
+        if let Some(codec) = &mut self.ctrl.deflate {
+            if codec.should_compress(payload.len()) {
+                let mut out = std::mem::take(&mut self.ctrl.scratch);
+                out.clear();
+                codec.compress(payload, &mut out)?;
+                self.push_frame(opcode, true, &out);
+                self.ctrl.scratch = out;
+                return Ok(());
+            }
+        }
+
+        self.push_frame(opcode, false, payload);
+        Ok(())
+    }
+
+    // queue coalesced writes as one frame
+    fn push_batch(&mut self) -> Result<()> {
+        let Some(co) = &mut self.ctrl.coalesce else {
+            return Ok(());
+        };
+        if co.buf.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut co.buf);
+        let res = self.push_message(&batch);
+        let co = self.ctrl.coalesce.as_mut().unwrap();
+        co.buf = batch;
+        co.buf.clear();
+        res
+    }
+
+    // queue coalesced writes once the delay is over
+    fn poll_batch(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Some(co) = &mut self.ctrl.coalesce else {
+            return Poll::Ready(Ok(()));
+        };
+        if co.buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Some(timer) = &mut co.timer {
+            ready!(timer.as_mut().poll(cx));
+        }
+        Poll::Ready(self.push_batch())
+    }
+
+    // send queued frames, before any other frame
+    fn poll_send_ctrl(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let ctrl = &mut *self.ctrl;
+        while ctrl.sent < ctrl.send.len() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &ctrl.send[ctrl.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            ctrl.sent += n;
+        }
+
+        if !ctrl.send.is_empty() {
+            ctrl.send.clear();
+            ctrl.sent = 0;
+            ctrl.flush = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // send control frames from reads, without waiting
+    fn send_ctrl_now(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        if let Poll::Ready(res) = self.poll_send_ctrl(cx) {
+            res?;
+        }
+
+        if self.ctrl.flush {
+            if let Poll::Ready(res) = Pin::new(&mut self.io).poll_flush(cx) {
+                res?;
+                self.ctrl.flush = false;
+            }
+        }
+        Ok(())
+    }
+
+    // no more reads, wake up the shutdown
+    fn set_eof(&mut self) {
+        self.read = Read::Eof;
+        if let Some(waker) = self.ctrl.close_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // send a ping when the timer fires
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        let Some(ka) = &mut self.ctrl.keepalive else {
+            return Ok(());
+        };
+
+        // nothing is sent after our close
+        if self.ctrl.close_sent {
+            return Ok(());
+        }
+
+        if ka.timer.as_mut().poll(cx).is_pending() {
+            return Ok(());
+        }
+
+        if ka.waiting {
+            return Err(Error::new(ErrorKind::TimedOut, "ws: ping timeout"));
+        }
+
+        ka.waiting = true;
+        ka.timer.as_mut().reset(Instant::now() + ka.timeout);
+        let _ = ka.timer.as_mut().poll(cx);
+        self.push_frame(OpCode::Ping, false, &[]);
+        Ok(())
+    }
+
+    // handle a complete control frame in `Ctrl::recv`
+    fn on_ctrl(&mut self, opcode: OpCode) -> Result<()> {
+        match opcode {
+            OpCode::Ping if !self.ctrl.close_sent && self.ctrl.send.len() < MAX_CTRL_QUEUE => {
+                let payload = std::mem::take(&mut self.ctrl.recv);
+                self.push_frame(OpCode::Pong, false, &payload);
+                self.ctrl.recv = payload;
+            }
+            OpCode::Pong => {
+                if let Some(ka) = &mut self.ctrl.keepalive {
+                    ka.waiting = false;
+                    ka.timer.as_mut().reset(Instant::now() + ka.interval);
+                }
+            }
+            OpCode::Close => {
+                let recv = &self.ctrl.recv;
+                let code = match recv.len() {
+                    0 => NORMAL_CLOSURE,
+                    1 => return Err(FrameError::IllegalData.into()),
+                    _ => u16::from_be_bytes([recv[0], recv[1]]),
+                };
+                if !matches!(code, NORMAL_CLOSURE | GOING_AWAY) {
+                    let reason = String::from_utf8_lossy(&recv[2..]).into_owned();
+                    self.ctrl.close_err = Some(CloseError { code, reason });
+                }
+
+                // echo the code, after data taken before
+                if !self.ctrl.close_sent {
+                    self.push_batch()?;
+                    self.push_frame(OpCode::Close, false, &code.to_be_bytes());
+                    self.ctrl.close_sent = true;
+                }
+                self.set_eof();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // payload must wait behind a compressed message, or be decoded
+    #[inline]
+    fn queueing(&self) -> bool {
+        let ctrl = &self.ctrl;
+        ctrl.inflating || ctrl.text.is_some() || !ctrl.pending.is_empty()
+    }
+
+    fn queue_payload(&mut self, data: &[u8]) -> Result<()> {
+        let ctrl = &mut *self.ctrl;
+        let out = match &mut ctrl.text {
+            Some(text) => &mut text.recv,
+            None => &mut ctrl.pending,
+        };
+        match &mut ctrl.deflate {
+            Some(codec) if ctrl.inflating => codec.decompress(data, out)?,
+            _ => out.extend_from_slice(data),
+        }
+
+        match &mut ctrl.text {
+            Some(text) => decode_text(&mut text.recv, &mut ctrl.pending),
+            None => Ok(()),
+        }
+    }
+
+    // end of a data frame
+    fn end_frame(&mut self, fin: bool) -> Result<()> {
+        if !fin {
+            return Ok(());
+        }
+
+        let ctrl = &mut *self.ctrl;
+        if let (true, Some(codec)) = (ctrl.inflating, &mut ctrl.deflate) {
+            match &mut ctrl.text {
+                Some(text) => codec.finish(&mut text.recv)?,
+                None => codec.finish(&mut ctrl.pending)?,
+            }
+            ctrl.inflating = false;
+        }
+
+        // padded, nothing left at the end of a message
+        if let Some(text) = &mut ctrl.text {
+            decode_text(&mut text.recv, &mut ctrl.pending)?;
+            if !text.recv.is_empty() {
+                return Err(invalid_text());
+            }
+        }
+        Ok(())
+    }
+
+    // move queued payload to the caller
+    fn take_pending(&mut self, dst: &mut [u8]) -> usize {
+        let ctrl = &mut *self.ctrl;
+        let src = &ctrl.pending[ctrl.pending_pos..];
+        let n = std::cmp::min(src.len(), dst.len());
+        dst[..n].copy_from_slice(&src[..n]);
+        ctrl.pending_pos += n;
+
+        if ctrl.pending_pos == ctrl.pending.len() {
+            ctrl.pending.clear();
+            ctrl.pending_pos = 0;
+        }
+        n
+    }
+
+    // parse frames in place, move payload to the front
+    fn process(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let (mut pos, mut out) = (0, 0);
+
+        while pos < buf.len() {
+            // rsv1 is not known to the decoder
+            let b0 = buf[pos];
+            buf[pos] &= !0x40;
+            let (head, n) = match FrameHead::decode(&buf[pos..]) {
+                Ok(x) => x,
+                Err(FrameError::NotEnoughData) => {
+                    buf[pos] = b0;
+                    let rest = &buf[pos..];
+                    self.head[..rest.len()].copy_from_slice(rest);
+                    self.head_len = rest.len() as u8;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            pos += n;
+
+            let len = head.length.to_num();
+            let max = self.ctrl.max_recv;
+            if max != 0 && len > max as u64 {
+                return Err(Error::new(ErrorKind::InvalidData, "ws: frame too large"));
+            }
+            let key = key_of(head.mask);
+            let fin = head.fin == Fin::Y;
+            let n = std::cmp::min(len, (buf.len() - pos) as u64) as usize;
+
+            let opcode = match self.ctrl.text {
+                Some(_) => OpCode::Text,
+                None => OpCode::Binary,
+            };
+
+            // only the first frame of a compressed message
+            if b0 & 0x40 != 0 {
+                if head.opcode != opcode || self.ctrl.deflate.is_none() {
+                    return Err(FrameError::IllegalFin.into());
+                }
+                self.ctrl.inflating = true;
+            }
+
+            match head.opcode {
+                OpCode::Binary | OpCode::Text if head.opcode != opcode => {
+                    return Err(FrameError::UnsupportedOpcode.into())
+                }
+                OpCode::Binary | OpCode::Text | OpCode::Continue => {
+                    if let Some(key) = key {
+                        apply_mask4(key, &mut buf[pos..pos + n]);
+                    }
+                    if self.queueing() {
+                        self.queue_payload(&buf[pos..pos + n])?;
+                    } else {
+                        buf.copy_within(pos..pos + n, out);
+                        out += n;
+                    }
+                    pos += n;
+
+                    if (n as u64) < len {
+                        self.read = Read::Data {
+                            left: len - n as u64,
+                            key: rotate(key, n),
+                            fin,
+                        };
+                    } else {
+                        self.end_frame(fin)?;
+                    }
+                }
+                OpCode::Ping | OpCode::Pong | OpCode::Close => {
+                    if len > MAX_CTRL_PAYLOAD as u64 {
+                        return Err(FrameError::IllegalData.into());
+                    }
+
+                    self.ctrl.recv.clear();
+                    self.ctrl.recv.extend_from_slice(&buf[pos..pos + n]);
+                    pos += n;
+
+                    if (n as u64) < len {
+                        self.read = Read::Ctrl {
+                            opcode: head.opcode,
+                            left: len as usize - n,
+                            key,
+                        };
+                    } else {
+                        if let Some(key) = key {
+                            apply_mask4(key, &mut self.ctrl.recv);
+                        }
+                        self.on_ctrl(head.opcode)?;
+                        if let Read::Eof = self.read {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    // read some payload, 0 if only control frames are read
+    fn poll_read_some(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<()>> {
+        match self.read {
+            Read::Eof => Poll::Ready(Ok(())),
+            Read::Data { left, key, fin } => {
+                let dst = buf.initialize_unfilled();
+                let max = std::cmp::min(left, dst.len() as u64) as usize;
+                let n = ready!(poll_read_io(&mut self.io, cx, &mut dst[..max]))?;
+                if n == 0 {
+                    self.set_eof();
+                    return Poll::Ready(Ok(()));
+                }
+
+                if let Some(key) = key {
+                    apply_mask4(key, &mut dst[..n]);
+                }
+                self.read = match left - n as u64 {
+                    0 => Read::Head,
+                    left => Read::Data {
+                        left,
+                        key: rotate(key, n),
+                        fin,
+                    },
+                };
+
+                let queueing = self.queueing();
+                if queueing {
+                    self.queue_payload(&dst[..n])?;
+                }
+                if let Read::Head = self.read {
+                    self.end_frame(fin)?;
+                }
+                let n = if queueing { self.take_pending(dst) } else { n };
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Read::Ctrl { opcode, left, key } => {
+                let mut dst = [0u8; MAX_CTRL_PAYLOAD];
+                let n = ready!(poll_read_io(&mut self.io, cx, &mut dst[..left]))?;
+                if n == 0 {
+                    self.set_eof();
+                    return Poll::Ready(Ok(()));
+                }
+
+                self.ctrl.recv.extend_from_slice(&dst[..n]);
+                if n < left {
+                    self.read = Read::Ctrl {
+                        opcode,
+                        left: left - n,
+                        key,
+                    };
+                } else {
+                    if let Some(key) = key {
+                        apply_mask4(key, &mut self.ctrl.recv);
+                    }
+                    self.read = Read::Head;
+                    self.on_ctrl(opcode)?;
+                }
+                Poll::Ready(Ok(()))
+            }
+            Read::Head => {
+                let h = self.head_len as usize;
+                let dst = buf.initialize_unfilled();
+
+                // a small buffer, read the head alone
+                if dst.len() < self.head.len() {
+                    let need = match h {
+                        0 | 1 => 2 - h,
+                        _ => head_len(&self.head[..2]) - h,
+                    };
+                    let n = ready!(poll_read_io(&mut self.io, cx, &mut self.head[h..h + need]))?;
+                    if n == 0 {
+                        self.set_eof();
+                        return Poll::Ready(Ok(()));
+                    }
+                    self.head_len += n as u8;
+
+                    let mut head = self.head;
+                    let len = self.head_len as usize;
+                    if len >= 2 && len == head_len(&head[..2]) {
+                        self.head_len = 0;
+                        let out = self.process(&mut head[..len])?;
+                        debug_assert_eq!(out, 0);
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+
+                dst[..h].copy_from_slice(&self.head[..h]);
+                let n = ready!(poll_read_io(&mut self.io, cx, &mut dst[h..]))?;
+                if n == 0 {
+                    self.set_eof();
+                    return Poll::Ready(Ok(()));
+                }
+
+                self.head_len = 0;
+                let mut out = self.process(&mut dst[..h + n])?;
+                out += self.take_pending(&mut dst[out..]);
+                buf.advance(out);
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+// decode whole groups, keep the rest
+fn decode_text(input: &mut Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+    let n = input.len() / 4 * 4;
+    STANDARD
+        .decode_vec(&input[..n], out)
+        .map_err(|_| invalid_text())?;
+    input.drain(..n);
+    Ok(())
+}
+
+#[inline]
+fn invalid_text() -> Error { Error::new(ErrorKind::InvalidData, "ws: invalid text frame") }
+
+// length of a frame head from its first 2 bytes
+fn head_len(b: &[u8]) -> usize {
+    let len = match b[1] & 0x7f {
+        126 => 4,
+        127 => 10,
+        _ => 2,
+    };
+    match b[1] & 0x80 {
+        0 => len,
+        _ => len + 4,
+    }
+}
+
+#[inline]
+fn poll_read_io<IO>(io: &mut IO, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut buf = ReadBuf::new(buf);
+    ready!(Pin::new(io).poll_read(cx, &mut buf))?;
+    Poll::Ready(Ok(buf.filled().len()))
+}
+
+impl<IO, R> AsyncRead for WsStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: Role,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let res = ready!(this.poll_read_frames(cx, buf));
+        if res.is_err() {
+            this.set_eof();
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<IO, R> WsStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: Role,
+{
+    fn poll_read_frames(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let filled = buf.filled().len();
+        loop {
+            self.send_ctrl_now(cx)?;
+
+            if !self.ctrl.pending.is_empty() {
+                let n = self.take_pending(buf.initialize_unfilled());
+                buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            // a pong may be received already
+            if self.poll_read_some(cx, buf)?.is_pending() {
+                self.poll_keepalive(cx)?;
+                self.send_ctrl_now(cx)?;
+                return Poll::Pending;
+            }
+
+            if buf.filled().len() > filled {
+                return Poll::Ready(Ok(()));
+            }
+
+            // with the echo of a close
+            if let Read::Eof = self.read {
+                self.send_ctrl_now(cx)?;
+                return Poll::Ready(match self.ctrl.close_err.take() {
+                    Some(e) => Err(e.into()),
+                    None => Ok(()),
+                });
+            }
+        }
+    }
+
+    // until the peer's close, eof, or timeout
+    fn poll_close_wait(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let timeout = self.ctrl.close_timeout;
+        if matches!(self.read, Read::Eof) || timeout.is_zero() {
+            return Poll::Ready(());
+        }
+
+        let timer =
+            (self.ctrl.close_timer).get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if timer.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(());
+        }
+
+        self.ctrl.close_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<IO, R> AsyncWrite for WsStream<IO, R>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    R: Role,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if this.ctrl.close_sent {
+            return Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "ws: already closed")));
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        ready!(this.poll_send_ctrl(cx))?;
+
+        // before encoding
+        let max = match (this.ctrl.max_send, &this.ctrl.text) {
+            (0, _) | (_, None) => this.ctrl.max_send,
+            (n, Some(_)) => std::cmp::max(n / 4 * 3, 1),
+        };
+        let buf = match buf.len() {
+            n if max != 0 && n > max => &buf[..max],
+            _ => buf,
+        };
+
+        // small writes are taken, sent as one frame later
+        if let Some(co) = &mut this.ctrl.coalesce {
+            // a gathered frame is not split
+            let size = match max {
+                0 => co.size,
+                n => std::cmp::min(co.size, n),
+            };
+            if buf.len() < size {
+                if co.buf.is_empty() {
+                    let deadline = Instant::now() + co.delay;
+                    match &mut co.timer {
+                        Some(timer) => timer.as_mut().reset(deadline),
+                        None => co.timer = Some(Box::pin(tokio::time::sleep_until(deadline))),
+                    }
+                }
+
+                let n = std::cmp::min(buf.len(), size - co.buf.len());
+                co.buf.extend_from_slice(&buf[..n]);
+                if co.buf.len() == size {
+                    this.push_batch()?;
+                    if let Poll::Ready(res) = this.poll_send_ctrl(cx) {
+                        res?;
+                    }
+                }
+                return Poll::Ready(Ok(n));
+            }
+
+            // keep the order
+            if !co.buf.is_empty() {
+                this.push_batch()?;
+                ready!(this.poll_send_ctrl(cx))?;
+            }
+        }
+
+        // the whole message is taken, sent later if blocked
+        let compress = (this.ctrl.deflate.as_ref()).is_some_and(|x| x.should_compress(buf.len()));
+        if compress || this.ctrl.text.is_some() {
+            this.push_message(buf)?;
+            if let Poll::Ready(res) = this.poll_send_ctrl(cx) {
+                res?;
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        let mask = this.role.next_mask();
+        let mut head = [0u8; 14];
+        let len = FrameHead::new(
+            Fin::Y,
+            OpCode::Binary,
+            mask,
+            PayloadLen::from_num(buf.len() as u64),
+        )
+        .encode(&mut head)
+        .unwrap();
+
+        let data = masked(&mut this.ctrl.scratch, key_of(mask), buf);
+        let iov = [IoSlice::new(&head[..len]), IoSlice::new(data)];
+        let w = ready!(Pin::new(&mut this.io).poll_write_vectored(cx, &iov))?;
+        if w == 0 {
+            return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+        }
+
+        // the frame is taken as a whole, the rest is sent before the next one
+        let send = &mut this.ctrl.send;
+        if w < len {
+            send.extend_from_slice(&head[w..len]);
+            send.extend_from_slice(data);
+        } else {
+            send.extend_from_slice(&data[w - len..]);
+        }
+        if let Poll::Ready(res) = this.poll_send_ctrl(cx) {
+            res?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_batch(cx))?;
+        ready!(this.poll_send_ctrl(cx))?;
+        ready!(Pin::new(&mut this.io).poll_flush(cx))?;
+        this.ctrl.flush = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        // after any frame taken before
+        if !this.ctrl.close_sent {
+            this.push_batch()?;
+            this.push_frame(OpCode::Close, false, &NORMAL_CLOSURE.to_be_bytes());
+            this.ctrl.close_sent = true;
+        }
+
+        ready!(this.poll_send_ctrl(cx))?;
+        ready!(Pin::new(&mut this.io).poll_flush(cx))?;
+        ready!(this.poll_close_wait(cx));
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    const MS: Duration = Duration::from_millis(50);
+
+    async fn read_some<T: AsyncRead + Unpin>(io: &mut T) -> Vec<u8> {
+        let mut buf = vec![0u8; 0x100];
+        let n = io.read(&mut buf).await.unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[tokio::test]
+    async fn answer_ping() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new());
+
+        // masked payload, a ping in the middle
+        let mut frames = vec![0x82, 0x83, 1, 2, 3, 4];
+        frames.extend([b'h' ^ 1, b'e' ^ 2, b'l' ^ 3]);
+        frames.extend([0x89, 0x03, b'a', b'b', b'c']);
+        frames.extend([0x82, 0x02, b'l', b'o']);
+        client.write_all(&frames).await.unwrap();
+
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf[..3]).await.unwrap();
+        server.read_exact(&mut buf[3..]).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        assert_eq!(read_some(&mut client).await, [0x8a, 0x03, b'a', b'b', b'c']);
+
+        // a close frame ends the stream, and is echoed
+        client.write_all(&[0x88, 0x00]).await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(read_some(&mut client).await, [0x88, 0x02, 0x03, 0xe8]);
+        assert!(server.write_all(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        macro_rules! run {
+            ($role: expr) => {{
+                // force partial heads and payload
+                let (client, server) = tokio::io::duplex(7);
+                let mut client = WsStream::new(client, $role);
+                let mut server = WsStream::new(server, Server::new());
+
+                let data: Vec<u8> = (0..0x4000).map(|x| x as u8).collect();
+                let send = async {
+                    for chunk in data.chunks(1000) {
+                        client.write_all(chunk).await.unwrap();
+                    }
+                    client.shutdown().await.unwrap();
+                };
+
+                // both fast and slow path
+                let recv = async {
+                    let mut recv = Vec::new();
+                    let mut buf = vec![0u8; 0x100];
+                    for i in 0.. {
+                        let n = server
+                            .read(&mut buf[..[3, 20, 0x100][i % 3]])
+                            .await
+                            .unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        recv.extend_from_slice(&buf[..n]);
+                    }
+                    recv
+                };
+
+                let (_, recv) = tokio::join!(send, recv);
+                assert_eq!(recv, data);
+            }};
+        }
+
+        run!(Client::new());
+        run!(StandardClient::new());
+        run!(FixedMaskClient::new());
+        run!(DynamicClient::with_mode(MaskMode::Standard));
+    }
+
+    #[tokio::test]
+    async fn dynamic_mask() {
+        macro_rules! y {
+            ( $( ($mode: expr, $empty: expr, $same: expr); )+ ) => {
+                $(
+                    let (client, mut server) = tokio::io::duplex(0x1000);
+                    let mut client = WsStream::new(client, DynamicClient::with_mode($mode));
+                    client.write_all(b"ab").await.unwrap();
+                    client.write_all(b"ab").await.unwrap();
+
+                    // 2 frames, with the key after 2 bytes
+                    let mut buf = [0u8; 16];
+                    server.read_exact(&mut buf).await.unwrap();
+                    let (a, b) = (&buf[2..6], &buf[10..14]);
+                    assert_eq!(a == [0u8; 4], $empty);
+                    assert_eq!(a == b, $same);
+                )+
+            }
+        }
+
+        y![
+            (MaskMode::Skip, true, true);
+            (MaskMode::Standard, false, false);
+            (MaskMode::Fixed, false, true);
+        ];
+    }
+
+    fn keepalive_pair() -> (
+        WsStream<DuplexStream, Client>,
+        WsStream<DuplexStream, Server>,
+    ) {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let client = WsStream::new(client, Client::new()).with_keepalive(MS, MS);
+        let server = WsStream::new(server, Server::new());
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let (mut client, mut server) = keepalive_pair();
+
+        // pings are answered, payload is not disturbed
+        let idle = async {
+            let mut buf = [0u8; 0x100];
+            tokio::time::timeout(MS * 6, client.read(&mut buf))
+                .await
+                .unwrap_err();
+            client.write_all(b"hello").await.unwrap();
+            client.read(&mut buf).await.unwrap()
+        };
+        let echo = async {
+            let buf = read_some(&mut server).await;
+            server.write_all(&buf).await.unwrap();
+            buf
+        };
+
+        let (n, buf) = tokio::join!(idle, echo);
+        assert_eq!(n, 5);
+        assert_eq!(buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn keepalive_timeout() {
+        let (mut client, _server) = keepalive_pair();
+
+        // nobody answers
+        let mut buf = [0u8; 0x100];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    // a frame is taken whole, even if the socket takes a part
+    #[tokio::test]
+    async fn close_after_partial_write() {
+        let (client, server) = tokio::io::duplex(7);
+        let mut client = WsStream::new(client, Client::new()).with_close_timeout(MS);
+        let mut server = WsStream::new(server, Server::new());
+
+        let data = [7u8; 100];
+        let send = async {
+            assert_eq!(client.write(&data).await.unwrap(), data.len());
+            client.shutdown().await.unwrap();
+        };
+        let recv = async {
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let (_, recv) = tokio::join!(send, recv);
+        assert_eq!(recv, data);
+    }
+
+    #[tokio::test]
+    async fn close_handshake() {
+        let (client, server) = tokio::io::duplex(0x1000);
+        let client = WsStream::new(client, Client::new()).with_close_timeout(MS * 100);
+        let mut server = WsStream::new(server, Server::new()).with_close_timeout(MS * 100);
+
+        // the server echoes the close, long before the timeout
+        let (mut rd, mut wr) = tokio::io::split(client);
+        let send = async {
+            wr.write_all(b"hi").await.unwrap();
+            wr.shutdown().await.unwrap();
+        };
+        let recv = async {
+            let mut buf = Vec::new();
+            rd.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let peer = async {
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            assert!(server.write_all(b"bye").await.is_err());
+            server.shutdown().await.unwrap();
+            buf
+        };
+
+        let all = async { tokio::join!(send, recv, peer) };
+        let (_, recv, peer) = tokio::time::timeout(MS * 10, all).await.unwrap();
+        assert_eq!(peer, b"hi");
+        assert!(recv.is_empty());
+    }
+
+    #[tokio::test]
+    async fn close_timeout() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new()).with_close_timeout(MS);
+
+        // nobody answers
+        server.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x88, 0x02, 0x03, 0xe8]);
+
+        let err = server.write_all(b"hi").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn close_error() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new());
+
+        let mut frame = vec![0x88, 0x06, 0x03, 0xf3];
+        frame.extend(b"oops");
+        client.write_all(&frame).await.unwrap();
+
+        let mut buf = [0u8; 0x100];
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+        let err = err.get_ref().unwrap().downcast_ref::<CloseError>().unwrap();
+        assert_eq!(err.code, 1011);
+        assert_eq!(err.reason, "oops");
+
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn frame_size() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new()).with_frame_size(4, 8);
+
+        // split into frames
+        server.write_all(b"hello").await.unwrap();
+        server.flush().await.unwrap();
+        let buf = read_some(&mut client).await;
+        assert_eq!(buf, [0x82, 0x04, b'h', b'e', b'l', b'l', 0x82, 0x01, b'o']);
+
+        // not larger than 8
+        client.write_all(b"\x82\x0812345678").await.unwrap();
+        let mut buf = [0u8; 0x100];
+        server.read_exact(&mut buf[..8]).await.unwrap();
+        assert_eq!(&buf[..8], b"12345678");
+
+        client.write_all(b"\x82\x09123456789").await.unwrap();
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn coalesce() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new()).with_coalesce(4, MS);
+
+        // sent once full
+        server.write_all(b"ab").await.unwrap();
+        server.write_all(b"cde").await.unwrap();
+        assert_eq!(
+            read_some(&mut client).await,
+            [0x82, 0x04, b'a', b'b', b'c', b'd']
+        );
+
+        // or flushed after the delay
+        let now = Instant::now();
+        server.flush().await.unwrap();
+        assert!(now.elapsed() >= MS);
+        assert_eq!(read_some(&mut client).await, [0x82, 0x01, b'e']);
+
+        // large writes go after gathered ones
+        server.write_all(b"f").await.unwrap();
+        server.write_all(b"ghij").await.unwrap();
+        server.flush().await.unwrap();
+        let mut expect = vec![0x82, 0x01, b'f', 0x82, 0x04];
+        expect.extend(b"ghij");
+        assert_eq!(read_some(&mut client).await, expect);
+
+        // and before the close frame
+        server.write_all(b"k").await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x82, 0x01, b'k', 0x88, 0x02, 0x03, 0xe8]);
+    }
+
+    fn params() -> Params {
+        Params {
+            window_bits: 15,
+            no_context_takeover: false,
+            threshold: 64,
+        }
+    }
+
+    #[tokio::test]
+    async fn deflate() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new()).with_deflate(params());
+
+        // rsv1 is set on compressed messages only
+        server.write_all(&[b'a'; 1000]).await.unwrap();
+        let buf = read_some(&mut client).await;
+        assert_eq!(buf[0], 0xc2);
+        assert!(buf.len() < 100);
+        server.write_all(b"hi").await.unwrap();
+        assert_eq!(read_some(&mut client).await, [0x82, 0x02, b'h', b'i']);
+
+        // not agreed
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new());
+        client.write_all(&buf).await.unwrap();
+        assert!(server.read(&mut [0u8; 0x100]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn deflate_roundtrip() {
+        let (client, server) = tokio::io::duplex(7);
+        let mut client = WsStream::new(client, StandardClient::new()).with_deflate(params());
+        let mut server = WsStream::new(server, Server::new()).with_deflate(params());
+
+        // compressed or not, in between
+        let messages: Vec<String> = (0..0x100)
+            .map(|i| format!("{i} hello ").repeat(i % 20))
+            .collect();
+        let send = async {
+            for msg in &messages {
+                client.write_all(msg.as_bytes()).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+        };
+
+        let recv = async {
+            let mut recv = Vec::new();
+            server.read_to_end(&mut recv).await.unwrap();
+            recv
+        };
+
+        let (_, recv) = tokio::join!(send, recv);
+        assert_eq!(recv, messages.concat().into_bytes());
+    }
+
+    #[tokio::test]
+    async fn text() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let mut server = WsStream::new(server, Server::new()).with_text(true);
+
+        server.write_all(b"hello").await.unwrap();
+        let buf = read_some(&mut client).await;
+        assert_eq!(&buf[..2], [0x81, 0x08]);
+        assert_eq!(std::str::from_utf8(&buf[2..]).unwrap(), "aGVsbG8=");
+
+        // split anywhere, even inside a group
+        let mut frames = vec![0x01, 0x03];
+        frames.extend(b"aGV");
+        frames.extend([0x00, 0x07]);
+        frames.extend(b"sbG8gd2");
+        frames.extend([0x80, 0x06]);
+        frames.extend(b"9ybGQ=");
+        client.write_all(&frames).await.unwrap();
+
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn text_err() {
+        macro_rules! n {
+            ( $( $frame: expr, )+ ) => {
+                $(
+                    let (mut client, server) = tokio::io::duplex(0x1000);
+                    let mut server = WsStream::new(server, Server::new()).with_text(true);
+                    client.write_all($frame).await.unwrap();
+                    assert!(server.read(&mut [0u8; 0x100]).await.is_err());
+                )+
+            }
+        }
+
+        n![
+            // binary
+            b"\x82\x04aGk=",
+            // not utf-8
+            b"\x81\x04aG\xffk",
+            // not base64
+            b"\x81\x04aG~k",
+            // a partial group at the end
+            b"\x81\x03aGk",
+        ];
+    }
+
+    #[tokio::test]
+    async fn text_roundtrip() {
+        macro_rules! run {
+            ($deflate: expr) => {{
+                let (client, server) = tokio::io::duplex(7);
+                let mut client = WsStream::new(client, StandardClient::new()).with_text(true);
+                let mut server = WsStream::new(server, Server::new()).with_text(true);
+                if $deflate {
+                    client = client.with_deflate(params());
+                    server = server.with_deflate(params());
+                }
+
+                let data: Vec<u8> = (0..0x4000).map(|x| (x % 251) as u8).collect();
+                let send = async {
+                    for chunk in data.chunks(1000) {
+                        client.write_all(chunk).await.unwrap();
+                    }
+                    client.shutdown().await.unwrap();
+                };
+
+                let recv = async {
+                    let mut recv = Vec::new();
+                    server.read_to_end(&mut recv).await.unwrap();
+                    recv
+                };
+
+                let (_, recv) = tokio::join!(send, recv);
+                assert_eq!(recv, data);
+            }};
+        }
+
+        run!(false);
+        run!(true);
+    }
+}